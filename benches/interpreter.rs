@@ -0,0 +1,61 @@
+//! Timings for `Cpu::run` on a few bundled and inline programs.
+//!
+//! criterion is not available to this build, so this is a small hand-rolled
+//! harness: every program runs a few times and the fastest run is reported.
+//! Run with `cargo bench`.
+//!
+//! The `unfolded` entries run without optimizations, one instruction per
+//! command, so that they time the dispatch of instructions rather than what
+//! the optimizer folds away.
+
+use brainfuck_interpreter::{ir, CpuBuilder};
+use std::hint::black_box;
+use std::io;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 10;
+
+/// Runs `program` `RUNS` times and returns the fastest run.
+fn time(program: &str, level: u8, opt_level: u8) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let mut cpu = CpuBuilder::new(black_box(program))
+                .level(level)
+                .opt_level(opt_level)
+                .sink(Box::new(io::sink()))
+                .build();
+            cpu.one_shot_output = true;
+            let started = Instant::now();
+            cpu.run(Some(Vec::new())).unwrap();
+            let elapsed = started.elapsed();
+            black_box(cpu.output);
+            elapsed
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let hello_world = include_str!("../programs/hello_world.bf");
+    let squares = include_str!("../programs/square.b");
+    // Three nested loops of 255 iterations each, about 80 million steps
+    let nested_loops = "-[>-[>-[>+<-]<-]<-]";
+
+    // Mostly `+` and `-` in a loop of a million iterations, about 10
+    // million steps
+    let arithmetic = "++++++++++++++++[>-[>-[>+-+-<-]<-]<-]";
+
+    for (name, program, level, opt_level) in [
+        ("hello_world", hello_world, 1, ir::MAX_OPT_LEVEL),
+        ("squares", squares, 0, ir::MAX_OPT_LEVEL),
+        ("nested_loops", nested_loops, 0, ir::MAX_OPT_LEVEL),
+        ("squares", squares, 0, 0),
+        ("arithmetic", arithmetic, 0, 0),
+    ] {
+        let name = match opt_level {
+            0 => format!("{} unfolded", name),
+            _ => name.to_string(),
+        };
+        println!("{:<22} {:>12.3?}", name, time(program, level, opt_level));
+    }
+}
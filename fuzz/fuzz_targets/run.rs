@@ -0,0 +1,37 @@
+//! Feeds arbitrary bytes to `Cpu::run` as program, input and settings. Any
+//! `Result` is fine, a panic is a bug. Run with `cargo fuzz run run`.
+
+#![no_main]
+
+use brainfuck_interpreter::{Cpu, PointerPolicy};
+use libfuzzer_sys::fuzz_target;
+use std::io;
+
+fuzz_target!(|data: &[u8]| {
+    let [tape_size, settings, rest @ ..] = data else {
+        return;
+    };
+    // The program runs until the first 0 byte, the rest is its input
+    let (program, input) = match rest.iter().position(|&byte| byte == 0) {
+        Some(end) => (&rest[..end], &rest[end + 1..]),
+        None => (rest, &[][..]),
+    };
+
+    let mut cpu = Cpu::new(
+        String::from_utf8_lossy(program).into_owned(),
+        *tape_size as usize,
+    );
+    cpu.one_shot_output = true;
+    cpu.sink = Box::new(io::sink());
+    cpu.max_steps = Some(100_000);
+    // Level 2 still has commands that are `todo!()`
+    cpu.level = settings % 2;
+    cpu.pointer_policy = match settings / 2 % 3 {
+        0 => PointerPolicy::Clamp,
+        1 => PointerPolicy::Wrap,
+        _ => PointerPolicy::Error,
+    };
+    cpu.strict_division = settings & 0x80 != 0;
+
+    let _ = cpu.run(Some(String::from_utf8_lossy(input).into_owned()));
+});
@@ -1 +1,2 @@
+!bf: level=1
 $++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.@
\ No newline at end of file
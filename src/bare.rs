@@ -0,0 +1,222 @@
+//! An execution loop that only needs `core`.
+//!
+//! `run_core` is the plain execution loop of `Cpu` with everything that needs
+//! an allocator or `std::io` taken out: the program and the tape are slices
+//! owned by the caller, and input and output go through closures, e.g. for a
+//! device with a fixed buffer for the tape. The crate as a whole still needs
+//! `std`, there is no `no_std` build of it, but this module uses nothing
+//! else and can be carried over to one as it is. Commands are those of
+//! level 0, cells are 8 bits wide and wrap, and moving off either end of the
+//! tape is an error.
+
+use core::fmt;
+
+/// Errors produced by `run_core`. Positions are byte offsets into the
+/// program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoreError {
+    /// A `[` without a matching `]`, or the other way around
+    UnmatchedBracket { pos: usize },
+    /// The data pointer left the tape
+    PointerOutOfBounds { pos: usize, pointer: isize },
+    /// The tape has no cell for the data pointer to start on
+    EmptyTape,
+}
+
+impl fmt::Display for CoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoreError::UnmatchedBracket { pos } => {
+                write!(f, "Unmatched bracket at position {}", pos)
+            }
+            CoreError::PointerOutOfBounds { pos, pointer } => write!(
+                f,
+                "Data pointer moved off the tape to {} at position {}",
+                pointer, pos
+            ),
+            CoreError::EmptyTape => write!(f, "The tape has no cells"),
+        }
+    }
+}
+
+/// Checks that every bracket of `program` is matched, so that jumps can look
+/// for their partner without keeping a table.
+fn check_brackets(program: &[u8]) -> Result<(), CoreError> {
+    let mut depth = 0usize;
+    for (pos, &command) in program.iter().enumerate() {
+        match command {
+            b'[' => depth += 1,
+            b']' if depth == 0 => return Err(CoreError::UnmatchedBracket { pos }),
+            b']' => depth -= 1,
+            _ => {}
+        }
+    }
+    if depth == 0 {
+        return Ok(());
+    }
+
+    // Like `compile`, reports the last `[` left open
+    let mut closed = 0usize;
+    for (pos, &command) in program.iter().enumerate().rev() {
+        match command {
+            b']' => closed += 1,
+            b'[' if closed == 0 => return Err(CoreError::UnmatchedBracket { pos }),
+            b'[' => closed -= 1,
+            _ => {}
+        }
+    }
+    unreachable!("an open bracket was counted")
+}
+
+/// Returns the position of the bracket matching the one at `pos`.
+fn partner(program: &[u8], pos: usize) -> usize {
+    let mut depth = 0usize;
+    if program[pos] == b'[' {
+        for (index, &command) in program.iter().enumerate().skip(pos) {
+            match command {
+                b'[' => depth += 1,
+                b']' if depth == 1 => return index,
+                b']' => depth -= 1,
+                _ => {}
+            }
+        }
+    } else {
+        for index in (0..=pos).rev() {
+            match program[index] {
+                b']' => depth += 1,
+                b'[' if depth == 1 => return index,
+                b'[' => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+    unreachable!("brackets are checked before the program runs")
+}
+
+/// Runs `program` on `tape`, starting at its first cell. `read` is called for
+/// `,` and returns `None` at the end of the input, which leaves the cell as
+/// it is. `write` is called with every byte `.` prints. Bytes that are not
+/// commands are comments. An empty `tape` is `CoreError::EmptyTape`.
+pub fn run_core(
+    program: &[u8],
+    tape: &mut [u8],
+    mut read: impl FnMut() -> Option<u8>,
+    mut write: impl FnMut(u8),
+) -> Result<(), CoreError> {
+    check_brackets(program)?;
+    if tape.is_empty() {
+        return Err(CoreError::EmptyTape);
+    }
+
+    let mut data_pointer = 0usize;
+    let mut instruction_pointer = 0;
+    while instruction_pointer < program.len() {
+        match program[instruction_pointer] {
+            b'>' if data_pointer + 1 >= tape.len() => {
+                return Err(CoreError::PointerOutOfBounds {
+                    pos: instruction_pointer,
+                    pointer: tape.len() as isize,
+                })
+            }
+            b'>' => data_pointer += 1,
+            b'<' if data_pointer == 0 => {
+                return Err(CoreError::PointerOutOfBounds {
+                    pos: instruction_pointer,
+                    pointer: -1,
+                })
+            }
+            b'<' => data_pointer -= 1,
+            b'+' => tape[data_pointer] = tape[data_pointer].wrapping_add(1),
+            b'-' => tape[data_pointer] = tape[data_pointer].wrapping_sub(1),
+            b'.' => write(tape[data_pointer]),
+            b',' => {
+                if let Some(byte) = read() {
+                    tape[data_pointer] = byte;
+                }
+            }
+            b'[' if tape[data_pointer] == 0 => {
+                instruction_pointer = partner(program, instruction_pointer)
+            }
+            b']' if tape[data_pointer] != 0 => {
+                instruction_pointer = partner(program, instruction_pointer)
+            }
+            _ => {}
+        }
+        instruction_pointer += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_buffers() {
+        // Echoes its input upper-cased until it reads a zero
+        let program = b",[>++++[<-------->-]<.,]";
+        let mut tape = [0u8; 4];
+        let mut input = b"abc".iter().copied();
+        let mut output = [0u8; 8];
+        let mut written = 0;
+
+        // Reading nothing would leave the last `C` in the cell and loop forever
+        let read = || Some(input.next().unwrap_or(0));
+        let result = run_core(program, &mut tape, read, |byte| {
+            output[written] = byte;
+            written += 1;
+        });
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(&output[..written], b"ABC");
+    }
+
+    #[test]
+    fn test_hello_world() {
+        let program = b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let mut tape = [0u8; 8];
+        let mut output = [0u8; 16];
+        let mut written = 0;
+
+        let result = run_core(
+            program,
+            &mut tape,
+            || None,
+            |byte| {
+                output[written] = byte;
+                written += 1;
+            },
+        );
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(&output[..written], b"Hello World!\n");
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut tape = [0u8; 2];
+        assert_eq!(
+            run_core(b"+[[]", &mut tape, || None, |_| {}),
+            Err(CoreError::UnmatchedBracket { pos: 1 })
+        );
+        assert_eq!(
+            run_core(b"+]", &mut tape, || None, |_| {}),
+            Err(CoreError::UnmatchedBracket { pos: 1 })
+        );
+        assert_eq!(
+            run_core(b">>", &mut tape, || None, |_| {}),
+            Err(CoreError::PointerOutOfBounds { pos: 1, pointer: 2 })
+        );
+        assert_eq!(
+            run_core(b"<", &mut tape, || None, |_| {}),
+            Err(CoreError::PointerOutOfBounds {
+                pos: 0,
+                pointer: -1
+            })
+        );
+        assert_eq!(
+            run_core(b"+", &mut [], || None, |_| {}),
+            Err(CoreError::EmptyTape)
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Translators from other brainfuck dialects into plain brainfuck.
+
+use anyhow::Error;
+
+use crate::error::BrainfuckError;
+
+/// The eight brainfuck commands, in the order a command map lists them
+pub const CANONICAL_COMMANDS: [char; 8] = ['>', '<', '+', '-', '.', ',', '[', ']'];
+
+/// Parses a command map, the characters standing for `><+-.,[]` in that
+/// order. All eight have to be given and be distinct.
+pub fn parse_command_map(map: &str) -> Result<[char; 8], Error> {
+    let characters: Vec<char> = map.chars().collect();
+    let map: [char; 8] = characters.as_slice().try_into().map_err(|_| {
+        anyhow::anyhow!(
+            "Command map needs 8 characters for ><+-.,[], got {}",
+            characters.len()
+        )
+    })?;
+
+    for (index, character) in map.iter().enumerate() {
+        if map[..index].contains(character) {
+            anyhow::bail!("Command map uses '{}' more than once", character);
+        }
+    }
+    Ok(map)
+}
+
+/// Rewrites a program written with the command characters in `map` into
+/// canonical brainfuck. Canonical command characters that are not part of the
+/// map are comments in the dialect and get dropped, everything else is kept so
+/// extended commands still work.
+pub fn remap(program: &str, map: &[char; 8]) -> String {
+    program
+        .chars()
+        .filter_map(|character| match map.iter().position(|&c| c == character) {
+            Some(index) => Some(CANONICAL_COMMANDS[index]),
+            None if CANONICAL_COMMANDS.contains(&character) => None,
+            None => Some(character),
+        })
+        .collect()
+}
+
+/// Translates Ook! source into brainfuck. Every command is a pair of `Ook.`,
+/// `Ook?` or `Ook!` tokens, anything that is not a token is ignored.
+pub fn ook_to_bf(source: &str) -> Result<String, BrainfuckError> {
+    let bytes = source.as_bytes();
+    let mut tokens: Vec<(usize, u8)> = Vec::new();
+    let mut position = 0;
+    while position < bytes.len() {
+        match &bytes[position..] {
+            [b'O', b'o', b'k', mark @ (b'.' | b'?' | b'!'), ..] => {
+                tokens.push((position, *mark));
+                position += 4;
+            }
+            _ => position += 1,
+        }
+    }
+
+    let mut program = String::new();
+    for pair in tokens.chunks(2) {
+        let command = match pair {
+            [(_, b'.'), (_, b'?')] => '>',
+            [(_, b'?'), (_, b'.')] => '<',
+            [(_, b'.'), (_, b'.')] => '+',
+            [(_, b'!'), (_, b'!')] => '-',
+            [(_, b'!'), (_, b'.')] => '.',
+            [(_, b'.'), (_, b'!')] => ',',
+            [(_, b'!'), (_, b'?')] => '[',
+            [(_, b'?'), (_, b'!')] => ']',
+            // `Ook? Ook?`, or a token without a partner at the end
+            _ => return Err(BrainfuckError::InvalidToken { pos: pair[0].0 }),
+        };
+        program.push(command);
+    }
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_program;
+
+    const HELLO_WORLD: &str = "++++++++++[>+++++++>++++++++++>+++>+<<<<-]>++.>+.+++++++\
+        ..+++.>++.<<+++++++++++++++.>.+++.------.--------.>+.>.";
+
+    fn bf_to_ook(program: &str) -> String {
+        let pairs: Vec<&str> = program
+            .chars()
+            .map(|command| match command {
+                '>' => "Ook. Ook?",
+                '<' => "Ook? Ook.",
+                '+' => "Ook. Ook.",
+                '-' => "Ook! Ook!",
+                '.' => "Ook! Ook.",
+                ',' => "Ook. Ook!",
+                '[' => "Ook! Ook?",
+                _ => "Ook? Ook!",
+            })
+            .collect();
+        pairs.join("\n")
+    }
+
+    #[test]
+    fn test_ook_commands() {
+        assert_eq!(
+            ook_to_bf(
+                "Ook. Ook? Ook? Ook. Ook. Ook. Ook! Ook! Ook! Ook. Ook. Ook! Ook! Ook? Ook? Ook!"
+            )
+            .unwrap(),
+            "><+-.,[]"
+        );
+        // Text between tokens is a comment
+        assert_eq!(
+            ook_to_bf("Ook.Ook. says the orangutan Ook!\tOok.").unwrap(),
+            "+."
+        );
+    }
+
+    #[test]
+    fn test_ook_hello_world() {
+        let program = ook_to_bf(&bf_to_ook(HELLO_WORLD)).unwrap();
+        assert_eq!(program, HELLO_WORLD);
+        assert_eq!(
+            run_program(&program, "", 0, 2048).unwrap(),
+            b"Hello World!\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_ook_pairs() {
+        assert!(matches!(
+            ook_to_bf("Ook. Ook. Ook? Ook?"),
+            Err(BrainfuckError::InvalidToken { pos: 10 })
+        ));
+        assert!(matches!(
+            ook_to_bf("Ook. Ook. Ook!"),
+            Err(BrainfuckError::InvalidToken { pos: 10 })
+        ));
+    }
+
+    #[test]
+    fn test_parse_command_map() {
+        assert_eq!(
+            parse_command_map("rlidoi[]").unwrap_err().to_string(),
+            "Command map uses 'i' more than once"
+        );
+        assert_eq!(
+            parse_command_map("rlid").unwrap_err().to_string(),
+            "Command map needs 8 characters for ><+-.,[], got 4"
+        );
+        assert_eq!(
+            parse_command_map("rlidoc()").unwrap(),
+            ['r', 'l', 'i', 'd', 'o', 'c', '(', ')']
+        );
+    }
+
+    #[test]
+    fn test_remapped_hello_world() {
+        // Every command swaps places with its opposite
+        let map = parse_command_map("<>-+,.][").unwrap();
+        let program: String = HELLO_WORLD
+            .chars()
+            .map(|command| match command {
+                '>' => '<',
+                '<' => '>',
+                '+' => '-',
+                '-' => '+',
+                '.' => ',',
+                ',' => '.',
+                '[' => ']',
+                _ => '[',
+            })
+            .collect();
+
+        let program = remap(&program, &map);
+        assert_eq!(program, HELLO_WORLD);
+        assert_eq!(
+            run_program(&program, "", 0, 2048).unwrap(),
+            b"Hello World!\n"
+        );
+    }
+
+    #[test]
+    fn test_remap_drops_unmapped_commands() {
+        let map = parse_command_map("abcdefgh").unwrap();
+        assert_eq!(remap("cc+[e] $", &map), "++. $");
+    }
+}
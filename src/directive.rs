@@ -0,0 +1,151 @@
+//! Settings a program file asks for in a `!bf:` comment on its first line,
+//! e.g. `!bf: size=30000 level=1 eof=zero`.
+//!
+//! The directive is read by the loader and removed from the program, as `!`
+//! and `-` in it would otherwise run at some levels. Command line flags take
+//! precedence over it.
+
+use anyhow::Error;
+
+use crate::{CellWidth, Cpu, EofPolicy, PointerPolicy};
+
+/// Starts a directive line
+pub const PREFIX: &str = "!bf:";
+
+/// The settings given by a directive, `None` where it does not mention one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Directive {
+    pub tape_size: Option<usize>,
+    pub level: Option<u8>,
+    pub cell_width: Option<CellWidth>,
+    pub eof_policy: Option<EofPolicy>,
+    pub pointer_policy: Option<PointerPolicy>,
+}
+
+impl Directive {
+    /// Parses whitespace separated `key=value` settings. Keys are `size`,
+    /// `level`, `cell-bits`, `eof` and `pointer`, with the values the command
+    /// line flags of the same name take.
+    pub fn parse(settings: &str) -> Result<Self, Error> {
+        let mut directive = Directive::default();
+
+        for setting in settings.split_whitespace() {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Directive setting without a value: {}", setting))?;
+            let invalid = || anyhow::anyhow!("Invalid value for {} in directive: {}", key, value);
+            match key {
+                "size" => directive.tape_size = Some(value.parse().map_err(|_| invalid())?),
+                "level" => directive.level = Some(crate::parse_level(value)?),
+                "cell-bits" => {
+                    directive.cell_width =
+                        Some(CellWidth::from_bits(value.parse().map_err(|_| invalid())?)?)
+                }
+                "eof" => directive.eof_policy = Some(EofPolicy::from_name(value)?),
+                "pointer" => directive.pointer_policy = Some(PointerPolicy::from_name(value)?),
+                _ => anyhow::bail!("Unknown directive setting: {}", key),
+            }
+        }
+
+        Ok(directive)
+    }
+
+    /// Settings of `other` replace those of `self`.
+    pub fn merge(self, other: Directive) -> Self {
+        Directive {
+            tape_size: other.tape_size.or(self.tape_size),
+            level: other.level.or(self.level),
+            cell_width: other.cell_width.or(self.cell_width),
+            eof_policy: other.eof_policy.or(self.eof_policy),
+            pointer_policy: other.pointer_policy.or(self.pointer_policy),
+        }
+    }
+
+    /// Applies the settings to `cpu`, except for the tape size, which is
+    /// needed to create it.
+    pub fn apply(&self, cpu: &mut Cpu) {
+        if let Some(level) = self.level {
+            cpu.level = level;
+        }
+        if let Some(cell_width) = self.cell_width {
+            cpu.cell_width = cell_width;
+        }
+        if let Some(eof_policy) = self.eof_policy {
+            cpu.eof_policy = eof_policy;
+        }
+        if let Some(pointer_policy) = self.pointer_policy {
+            cpu.pointer_policy = pointer_policy;
+        }
+    }
+}
+
+/// Removes a directive from the first line of `program`, which may be the
+/// empty line left by `strip_shebang`. The newline ending the directive is
+/// kept.
+pub fn strip_directive(program: &str) -> Result<(Directive, &str), Error> {
+    let start = usize::from(program.starts_with('\n'));
+    match program[start..].strip_prefix(PREFIX) {
+        Some(rest) => {
+            let end = rest.find('\n').unwrap_or(rest.len());
+            Ok((Directive::parse(&rest[..end])?, &rest[end..]))
+        }
+        None => Ok((Directive::default(), program)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strip_shebang;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Directive::parse(" size=30000  level=1 eof=minus-one ").unwrap(),
+            Directive {
+                tape_size: Some(30000),
+                level: Some(1),
+                eof_policy: Some(EofPolicy::MinusOne),
+                ..Directive::default()
+            }
+        );
+        assert_eq!(Directive::parse("").unwrap(), Directive::default());
+
+        assert!(Directive::parse("speed=11").is_err());
+        assert!(Directive::parse("level=4").is_err());
+        assert!(Directive::parse("size").is_err());
+        assert!(Directive::parse("size=big").is_err());
+        assert!(Directive::parse("eof=never").is_err());
+        assert!(Directive::parse("cell-bits=12").is_err());
+    }
+
+    #[test]
+    fn test_strip_directive() {
+        let (directive, program) = strip_directive("!bf: size=4\n+[>+]").unwrap();
+        assert_eq!(directive.tape_size, Some(4));
+        assert_eq!(program, "\n+[>+]");
+
+        // After a shebang
+        let source = "#!/usr/bin/env bf\n!bf: pointer=wrap\n+.";
+        let (directive, program) = strip_directive(strip_shebang(source)).unwrap();
+        assert_eq!(directive.pointer_policy, Some(PointerPolicy::Wrap));
+        assert_eq!(program, "\n+.");
+
+        // Only the first line counts
+        let (directive, program) = strip_directive("+\n!bf: size=4").unwrap();
+        assert_eq!(directive, Directive::default());
+        assert_eq!(program, "+\n!bf: size=4");
+    }
+
+    #[test]
+    fn test_merge_and_apply() {
+        let directive = Directive::parse("level=1 eof=zero")
+            .unwrap()
+            .merge(Directive::parse("eof=unchanged").unwrap());
+        let mut cpu = Cpu::new(String::new(), 16);
+        directive.apply(&mut cpu);
+        assert_eq!(cpu.level, 1);
+        assert_eq!(cpu.eof_policy, EofPolicy::Unchanged);
+        assert_eq!(cpu.pointer_policy, PointerPolicy::Clamp);
+    }
+}
@@ -0,0 +1,488 @@
+use crate::ir::Instruction;
+use crate::CellWidth;
+
+fn c_cell_type(cell_width: CellWidth) -> &'static str {
+    match cell_width {
+        CellWidth::U8 => "uint8_t",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+    }
+}
+
+fn rust_cell_type(cell_width: CellWidth) -> &'static str {
+    match cell_width {
+        CellWidth::U8 => "u8",
+        CellWidth::U16 => "u16",
+        CellWidth::U32 => "u32",
+    }
+}
+
+/// Appends one translated line per instruction, indenting loop bodies.
+/// Loops are expected to open with a trailing `{` and close with `}`.
+fn emit_body(
+    code: &mut String,
+    instructions: &[Instruction],
+    mut translate: impl FnMut(&Instruction) -> String,
+) {
+    let mut depth = 1;
+    for instruction in instructions {
+        if let Instruction::LoopEnd(_) = instruction {
+            depth -= 1;
+        }
+
+        code.push_str(&"    ".repeat(depth));
+        code.push_str(&translate(instruction));
+        code.push('\n');
+
+        if let Instruction::LoopStart(_) | Instruction::MultiplyLoop(_) = instruction {
+            depth += 1;
+        }
+    }
+}
+
+/// Translates compiled instructions into a standalone C program. The
+/// generated code does not bounds check the data pointer.
+pub fn emit_c(instructions: &[Instruction], tape_size: usize, cell_width: CellWidth) -> String {
+    let cell_type = c_cell_type(cell_width);
+
+    let mut code = String::new();
+    code.push_str("#include <stdint.h>\n#include <stdio.h>\n\n");
+    code.push_str(&format!("static {} tape[{}];\n\n", cell_type, tape_size));
+    code.push_str("int main(void) {\n");
+    code.push_str(&format!("    {} *p = tape;\n", cell_type));
+    code.push_str(&format!("    {} storage = 0;\n", cell_type));
+    code.push_str("    int c;\n");
+    code.push_str("    (void)storage;\n    (void)c;\n\n");
+
+    emit_body(&mut code, instructions, |instruction| match instruction {
+        Instruction::Add(amount) if *amount < 0 => format!("*p -= {};", -(*amount as i64)),
+        Instruction::Add(amount) => format!("*p += {};", amount),
+        Instruction::Move(amount) if *amount < 0 => format!("p -= {};", -amount),
+        Instruction::Move(amount) => format!("p += {};", amount),
+        Instruction::Output => String::from("putchar(*p);"),
+        Instruction::Input => String::from("if ((c = getchar()) != EOF) *p = c;"),
+        Instruction::Clear => String::from("*p = 0;"),
+        Instruction::ScanRight => String::from("while (*p) p += 1;"),
+        Instruction::ScanLeft => String::from("while (*p) p -= 1;"),
+        Instruction::LoopStart(_) | Instruction::MultiplyLoop(_) => String::from("while (*p) {"),
+        Instruction::LoopEnd(_) => String::from("}"),
+        Instruction::Halt => String::from("return 0;"),
+        Instruction::Store => String::from("storage = *p;"),
+        Instruction::Load => String::from("*p = storage;"),
+        Instruction::ShiftRight => String::from("*p >>= 1;"),
+        Instruction::ShiftLeft => String::from("*p <<= 1;"),
+        Instruction::Not => String::from("*p = ~*p;"),
+        Instruction::Xor => String::from("*p ^= storage;"),
+        Instruction::And => String::from("*p &= storage;"),
+        Instruction::Or => String::from("*p |= storage;"),
+        Instruction::Multiply => String::from("*p *= storage;"),
+        Instruction::Divide => String::from("if (storage) *p /= storage;"),
+        Instruction::AddStorage => String::from("*p += storage;"),
+        Instruction::SubtractStorage => String::from("*p -= storage;"),
+        Instruction::Modulo => String::from("if (storage) *p %= storage;"),
+        Instruction::Set(value) => format!("*p = {};", value & cell_width.mask()),
+        Instruction::DebugPrint => String::from("/* '#' is not supported */"),
+        Instruction::Unimplemented(command) => format!("/* '{}' is not supported */", command),
+    });
+
+    code.push_str("\n    return 0;\n}\n");
+    code
+}
+
+/// Translates compiled instructions into a standalone Rust program. Moving
+/// the data pointer off the tape panics.
+pub fn emit_rust(instructions: &[Instruction], tape_size: usize, cell_width: CellWidth) -> String {
+    let cell_type = rust_cell_type(cell_width);
+    let modulus = cell_width.mask() as i64 + 1;
+
+    let mut code = String::new();
+    code.push_str("#![allow(unused)]\nuse std::io::{Read, Write};\n\n");
+    code.push_str("fn main() {\n");
+    code.push_str(&format!(
+        "    let mut tape = vec![0{}; {}];\n",
+        cell_type, tape_size
+    ));
+    code.push_str("    let mut p: usize = 0;\n");
+    code.push_str(&format!("    let mut storage: {} = 0;\n", cell_type));
+    code.push_str("    let mut byte = [0u8; 1];\n");
+    code.push_str("    let mut stdin = std::io::stdin();\n");
+    code.push_str("    let mut stdout = std::io::stdout();\n\n");
+
+    emit_body(&mut code, instructions, |instruction| match instruction {
+        Instruction::Add(amount) => format!(
+            "tape[p] = tape[p].wrapping_add({});",
+            (*amount as i64).rem_euclid(modulus)
+        ),
+        Instruction::Move(amount) if *amount < 0 => format!("p -= {};", -amount),
+        Instruction::Move(amount) => format!("p += {};", amount),
+        Instruction::Output => String::from("stdout.write_all(&[tape[p] as u8]).unwrap();"),
+        Instruction::Input => format!(
+            "if stdin.read(&mut byte).unwrap() == 1 {{ tape[p] = byte[0] as {}; }}",
+            cell_type
+        ),
+        Instruction::Clear => String::from("tape[p] = 0;"),
+        Instruction::ScanRight => {
+            String::from("p += tape[p..].iter().position(|&cell| cell == 0).unwrap();")
+        }
+        Instruction::ScanLeft => {
+            String::from("p = tape[..=p].iter().rposition(|&cell| cell == 0).unwrap();")
+        }
+        Instruction::LoopStart(_) | Instruction::MultiplyLoop(_) => {
+            String::from("while tape[p] != 0 {")
+        }
+        Instruction::LoopEnd(_) => String::from("}"),
+        Instruction::Halt => String::from("stdout.flush().unwrap(); return;"),
+        Instruction::Store => String::from("storage = tape[p];"),
+        Instruction::Load => String::from("tape[p] = storage;"),
+        Instruction::ShiftRight => String::from("tape[p] >>= 1;"),
+        Instruction::ShiftLeft => String::from("tape[p] <<= 1;"),
+        Instruction::Not => String::from("tape[p] = !tape[p];"),
+        Instruction::Xor => String::from("tape[p] ^= storage;"),
+        Instruction::And => String::from("tape[p] &= storage;"),
+        Instruction::Or => String::from("tape[p] |= storage;"),
+        Instruction::Multiply => String::from("tape[p] = tape[p].wrapping_mul(storage);"),
+        Instruction::Divide => String::from("if storage != 0 { tape[p] /= storage; }"),
+        Instruction::AddStorage => String::from("tape[p] = tape[p].wrapping_add(storage);"),
+        Instruction::SubtractStorage => String::from("tape[p] = tape[p].wrapping_sub(storage);"),
+        Instruction::Modulo => String::from("if storage != 0 { tape[p] %= storage; }"),
+        Instruction::Set(value) => format!("tape[p] = {};", value & cell_width.mask()),
+        Instruction::DebugPrint => String::from("// '#' is not supported"),
+        Instruction::Unimplemented(command) => format!("// '{}' is not supported", command),
+    });
+
+    code.push_str("\n    stdout.flush().unwrap();\n}\n");
+    code
+}
+
+/// The operand size, cell size in bytes, and the low parts of `eax` and
+/// `r12` that hold a cell.
+fn asm_cell(cell_width: CellWidth) -> (&'static str, usize, &'static str, &'static str) {
+    match cell_width {
+        CellWidth::U8 => ("BYTE", 1, "al", "r12b"),
+        CellWidth::U16 => ("WORD", 2, "ax", "r12w"),
+        CellWidth::U32 => ("DWORD", 4, "eax", "r12d"),
+    }
+}
+
+/// Translates compiled instructions into x86-64 assembly for GNU as on
+/// Linux, a standalone program without libc. The data pointer lives in
+/// `rbx` and the storage of the extended levels in `r12`, the tape is a
+/// buffer in `.bss`, and `.` and `,` make one `write` or `read` system call
+/// per byte. Loops become a check that jumps past the labelled body and a
+/// check at its end that jumps back. Besides moves, arithmetic and logic on
+/// the cell at `rbx` and those jumps, only `mul` and `div` are used, for the
+/// commands of level 2. The data pointer is not bounds checked.
+pub fn emit_asm(instructions: &[Instruction], tape_size: usize, cell_width: CellWidth) -> String {
+    let (size, bytes, value, storage) = asm_cell(cell_width);
+    let mask = cell_width.mask() as i64;
+    let cell = format!("{} PTR [rbx]", size);
+    // Loads the cell into `eax`, zero-extended
+    let load = match cell_width {
+        CellWidth::U32 => format!("mov eax, {}", cell),
+        _ => format!("movzx eax, {}", cell),
+    };
+
+    let mut code = String::new();
+    code.push_str("# Build with: as program.s -o program.o && ld program.o -o program\n");
+    code.push_str("    .intel_syntax noprefix\n    .globl _start\n\n");
+    code.push_str("    .bss\n");
+    code.push_str(&format!("tape:\n    .zero {}\n", tape_size * bytes));
+    code.push_str("input_byte:\n    .zero 1\n\n");
+    code.push_str("    .text\n_start:\n");
+    code.push_str("    lea rbx, [rip + tape]\n    xor r12d, r12d\n");
+
+    for (index, instruction) in instructions.iter().enumerate() {
+        let lines = match instruction {
+            Instruction::Add(amount) => {
+                vec![format!("add {}, {}", cell, (*amount as i64) & mask)]
+            }
+            Instruction::Move(amount) if *amount < 0 => {
+                vec![format!("sub rbx, {}", -amount * bytes as isize)]
+            }
+            Instruction::Move(amount) => vec![format!("add rbx, {}", amount * bytes as isize)],
+            Instruction::Output => vec![
+                String::from("mov eax, 1"),
+                String::from("mov edi, 1"),
+                String::from("mov rsi, rbx"),
+                String::from("mov edx, 1"),
+                String::from("syscall"),
+            ],
+            // The cell is left as it is at the end of the input
+            Instruction::Input => vec![
+                String::from("xor eax, eax"),
+                String::from("xor edi, edi"),
+                String::from("lea rsi, [rip + input_byte]"),
+                String::from("mov edx, 1"),
+                String::from("syscall"),
+                String::from("cmp rax, 1"),
+                format!("jne .Linput{}", index),
+                String::from("movzx eax, BYTE PTR [rip + input_byte]"),
+                format!("mov {}, {}", cell, value),
+                format!(".Linput{}:", index),
+            ],
+            Instruction::Clear => vec![format!("mov {}, 0", cell)],
+            Instruction::ScanRight | Instruction::ScanLeft => vec![
+                format!(".Lscan{}:", index),
+                format!("cmp {}, 0", cell),
+                format!("je .Lscanned{}", index),
+                match instruction {
+                    Instruction::ScanRight => format!("add rbx, {}", bytes),
+                    _ => format!("sub rbx, {}", bytes),
+                },
+                format!("jmp .Lscan{}", index),
+                format!(".Lscanned{}:", index),
+            ],
+            Instruction::LoopStart(_) | Instruction::MultiplyLoop(_) => vec![
+                format!("cmp {}, 0", cell),
+                format!("je .Lend{}", index),
+                format!(".Lbody{}:", index),
+            ],
+            Instruction::LoopEnd(start) => vec![
+                format!("cmp {}, 0", cell),
+                format!("jne .Lbody{}", start),
+                format!(".Lend{}:", start),
+            ],
+            Instruction::Halt => vec![String::from("jmp .Lexit")],
+            Instruction::Store => vec![load.clone(), String::from("mov r12d, eax")],
+            Instruction::Load => vec![format!("mov {}, {}", cell, storage)],
+            Instruction::ShiftRight => vec![format!("shr {}, 1", cell)],
+            Instruction::ShiftLeft => vec![format!("shl {}, 1", cell)],
+            Instruction::Not => vec![format!("not {}", cell)],
+            Instruction::Xor => vec![format!("xor {}, {}", cell, storage)],
+            Instruction::And => vec![format!("and {}, {}", cell, storage)],
+            Instruction::Or => vec![format!("or {}, {}", cell, storage)],
+            Instruction::Multiply => vec![
+                load.clone(),
+                String::from("mul r12d"),
+                format!("mov {}, {}", cell, value),
+            ],
+            // Like the C translation, dividing by zero leaves the cell alone
+            Instruction::Divide | Instruction::Modulo => vec![
+                String::from("test r12d, r12d"),
+                format!("jz .Ldivided{}", index),
+                load.clone(),
+                String::from("xor edx, edx"),
+                String::from("div r12d"),
+                match instruction {
+                    Instruction::Divide => format!("mov {}, {}", cell, value),
+                    _ => format!("mov {}, {}", cell, asm_remainder(cell_width)),
+                },
+                format!(".Ldivided{}:", index),
+            ],
+            Instruction::AddStorage => vec![format!("add {}, {}", cell, storage)],
+            Instruction::SubtractStorage => vec![format!("sub {}, {}", cell, storage)],
+            Instruction::Set(literal) => vec![format!("mov {}, {}", cell, literal & mask as u32)],
+            Instruction::DebugPrint => vec![String::from("# '#' is not supported")],
+            Instruction::Unimplemented(command) => {
+                vec![format!("# '{}' is not supported", command)]
+            }
+        };
+
+        for line in lines {
+            // Labels start their line, instructions are indented
+            if !line.ends_with(':') {
+                code.push_str("    ");
+            }
+            code.push_str(&line);
+            code.push('\n');
+        }
+    }
+
+    code.push_str("\n.Lexit:\n    mov eax, 60\n    xor edi, edi\n    syscall\n");
+    code
+}
+
+/// The low part of `edx`, where `div` leaves the remainder, that holds a cell.
+fn asm_remainder(cell_width: CellWidth) -> &'static str {
+    match cell_width {
+        CellWidth::U8 => "dl",
+        CellWidth::U16 => "dx",
+        CellWidth::U32 => "edx",
+    }
+}
+
+/// Lists compiled instructions one per line with their index and the offset
+/// of the command they were compiled from, for seeing what the optimizer did.
+/// `offsets` is indexed like `instructions`, see `ir::compile_with_offsets`.
+pub fn emit_ir(instructions: &[Instruction], offsets: &[usize]) -> String {
+    let mut listing = String::from("index  offset  instruction\n");
+    for (index, (instruction, offset)) in instructions.iter().zip(offsets).enumerate() {
+        let instruction = match instruction {
+            Instruction::LoopStart(end) => format!("LoopStart -> {}", end),
+            Instruction::MultiplyLoop(end) => format!("MultiplyLoop -> {}", end),
+            Instruction::LoopEnd(start) => format!("LoopEnd -> {}", start),
+            other => format!("{:?}", other),
+        };
+        listing.push_str(&format!("{:>5}  {:>6}  {}\n", index, offset, instruction));
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{compile, compile_with_offsets, Options};
+    use std::process::Command;
+
+    const HELLO_WORLD: &str = "++++++++++[>+++++++>++++++++++>+++>+<<<<-]>++.>+.+++++++\
+        ..+++.>++.<<+++++++++++++++.>.+++.------.--------.>+.>.";
+
+    #[test]
+    fn test_emit_c_hello_world() {
+        let instructions = compile(HELLO_WORLD.as_bytes(), 0).unwrap();
+        let code = emit_c(&instructions, 2048, CellWidth::U8);
+
+        assert!(code.starts_with("#include <stdint.h>\n#include <stdio.h>\n"));
+        assert!(code.contains("static uint8_t tape[2048];"));
+        assert!(
+            code.contains("    *p += 10;\n    while (*p) {\n        p += 1;\n        *p += 7;\n")
+        );
+        assert!(code.contains("        p -= 4;\n        *p -= 1;\n    }\n"));
+        assert!(code.contains("    putchar(*p);\n"));
+        assert_eq!(code.matches('{').count(), code.matches('}').count());
+    }
+
+    #[test]
+    fn test_emit_c_cell_width_and_clear() {
+        let instructions = compile(b"+[-]", 0).unwrap();
+        let code = emit_c(&instructions, 16, CellWidth::U16);
+        assert!(code.contains("static uint16_t tape[16];"));
+        assert!(code.contains("    *p = 0;\n"));
+        assert!(!code.contains("while"));
+    }
+
+    #[test]
+    fn test_emit_rust_hello_world_structure() {
+        let instructions = compile(HELLO_WORLD.as_bytes(), 0).unwrap();
+        let code = emit_rust(&instructions, 2048, CellWidth::U8);
+
+        assert!(code.contains("let mut tape = vec![0u8; 2048];"));
+        assert!(code.contains(
+            "    tape[p] = tape[p].wrapping_add(10);\n    while tape[p] != 0 {\n        p += 1;\n"
+        ));
+        // Negative amounts wrap around the cell width
+        assert!(code.contains("        tape[p] = tape[p].wrapping_add(255);\n    }\n"));
+        assert!(code.contains("    stdout.write_all(&[tape[p] as u8]).unwrap();\n"));
+    }
+
+    #[test]
+    fn test_emit_rust_compiles_and_runs() {
+        let instructions = compile(HELLO_WORLD.as_bytes(), 0).unwrap();
+        let code = emit_rust(&instructions, 2048, CellWidth::U8);
+
+        let directory = std::env::temp_dir().join(format!("bf-emit-rust-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("hello.rs");
+        let binary = directory.join("hello");
+        std::fs::write(&source, code).unwrap();
+
+        let status = Command::new(std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into()))
+            .arg(&source)
+            .arg("-o")
+            .arg(&binary)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let output = Command::new(&binary).output().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "Hello World!\n");
+    }
+
+    #[test]
+    fn test_emit_asm_loop_labels() {
+        let instructions = compile(b"++[>+<-]", 0).unwrap();
+        let code = emit_asm(&instructions, 16, CellWidth::U8);
+
+        assert!(code.contains("tape:\n    .zero 16\n"));
+        assert!(code.contains(concat!(
+            "    add BYTE PTR [rbx], 2\n",
+            "    cmp BYTE PTR [rbx], 0\n",
+            "    je .Lend1\n",
+            ".Lbody1:\n",
+            "    add rbx, 1\n",
+            "    add BYTE PTR [rbx], 1\n",
+            "    sub rbx, 1\n",
+            "    add BYTE PTR [rbx], 255\n",
+            "    cmp BYTE PTR [rbx], 0\n",
+            "    jne .Lbody1\n",
+            ".Lend1:\n",
+        )));
+
+        // Wider cells move the pointer by their size
+        let code = emit_asm(&instructions, 16, CellWidth::U32);
+        assert!(code.contains("tape:\n    .zero 64\n"));
+        assert!(code.contains("    add rbx, 4\n    add DWORD PTR [rbx], 1\n"));
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn test_emit_asm_assembles_and_runs() {
+        let instructions = compile(HELLO_WORLD.as_bytes(), 0).unwrap();
+        let code = emit_asm(&instructions, 2048, CellWidth::U8);
+
+        let directory = std::env::temp_dir().join(format!("bf-emit-asm-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let source = directory.join("hello.s");
+        let object = directory.join("hello.o");
+        let binary = directory.join("hello");
+        std::fs::write(&source, code).unwrap();
+
+        let assembled = Command::new("as")
+            .arg(&source)
+            .arg("-o")
+            .arg(&object)
+            .status();
+        // Leave it to the other tests where there are no binutils
+        if !assembled.is_ok_and(|status| status.success()) {
+            std::fs::remove_dir_all(&directory).unwrap();
+            return;
+        }
+        let linked = Command::new("ld")
+            .arg(&object)
+            .arg("-o")
+            .arg(&binary)
+            .status()
+            .unwrap();
+        assert!(linked.success());
+
+        let output = Command::new(&binary).output().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "Hello World!\n");
+    }
+
+    #[test]
+    fn test_emit_scan_loops() {
+        let instructions = compile(b"+>+>+[<]>[>]", 0).unwrap();
+        let c = emit_c(&instructions, 16, CellWidth::U8);
+        assert!(c.contains("    while (*p) p -= 1;\n    p += 1;\n    while (*p) p += 1;\n"));
+
+        let rust = emit_rust(&instructions, 16, CellWidth::U8);
+        assert!(rust.contains("p = tape[..=p].iter().rposition(|&cell| cell == 0).unwrap();"));
+        assert!(rust.contains("p += tape[p..].iter().position(|&cell| cell == 0).unwrap();"));
+    }
+
+    #[test]
+    fn test_emit_ir() {
+        let options = Options {
+            opt_level: 2,
+            ..Options::default()
+        };
+        let (instructions, offsets) = compile_with_offsets(b"++++ [-]>[->+<]", &options).unwrap();
+        assert_eq!(
+            emit_ir(&instructions, &offsets),
+            concat!(
+                "index  offset  instruction\n",
+                "    0       0  Add(4)\n",
+                "    1       5  Clear\n",
+                "    2       8  Move(1)\n",
+                "    3       9  MultiplyLoop -> 8\n",
+                "    4      10  Add(-1)\n",
+                "    5      11  Move(1)\n",
+                "    6      12  Add(1)\n",
+                "    7      13  Move(-1)\n",
+                "    8      14  LoopEnd -> 3\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Errors produced while compiling or running a program. Positions are byte
+/// offsets into the program source.
+#[derive(Debug)]
+pub enum BrainfuckError {
+    /// A `[` without a matching `]`, or the other way around
+    UnmatchedBracket {
+        pos: usize,
+    },
+    /// Source in another dialect that does not translate to brainfuck
+    InvalidToken {
+        pos: usize,
+    },
+    /// `/` or `%` with a storage value of zero
+    DivisionByZero {
+        pos: usize,
+    },
+    /// The data pointer left the tape under `PointerPolicy::Error`
+    PointerOutOfBounds {
+        pos: usize,
+        pointer: isize,
+    },
+    /// `+` or `-` took a cell out of its range under `CellOverflow::Error`
+    CellOutOfRange {
+        pos: usize,
+        value: i64,
+    },
+    /// The program ran for more steps than `max_steps`
+    StepLimitExceeded,
+    /// The program tried to print more than `max_output` bytes
+    OutputLimitExceeded,
+    /// `Cpu::interrupt` was set while the program ran
+    Interrupted {
+        pos: usize,
+    },
+    /// The program ran for longer than `timeout`
+    TimedOut,
+    /// A `[` nested more than `max_depth` loops deep
+    LoopTooDeep {
+        pos: usize,
+        max_depth: usize,
+    },
+    /// `,` found no input left under `EofPolicy::Error`
+    EndOfInput {
+        pos: usize,
+    },
+    /// `,` read a byte with the high bit set under `AsciiPolicy::Error`
+    NonAsciiInput {
+        pos: usize,
+        byte: u8,
+    },
+    /// An extended command the interpreter does not implement yet
+    UnimplementedCommand {
+        command: char,
+        pos: usize,
+    },
+    /// An Extended Brainfuck level above `MAX_LEVEL`
+    InvalidLevel {
+        level: u8,
+    },
+    Io(io::Error),
+}
+
+impl BrainfuckError {
+    /// The position in the program the error happened at, if it has one
+    pub fn pos(&self) -> Option<usize> {
+        match self {
+            BrainfuckError::UnmatchedBracket { pos }
+            | BrainfuckError::InvalidToken { pos }
+            | BrainfuckError::DivisionByZero { pos }
+            | BrainfuckError::PointerOutOfBounds { pos, .. }
+            | BrainfuckError::CellOutOfRange { pos, .. }
+            | BrainfuckError::Interrupted { pos }
+            | BrainfuckError::LoopTooDeep { pos, .. }
+            | BrainfuckError::EndOfInput { pos }
+            | BrainfuckError::NonAsciiInput { pos, .. }
+            | BrainfuckError::UnimplementedCommand { pos, .. } => Some(*pos),
+            BrainfuckError::StepLimitExceeded
+            | BrainfuckError::OutputLimitExceeded
+            | BrainfuckError::TimedOut
+            | BrainfuckError::InvalidLevel { .. }
+            | BrainfuckError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for BrainfuckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BrainfuckError::UnmatchedBracket { pos } => {
+                write!(f, "Unmatched bracket at position {}", pos)
+            }
+            BrainfuckError::InvalidToken { pos } => {
+                write!(f, "Invalid token at position {}", pos)
+            }
+            BrainfuckError::DivisionByZero { pos } => {
+                write!(f, "Division by zero at position {}", pos)
+            }
+            BrainfuckError::PointerOutOfBounds { pos, pointer } => {
+                write!(
+                    f,
+                    "Data pointer moved off the tape to {} at position {}",
+                    pointer, pos
+                )
+            }
+            BrainfuckError::CellOutOfRange { pos, value } => write!(
+                f,
+                "Cell value went out of range to {} at position {}",
+                value, pos
+            ),
+            BrainfuckError::StepLimitExceeded => write!(f, "Step limit exceeded"),
+            BrainfuckError::OutputLimitExceeded => write!(f, "Output limit exceeded"),
+            BrainfuckError::Interrupted { pos } => {
+                write!(f, "Interrupted at position {}", pos)
+            }
+            BrainfuckError::TimedOut => write!(f, "Timed out"),
+            BrainfuckError::LoopTooDeep { pos, max_depth } => write!(
+                f,
+                "Loop nested deeper than {} at position {}",
+                max_depth, pos
+            ),
+            BrainfuckError::EndOfInput { pos } => {
+                write!(f, "No input left to read at position {}", pos)
+            }
+            BrainfuckError::NonAsciiInput { pos, byte } => {
+                write!(f, "Read non-ASCII byte 0x{:02x} at position {}", byte, pos)
+            }
+            BrainfuckError::UnimplementedCommand { command, pos } => write!(
+                f,
+                "Command '{}' at position {} is not implemented yet",
+                command, pos
+            ),
+            BrainfuckError::InvalidLevel { level } => write!(
+                f,
+                "Invalid level: {} (expected 0 to {})",
+                level,
+                crate::MAX_LEVEL
+            ),
+            BrainfuckError::Io(error) => write!(f, "I/O error: {}", error),
+        }
+    }
+}
+
+impl Error for BrainfuckError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BrainfuckError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BrainfuckError {
+    fn from(error: io::Error) -> Self {
+        BrainfuckError::Io(error)
+    }
+}
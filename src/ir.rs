@@ -0,0 +1,658 @@
+use crate::error::BrainfuckError;
+
+/// A single operation of a compiled program. Runs of `+`/`-` and `>`/`<` are
+/// folded together, `[-]`/`[+]` becomes `Clear`, `[>]`/`[<]` become scans
+/// and multiply loops like `[->++<]` start with `MultiplyLoop`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// Add a possibly negative amount to the current cell
+    Add(i32),
+    /// Move the data pointer by a possibly negative amount
+    Move(isize),
+    Output,
+    Input,
+    /// Set the current cell to zero
+    Clear,
+    /// Move right until the current cell is zero
+    ScanRight,
+    /// Move left until the current cell is zero
+    ScanLeft,
+    /// Jump past the matching `LoopEnd` at the given index if the cell is zero
+    LoopStart(usize),
+    /// Jump back to the matching `LoopStart` at the given index if the cell
+    /// is not zero
+    LoopEnd(usize),
+    /// A `LoopStart` whose body only adds and moves, ends where it started
+    /// and takes one off the starting cell. Such a loop adds a multiple of
+    /// the cell to the cells it visits and clears it, which can be done
+    /// without running the body once per iteration.
+    MultiplyLoop(usize),
+
+    // Extended commands I
+    /// `@`, end the program as if it had run to its end
+    Halt,
+    Store,
+    Load,
+    ShiftRight,
+    ShiftLeft,
+    Not,
+    Xor,
+    And,
+    Or,
+
+    // Extended commands II
+    Multiply,
+    Divide,
+    AddStorage,
+    SubtractStorage,
+    Modulo,
+
+    // Extended commands III
+    /// Set the current cell to a value given as a hex literal
+    Set(u32),
+
+    /// `#` with `Options::debug_hash`, print the cells around the pointer
+    DebugPrint,
+    /// A command the interpreter does not implement yet
+    Unimplemented(char),
+}
+
+impl Instruction {
+    /// Whether the instruction may change the current cell, which is the
+    /// only cell any instruction writes. A `MultiplyLoop` run in one go
+    /// writes other cells too, and is left to the caller.
+    pub fn writes_cell(self) -> bool {
+        matches!(
+            self,
+            Instruction::Add(_)
+                | Instruction::Input
+                | Instruction::Clear
+                | Instruction::Load
+                | Instruction::ShiftRight
+                | Instruction::ShiftLeft
+                | Instruction::Not
+                | Instruction::Xor
+                | Instruction::And
+                | Instruction::Or
+                | Instruction::Multiply
+                | Instruction::Divide
+                | Instruction::AddStorage
+                | Instruction::SubtractStorage
+                | Instruction::Modulo
+                | Instruction::Set(_)
+        )
+    }
+}
+
+/// The highest optimization level. Level 0 compiles every command to exactly
+/// one instruction, level 1 folds runs of `+`/`-` and `>`/`<`, and level 2
+/// also turns `[-]`, `[>]` and `[<]` into single instructions and marks
+/// multiply loops.
+pub const MAX_OPT_LEVEL: u8 = 2;
+
+/// What `compile_with_offsets` compiles and how.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    /// Extended Brainfuck level, commands above it are comments
+    pub level: u8,
+    /// Extended commands to compile in place of those of `level`
+    pub enabled: Option<crate::CommandSet>,
+    /// Which optimizations run, see `MAX_OPT_LEVEL`
+    pub opt_level: u8,
+    /// Compile `#` to `DebugPrint` at every level
+    pub debug_hash: bool,
+    /// Reject loops nested deeper than this
+    pub max_loop_depth: Option<usize>,
+}
+
+/// Compiles `program` into optimized instructions, ignoring commands that are
+/// not active at `level`.
+pub fn compile(program: &[u8], level: u8) -> Result<Vec<Instruction>, BrainfuckError> {
+    let options = Options {
+        level,
+        opt_level: MAX_OPT_LEVEL,
+        ..Options::default()
+    };
+    Ok(compile_with_offsets(program, &options)?.0)
+}
+
+/// Compiles `program` into instructions along with the source offset each
+/// instruction starts at. At `opt_level` 0 every command, or hex literal,
+/// becomes exactly one instruction.
+pub fn compile_with_offsets(
+    program: &[u8],
+    options: &Options,
+) -> Result<(Vec<Instruction>, Vec<usize>), BrainfuckError> {
+    if options.level > crate::MAX_LEVEL {
+        return Err(BrainfuckError::InvalidLevel {
+            level: options.level,
+        });
+    }
+
+    let mut instructions: Vec<Instruction> = Vec::new();
+    let mut offsets: Vec<usize> = Vec::new();
+
+    // Offset just past the last hex digit, consecutive digits form one literal
+    let mut literal_end = None;
+    // Loops open at the current command, counted before folding
+    let mut depth: usize = 0;
+
+    for (position, &byte) in program.iter().enumerate() {
+        let command = byte as char;
+        if command == '#' && options.debug_hash {
+            instructions.push(Instruction::DebugPrint);
+            offsets.push(position);
+            continue;
+        }
+        if !crate::is_enabled(command, options.level, options.enabled) {
+            continue;
+        }
+
+        if let Some(digit) = command.to_digit(16) {
+            match instructions.last_mut() {
+                Some(Instruction::Set(value)) if literal_end == Some(position) => {
+                    *value = value.wrapping_mul(16).wrapping_add(digit)
+                }
+                _ => {
+                    instructions.push(Instruction::Set(digit));
+                    offsets.push(position);
+                }
+            }
+            literal_end = Some(position + 1);
+            continue;
+        }
+
+        match command {
+            '[' => {
+                depth += 1;
+                if let Some(max_depth) = options.max_loop_depth.filter(|&max| depth > max) {
+                    return Err(BrainfuckError::LoopTooDeep {
+                        pos: position,
+                        max_depth,
+                    });
+                }
+            }
+            ']' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+
+        let instruction = match command {
+            '+' => Instruction::Add(1),
+            '-' => Instruction::Add(-1),
+            '>' => Instruction::Move(1),
+            '<' => Instruction::Move(-1),
+            '.' => Instruction::Output,
+            ',' => Instruction::Input,
+            // Jump targets are filled in once the program is folded
+            '[' => Instruction::LoopStart(0),
+            ']' => Instruction::LoopEnd(0),
+            '@' => Instruction::Halt,
+            '$' => Instruction::Store,
+            '!' => Instruction::Load,
+            '}' => Instruction::ShiftRight,
+            '{' => Instruction::ShiftLeft,
+            '~' => Instruction::Not,
+            '^' => Instruction::Xor,
+            '&' => Instruction::And,
+            '|' => Instruction::Or,
+            '*' => Instruction::Multiply,
+            '/' => Instruction::Divide,
+            '=' => Instruction::AddStorage,
+            '_' => Instruction::SubtractStorage,
+            '%' => Instruction::Modulo,
+            _ => Instruction::Unimplemented(command),
+        };
+
+        if options.opt_level > 0 {
+            let fold_loops = options.opt_level >= 2;
+            push_folded(
+                &mut instructions,
+                &mut offsets,
+                instruction,
+                position,
+                fold_loops,
+            );
+        } else {
+            instructions.push(instruction);
+            offsets.push(position);
+        }
+    }
+
+    link_loops(&mut instructions, &offsets)?;
+
+    Ok((instructions, offsets))
+}
+
+/// Points every `LoopStart` and `LoopEnd` at its matching partner.
+fn link_loops(instructions: &mut [Instruction], offsets: &[usize]) -> Result<(), BrainfuckError> {
+    let mut open_loops: Vec<usize> = Vec::new();
+
+    for index in 0..instructions.len() {
+        match instructions[index] {
+            Instruction::LoopStart(_) | Instruction::MultiplyLoop(_) => open_loops.push(index),
+            Instruction::LoopEnd(_) => {
+                let start = open_loops.pop().ok_or(BrainfuckError::UnmatchedBracket {
+                    pos: offsets[index],
+                })?;
+                instructions[start] = match instructions[start] {
+                    Instruction::MultiplyLoop(_) => Instruction::MultiplyLoop(index),
+                    _ => Instruction::LoopStart(index),
+                };
+                instructions[index] = Instruction::LoopEnd(start);
+            }
+            _ => (),
+        }
+    }
+
+    match open_loops.pop() {
+        Some(start) => Err(BrainfuckError::UnmatchedBracket {
+            pos: offsets[start],
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Appends `instruction`, merging it with the previous instructions where
+/// possible. Loops are only replaced with `fold_loops`. `offsets` is kept in
+/// step with `instructions`.
+fn push_folded(
+    instructions: &mut Vec<Instruction>,
+    offsets: &mut Vec<usize>,
+    instruction: Instruction,
+    offset: usize,
+    fold_loops: bool,
+) {
+    if let (Instruction::LoopEnd(_), true) = (instruction, fold_loops) {
+        let replacement = match instructions[..] {
+            [.., Instruction::LoopStart(_), Instruction::Add(1) | Instruction::Add(-1)] => {
+                Some(Instruction::Clear)
+            }
+            [.., Instruction::LoopStart(_), Instruction::Move(1)] => Some(Instruction::ScanRight),
+            [.., Instruction::LoopStart(_), Instruction::Move(-1)] => Some(Instruction::ScanLeft),
+            _ => None,
+        };
+
+        // The replacement takes the place, and offset, of the loop start
+        if let Some(replacement) = replacement {
+            instructions.truncate(instructions.len() - 2);
+            offsets.truncate(offsets.len() - 1);
+            instructions.push(replacement);
+            return;
+        }
+
+        // The body stays, for where the loop cannot be run in one go
+        if let Some(start) = multiply_loop_start(instructions) {
+            instructions[start] = Instruction::MultiplyLoop(0);
+        }
+    }
+
+    match (instructions.last_mut(), instruction) {
+        (Some(Instruction::Add(total)), Instruction::Add(amount)) => {
+            *total = total.wrapping_add(amount);
+            if *total == 0 {
+                instructions.pop();
+                offsets.pop();
+            }
+        }
+        // Only moves in the same direction fold, so that a clamped pointer
+        // ends up where the individual moves would take it
+        (Some(Instruction::Move(total)), Instruction::Move(amount))
+            if total.signum() == amount.signum() =>
+        {
+            *total += amount
+        }
+        _ => {
+            instructions.push(instruction);
+            offsets.push(offset);
+        }
+    }
+}
+
+/// Returns the index of the `LoopStart` of the loop about to be closed if
+/// it is a multiply loop, see `Instruction::MultiplyLoop`.
+fn multiply_loop_start(instructions: &[Instruction]) -> Option<usize> {
+    let start = instructions.iter().rposition(|instruction| {
+        !matches!(instruction, Instruction::Add(_) | Instruction::Move(_))
+    })?;
+    if !matches!(instructions[start], Instruction::LoopStart(_)) {
+        return None;
+    }
+
+    // Net pointer motion, and net change of the cell the loop starts on
+    let mut pointer: isize = 0;
+    let mut counter: i64 = 0;
+    for instruction in &instructions[start + 1..] {
+        match *instruction {
+            Instruction::Move(amount) => pointer += amount,
+            Instruction::Add(amount) if pointer == 0 => counter += amount as i64,
+            _ => (),
+        }
+    }
+    (pointer == 0 && counter == -1).then_some(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_folds_runs() {
+        let instructions = compile(b"+++ comment -- >>><", 0).unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Add(1),
+                Instruction::Move(3),
+                Instruction::Move(-1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cancelling_runs_disappear() {
+        assert_eq!(compile(b"+-+ -", 0).unwrap(), vec![]);
+        // `<>` on the first cell of a clamped tape does not return to it
+        assert_eq!(
+            compile(b"<>", 0).unwrap(),
+            vec![Instruction::Move(-1), Instruction::Move(1)]
+        );
+    }
+
+    #[test]
+    fn test_clear_loops() {
+        let instructions = compile(b"+[-]>[+]", 0).unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Add(1),
+                Instruction::Clear,
+                Instruction::Move(1),
+                Instruction::Clear,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unmatched_brackets() {
+        assert!(matches!(
+            compile(b"[[]", 0),
+            Err(BrainfuckError::UnmatchedBracket { pos: 0 })
+        ));
+        assert!(matches!(
+            compile(b"[]]", 0),
+            Err(BrainfuckError::UnmatchedBracket { pos: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_extended_commands_follow_level() {
+        assert_eq!(compile(b"$*", 0).unwrap(), vec![]);
+        assert_eq!(compile(b"$*", 1).unwrap(), vec![Instruction::Store]);
+        assert_eq!(
+            compile(b"$*", 2).unwrap(),
+            vec![Instruction::Store, Instruction::Multiply]
+        );
+    }
+
+    #[test]
+    fn test_enabled_commands() {
+        let options = Options {
+            enabled: Some(crate::CommandSet::parse("*").unwrap()),
+            ..Options::default()
+        };
+        // Only `*` is enabled, even at a level that has `/`
+        for level in [0, 2] {
+            let options = Options { level, ..options };
+            assert_eq!(
+                compile_with_offsets(b"+*/$", &options).unwrap(),
+                (vec![Instruction::Add(1), Instruction::Multiply], vec![0, 1])
+            );
+        }
+    }
+
+    #[test]
+    fn test_extended_commands() {
+        assert_eq!(
+            compile(b"@$!}{~^&|*/=_%?", 2).unwrap(),
+            vec![
+                Instruction::Halt,
+                Instruction::Store,
+                Instruction::Load,
+                Instruction::ShiftRight,
+                Instruction::ShiftLeft,
+                Instruction::Not,
+                Instruction::Xor,
+                Instruction::And,
+                Instruction::Or,
+                Instruction::Multiply,
+                Instruction::Divide,
+                Instruction::AddStorage,
+                Instruction::SubtractStorage,
+                Instruction::Modulo,
+                Instruction::Unimplemented('?'),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hex_literals() {
+        assert_eq!(compile(b"41", 2).unwrap(), vec![]);
+        assert_eq!(
+            compile(b"41.F f 0A", 3).unwrap(),
+            vec![
+                Instruction::Set(0x41),
+                Instruction::Output,
+                Instruction::Set(0xf),
+                Instruction::Set(0xa),
+            ]
+        );
+        // Literals are not an optimization, they stay whole without folding too
+        let (instructions, offsets) = compile_with_offsets(
+            b"+FF+",
+            &Options {
+                level: 3,
+                opt_level: 0,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Add(1),
+                Instruction::Set(0xff),
+                Instruction::Add(1)
+            ]
+        );
+        assert_eq!(offsets, vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn test_scan_loops() {
+        let instructions = compile(b"[>]<[<]>[>>]", 0).unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::ScanRight,
+                Instruction::Move(-1),
+                Instruction::ScanLeft,
+                Instruction::Move(1),
+                Instruction::LoopStart(6),
+                Instruction::Move(2),
+                Instruction::LoopEnd(4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multiply_loops() {
+        // Moving, copying to two cells, and multiplying by 3 to the left
+        let instructions = compile(b"[->+<]>[->+>+<<]>[<+++>-]", 0).unwrap();
+        assert_eq!(
+            instructions[..6],
+            [
+                Instruction::MultiplyLoop(5),
+                Instruction::Add(-1),
+                Instruction::Move(1),
+                Instruction::Add(1),
+                Instruction::Move(-1),
+                Instruction::LoopEnd(0),
+            ]
+        );
+        assert_eq!(instructions[7], Instruction::MultiplyLoop(14));
+        assert_eq!(instructions[16], Instruction::MultiplyLoop(21));
+
+        // Unbalanced, counting down by two, with output, nested, and at
+        // opt level 1
+        for program in [&b"[->+]"[..], b"[-->+<]", b"[->.<]", b"[->[-]<]"] {
+            assert!(!compile(program, 0)
+                .unwrap()
+                .iter()
+                .any(|instruction| matches!(instruction, Instruction::MultiplyLoop(_))));
+        }
+        let options = Options {
+            opt_level: 1,
+            ..Options::default()
+        };
+        assert!(matches!(
+            compile_with_offsets(b"[->+<]", &options).unwrap().0[0],
+            Instruction::LoopStart(5)
+        ));
+    }
+
+    #[test]
+    fn test_nested_loop_targets() {
+        let instructions = compile(b"+[>+[>+<-]<-]", 0).unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Add(1),
+                Instruction::LoopStart(12),
+                Instruction::Move(1),
+                Instruction::Add(1),
+                Instruction::MultiplyLoop(9),
+                Instruction::Move(1),
+                Instruction::Add(1),
+                Instruction::Move(-1),
+                Instruction::Add(-1),
+                Instruction::LoopEnd(4),
+                Instruction::Move(-1),
+                Instruction::Add(-1),
+                Instruction::LoopEnd(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_offsets() {
+        let (instructions, offsets) = compile_with_offsets(
+            b"++ x [-] >",
+            &Options {
+                level: 0,
+                opt_level: MAX_OPT_LEVEL,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Add(2),
+                Instruction::Clear,
+                Instruction::Move(1)
+            ]
+        );
+        assert_eq!(offsets, vec![0, 5, 9]);
+    }
+
+    #[test]
+    fn test_unoptimized_is_one_to_one() {
+        let (instructions, offsets) = compile_with_offsets(
+            b"++[-]",
+            &Options {
+                level: 0,
+                opt_level: 0,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Add(1),
+                Instruction::Add(1),
+                Instruction::LoopStart(4),
+                Instruction::Add(-1),
+                Instruction::LoopEnd(2),
+            ]
+        );
+        assert_eq!(offsets, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_debug_hash() {
+        let options = Options {
+            debug_hash: true,
+            ..Options::default()
+        };
+        let (instructions, offsets) = compile_with_offsets(b"+#+", &options).unwrap();
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::Add(1),
+                Instruction::DebugPrint,
+                Instruction::Add(1)
+            ]
+        );
+        assert_eq!(offsets, vec![0, 1, 2]);
+        assert_eq!(compile(b"+#+", 3).unwrap(), vec![Instruction::Add(2)]);
+    }
+
+    #[test]
+    fn test_opt_levels() {
+        let compile_at = |opt_level| {
+            let options = Options {
+                opt_level,
+                ..Options::default()
+            };
+            compile_with_offsets(b"++[-]>>", &options).unwrap().0
+        };
+
+        assert_eq!(compile_at(0).len(), 7);
+        assert_eq!(
+            compile_at(1),
+            vec![
+                Instruction::Add(2),
+                Instruction::LoopStart(3),
+                Instruction::Add(-1),
+                Instruction::LoopEnd(1),
+                Instruction::Move(2),
+            ]
+        );
+        assert_eq!(
+            compile_at(2),
+            vec![
+                Instruction::Add(2),
+                Instruction::Clear,
+                Instruction::Move(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_loop_depth() {
+        let options = Options {
+            max_loop_depth: Some(2),
+            ..Options::default()
+        };
+
+        assert!(compile_with_offsets(b"[[]][[]]", &options).is_ok());
+        assert!(matches!(
+            compile_with_offsets(b"[[]] [[[-]]]", &options),
+            Err(BrainfuckError::LoopTooDeep {
+                pos: 7,
+                max_depth: 2
+            })
+        ));
+    }
+}
@@ -0,0 +1,4629 @@
+//! A Brainfuck interpreter supporting the Extended Brainfuck levels.
+
+use anyhow::Error;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+pub mod bare;
+pub mod dialect;
+pub mod directive;
+pub mod emit;
+mod error;
+pub mod ir;
+pub mod lint;
+pub mod macros;
+pub mod program;
+pub mod source;
+
+pub use error::BrainfuckError;
+use ir::Instruction;
+use lint::{Lint, Unreachable};
+use program::Program;
+
+/// What happens when the data pointer is moved off either end of the tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerPolicy {
+    /// Stay on the first or last cell
+    Clamp,
+    /// Continue from the other end of the tape
+    Wrap,
+    /// Stop with `BrainfuckError::PointerOutOfBounds`
+    Error,
+}
+
+impl PointerPolicy {
+    /// Parses the name used on the command line, e.g. `wrap`.
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "clamp" => Ok(PointerPolicy::Clamp),
+            "wrap" => Ok(PointerPolicy::Wrap),
+            "error" => Ok(PointerPolicy::Error),
+            _ => Err(anyhow::anyhow!(
+                "Unknown pointer policy: {} (expected clamp, wrap or error)",
+                name
+            )),
+        }
+    }
+}
+
+/// What happens when `+` or `-` take a cell past either end of its range.
+/// This only concerns cell values, where the data pointer goes is up to
+/// `PointerPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellOverflow {
+    /// Continue from the other end of the range, e.g. 255 + 1 is 0
+    Wrap,
+    /// Stop with `BrainfuckError::CellOutOfRange`
+    Error,
+}
+
+impl CellOverflow {
+    /// Parses the name used on the command line, e.g. `wrap`.
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "wrap" => Ok(CellOverflow::Wrap),
+            "error" => Ok(CellOverflow::Error),
+            _ => Err(anyhow::anyhow!(
+                "Unknown cell overflow policy: {} (expected wrap or error)",
+                name
+            )),
+        }
+    }
+}
+
+/// A preset of the cell overflow and pointer policies, for the usual
+/// combinations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compat {
+    /// Cells wrap and leaving the tape is an error, like the original
+    /// implementation on a fixed buffer
+    Classic,
+    /// Both cells and the data pointer wrap
+    Wrapping,
+    /// Both a cell leaving its range and the pointer leaving the tape are
+    /// errors
+    Strict,
+}
+
+impl Compat {
+    /// Parses the name used on the command line, e.g. `classic`.
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "classic" => Ok(Compat::Classic),
+            "wrapping" => Ok(Compat::Wrapping),
+            "strict" => Ok(Compat::Strict),
+            _ => Err(anyhow::anyhow!(
+                "Unknown compatibility preset: {} (expected classic, wrapping or strict)",
+                name
+            )),
+        }
+    }
+
+    /// The cell overflow and pointer policies the preset stands for
+    pub fn policies(self) -> (CellOverflow, PointerPolicy) {
+        match self {
+            Compat::Classic => (CellOverflow::Wrap, PointerPolicy::Error),
+            Compat::Wrapping => (CellOverflow::Wrap, PointerPolicy::Wrap),
+            Compat::Strict => (CellOverflow::Error, PointerPolicy::Error),
+        }
+    }
+}
+
+/// What `,` stores once its input is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EofPolicy {
+    /// Stop with `BrainfuckError::EndOfInput`
+    Error,
+    /// Leave the current cell as it is
+    Unchanged,
+    /// Set the current cell to 0
+    Zero,
+    /// Set every bit of the current cell, i.e. -1 in two's complement
+    MinusOne,
+}
+
+impl EofPolicy {
+    /// Parses the name used on the command line, e.g. `minus-one`.
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "error" => Ok(EofPolicy::Error),
+            "unchanged" => Ok(EofPolicy::Unchanged),
+            "zero" => Ok(EofPolicy::Zero),
+            "minus-one" => Ok(EofPolicy::MinusOne),
+            _ => Err(anyhow::anyhow!(
+                "Unknown EOF policy: {} (expected error, unchanged, zero or minus-one)",
+                name
+            )),
+        }
+    }
+}
+
+/// What a fresh tape is filled with instead of `initial_value`, to catch
+/// programs that assume every cell starts at zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Poison {
+    /// Every byte of every cell is the given one
+    Pattern(u8),
+    /// Pseudo-random values, the same for the same seed
+    Random(u64),
+}
+
+/// The byte `Poison::from_name("pattern")` fills cells with
+pub const POISON_PATTERN: u8 = 0xaa;
+
+impl Poison {
+    /// Parses the name used on the command line: `pattern`, or `random` with
+    /// an optional seed as in `random:42`, 0 by default.
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name.split_once(':') {
+            None if name == "pattern" => Ok(Poison::Pattern(POISON_PATTERN)),
+            None if name == "random" => Ok(Poison::Random(0)),
+            Some(("random", seed)) => seed
+                .parse()
+                .map(Poison::Random)
+                .map_err(|_| anyhow::anyhow!("Invalid poison seed: {}", seed)),
+            _ => Err(anyhow::anyhow!(
+                "Unknown poison: {} (expected pattern, random or random:SEED)",
+                name
+            )),
+        }
+    }
+
+    /// Returns a tape of `size` cells of `cell_width` filled with the poison.
+    pub fn fill(self, size: usize, cell_width: CellWidth) -> Vec<u32> {
+        match self {
+            Poison::Pattern(byte) => vec![u32::from_ne_bytes([byte; 4]) & cell_width.mask(); size],
+            Poison::Random(seed) => {
+                // xorshift64*, whose state must not be zero
+                let mut state = seed ^ 0x9e37_79b9_7f4a_7c15;
+                (0..size)
+                    .map(|_| {
+                        state ^= state >> 12;
+                        state ^= state << 25;
+                        state ^= state >> 27;
+                        (state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as u32 & cell_width.mask()
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// What `,` does with an input byte that is not 7-bit ASCII.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsciiPolicy {
+    /// Store the byte as it is
+    Any,
+    /// Stop with `BrainfuckError::NonAsciiInput`
+    Error,
+    /// Clear the high bit, keeping the low 7 bits
+    Mask,
+}
+
+impl AsciiPolicy {
+    /// Parses the name used on the command line, e.g. `mask`.
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        match name {
+            "any" => Ok(AsciiPolicy::Any),
+            "error" => Ok(AsciiPolicy::Error),
+            "mask" => Ok(AsciiPolicy::Mask),
+            _ => Err(anyhow::anyhow!(
+                "Unknown ASCII policy: {} (expected any, error or mask)",
+                name
+            )),
+        }
+    }
+}
+
+/// Number of cells on the tape unless told otherwise
+pub const DEFAULT_TAPE_SIZE: usize = 2048;
+
+/// How many steps run between checks of the timeout and interrupt flag
+const CHECK_INTERVAL: u64 = 4096;
+
+/// Width of a single tape cell. Cells are stored as `u32` and masked down to
+/// the selected width after every arithmetic operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    pub fn from_bits(bits: u32) -> Result<Self, Error> {
+        match bits {
+            8 => Ok(CellWidth::U8),
+            16 => Ok(CellWidth::U16),
+            32 => Ok(CellWidth::U32),
+            _ => Err(anyhow::anyhow!(
+                "Unsupported cell width: {} (expected 8, 16 or 32)",
+                bits
+            )),
+        }
+    }
+
+    pub fn mask(self) -> u32 {
+        match self {
+            CellWidth::U8 => u8::MAX as u32,
+            CellWidth::U16 => u16::MAX as u32,
+            CellWidth::U32 => u32::MAX,
+        }
+    }
+}
+
+/// How `.` and `,` translate between cells and the outside world.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoMode {
+    /// Cells are read and written as raw bytes
+    Byte,
+    /// Cells are written as decimal integers and read by parsing ASCII digits
+    Numeric,
+}
+
+/// Printed after every value written in `IoMode::Numeric`
+pub const NUMERIC_SEPARATOR: char = '\n';
+
+/// Reads a single byte of input, returning `None` once the input is exhausted.
+/// Bytes read from `reader` are written to `echo` and `record` if given, the
+/// pre-defined input is never written anywhere. A reader with
+/// nothing to read, like a closed stdin, is at the end of the input, and only
+/// errors reading it are `BrainfuckError::Io`. A read cut short by a signal
+/// is retried, unless `interrupt` is set, which stops the `,` at `pos`.
+fn read_input_byte(
+    pre_defined_input: &Option<Vec<u8>>,
+    input_index: &mut usize,
+    reader: &mut dyn Read,
+    interrupt: Option<(&AtomicBool, usize)>,
+    echo: Option<&mut dyn Write>,
+    record: Option<&mut dyn Write>,
+) -> Result<Option<u8>, BrainfuckError> {
+    match pre_defined_input {
+        Some(input) => {
+            let byte = input.get(*input_index).copied();
+            *input_index += 1;
+            Ok(byte)
+        }
+        None => {
+            let mut input = [0];
+            loop {
+                match reader.read(&mut input) {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => break,
+                    // E.g. a signal arrived while waiting for a key press
+                    Err(error) if error.kind() == io::ErrorKind::Interrupted => {
+                        if let Some((interrupt, pos)) = interrupt {
+                            if interrupt.load(Ordering::Relaxed) {
+                                return Err(BrainfuckError::Interrupted { pos });
+                            }
+                        }
+                    }
+                    Err(error) => return Err(error.into()),
+                }
+            }
+            if let Some(echo) = echo {
+                echo.write_all(&input)?;
+                echo.flush()?;
+            }
+            if let Some(record) = record {
+                record.write_all(&input)?;
+            }
+            Ok(Some(input[0]))
+        }
+    }
+}
+
+/// Parses a decimal integer from the input, skipping leading whitespace. The
+/// first non-digit after the number is consumed as its terminator. Returns
+/// `None` if the input ends before a digit.
+fn read_input_number(
+    pre_defined_input: &Option<Vec<u8>>,
+    input_index: &mut usize,
+    reader: &mut dyn Read,
+    interrupt: Option<(&AtomicBool, usize)>,
+    mut echo: Option<&mut dyn Write>,
+    mut record: Option<&mut dyn Write>,
+) -> Result<Option<u32>, BrainfuckError> {
+    let mut value: u32 = 0;
+    let mut seen_digit = false;
+
+    loop {
+        let echo = echo.as_mut().map(|echo| &mut **echo as &mut dyn Write);
+        let record = record
+            .as_mut()
+            .map(|record| &mut **record as &mut dyn Write);
+        let Some(byte) = read_input_byte(
+            pre_defined_input,
+            input_index,
+            reader,
+            interrupt,
+            echo,
+            record,
+        )?
+        else {
+            return Ok(seen_digit.then_some(value));
+        };
+        if byte.is_ascii_digit() {
+            value = value.wrapping_mul(10).wrapping_add((byte - b'0') as u32);
+            seen_digit = true;
+        } else if seen_digit || !byte.is_ascii_whitespace() {
+            break;
+        }
+    }
+
+    Ok(Some(value))
+}
+
+/// Reads its input a whole line at a time, newline included, and hands out
+/// the bytes of the line to successive reads. As `Cpu::stdin`, `,` then waits
+/// for a complete line however the reader delivers it, as many brainfuck
+/// programs written for terminal input expect. A read interrupted by a
+/// signal fails with `io::ErrorKind::Interrupted` and keeps what it read of
+/// the line for the next one.
+pub struct LineInput<R> {
+    reader: R,
+    line: Vec<u8>,
+    // Bytes of `line` already handed out
+    position: usize,
+    // Whether `line` holds all of its line, or is still being read
+    complete: bool,
+}
+
+impl<R: BufRead> LineInput<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: Vec::new(),
+            position: 0,
+            complete: true,
+        }
+    }
+}
+
+impl<R: BufRead> Read for LineInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.complete && self.position == self.line.len() {
+            self.line.clear();
+            self.position = 0;
+            self.complete = false;
+        }
+        // Unlike `read_until`, passes on interruptions instead of retrying
+        while !self.complete {
+            let available = self.reader.fill_buf()?;
+            let (used, complete) = match available.iter().position(|&byte| byte == b'\n') {
+                Some(newline) => (newline + 1, true),
+                // The last line of the input may not end in a newline
+                None => (available.len(), available.is_empty()),
+            };
+            self.line.extend_from_slice(&available[..used]);
+            self.reader.consume(used);
+            self.complete = complete;
+        }
+        let count = buf.len().min(self.line.len() - self.position);
+        buf[..count].copy_from_slice(&self.line[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// Writes a byte of program output as is. With `escape`, bytes other than
+/// graphic ASCII, spaces and newlines are shown as escapes like `\x07`.
+fn write_output(sink: &mut impl Write, byte: u8, escape: bool) -> io::Result<()> {
+    if escape && !(byte.is_ascii_graphic() || byte == b' ' || byte == b'\n') {
+        write!(sink, "\\x{:02x}", byte)
+    } else {
+        sink.write_all(&[byte])
+    }
+}
+
+/// The highest Extended Brainfuck level, commands of higher ones do not exist
+pub const MAX_LEVEL: u8 = 3;
+
+/// Parses an Extended Brainfuck level, 0 to `MAX_LEVEL`.
+pub fn parse_level(level: &str) -> Result<u8, Error> {
+    match level.parse() {
+        Ok(level) if level <= MAX_LEVEL => Ok(level),
+        _ => Err(anyhow::anyhow!(
+            "Invalid level: {} (expected 0 to {})",
+            level,
+            MAX_LEVEL
+        )),
+    }
+}
+
+/// Returns whether `command` is interpreted at the given extended level.
+pub fn is_command(command: char, level: u8) -> bool {
+    match command {
+        '>' | '<' | '+' | '-' | '.' | ',' | '[' | ']' => true,
+        '@' | '$' | '!' | '}' | '{' | '~' | '^' | '&' | '|' => level >= 1,
+        '?' | '(' | ')' | '*' | '/' | '=' | '_' | '%' => level >= 2,
+        '0'..='9' | 'A'..='F' => level >= 3,
+        _ => false,
+    }
+}
+
+/// A set of extended commands to enable one by one, in place of all those
+/// of a level.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommandSet(u128);
+
+impl CommandSet {
+    /// Parses a set written as its commands, e.g. `*/=_%`.
+    pub fn parse(commands: &str) -> Result<Self, Error> {
+        let mut set = CommandSet::default();
+        for command in commands.chars() {
+            if is_command(command, 0) || !is_command(command, MAX_LEVEL) {
+                anyhow::bail!("Not an extended command: {:?}", command);
+            }
+            set.0 |= 1 << command as u32;
+        }
+        Ok(set)
+    }
+
+    pub fn contains(self, command: char) -> bool {
+        command.is_ascii() && self.0 & (1 << command as u32) != 0
+    }
+}
+
+/// Returns whether `command` is interpreted. Plain brainfuck commands always
+/// are. Extended commands are if they are in `enabled`, or without a set if
+/// they are at `level`.
+pub fn is_enabled(command: char, level: u8, enabled: Option<CommandSet>) -> bool {
+    match enabled {
+        Some(enabled) => is_command(command, 0) || enabled.contains(command),
+        None => is_command(command, level),
+    }
+}
+
+/// Removes the UTF-8 byte order mark some editors put at the start of files.
+pub fn strip_bom(source: &str) -> &str {
+    source.strip_prefix('\u{feff}').unwrap_or(source)
+}
+
+/// Removes a leading `#!` line so that program files can be executable
+/// scripts. The newline is kept, so line numbers stay the same.
+pub fn strip_shebang(source: &str) -> &str {
+    match source.strip_prefix("#!") {
+        Some(rest) => &rest[rest.find('\n').unwrap_or(rest.len())..],
+        None => source,
+    }
+}
+
+/// A line separating a program from its input when both come from the same
+/// stream, as online judges give them
+pub const INPUT_SEPARATOR: &str = "!";
+
+/// Splits `stream` at its first line that is only `INPUT_SEPARATOR` into the
+/// program before it and the input after it. Without such a line the whole
+/// stream is the program. The program keeps its last newline, and a
+/// separator line ending in `\r\n` is accepted.
+pub fn split_input(stream: &str) -> (&str, Option<&str>) {
+    let mut line_start = 0;
+    for line in stream.split_inclusive('\n') {
+        let end = line_start + line.len();
+        if line.trim_end_matches(['\n', '\r']) == INPUT_SEPARATOR {
+            return (&stream[..line_start], Some(&stream[end..]));
+        }
+        line_start = end;
+    }
+    (stream, None)
+}
+
+/// Starts and ends a span of the program that is disabled, see
+/// `disable_spans`. It is not a command at any level.
+pub const DISABLE_MARKER: char = '`';
+
+/// Blanks out the spans between pairs of `DISABLE_MARKER`, so that the
+/// commands in them do not run. Spans do not nest. Every byte of a span, the
+/// markers included, becomes a space except for newlines, so offsets and
+/// line numbers stay the same. A marker that is never closed is returned as
+/// the error, by its offset.
+pub fn disable_spans(program: &str) -> Result<String, usize> {
+    let mut enabled = String::with_capacity(program.len());
+    let mut span_start = None;
+
+    for (offset, character) in program.char_indices() {
+        match (character, span_start) {
+            (DISABLE_MARKER, None) => {
+                span_start = Some(offset);
+                enabled.push(' ');
+            }
+            (DISABLE_MARKER, Some(_)) => {
+                span_start = None;
+                enabled.push(' ');
+            }
+            (character, None) => enabled.push(character),
+            ('\n', Some(_)) => enabled.push('\n'),
+            (character, Some(_)) => enabled.extend(std::iter::repeat_n(' ', character.len_utf8())),
+        }
+    }
+
+    match span_start {
+        Some(offset) => Err(offset),
+        None => Ok(enabled),
+    }
+}
+
+/// Strips everything but the commands active at `level` from `program`.
+pub fn minify(program: &str, level: u8) -> String {
+    minify_enabled(program, level, None)
+}
+
+/// Like `minify`, but keeps the commands `is_enabled` accepts.
+pub fn minify_enabled(program: &str, level: u8, enabled: Option<CommandSet>) -> String {
+    program
+        .chars()
+        .filter(|&command| is_enabled(command, level, enabled))
+        .collect()
+}
+
+/// A command about to be executed, passed to the hook of `Cpu::run_with_hook`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepInfo {
+    /// Offset of the command in the program
+    pub instruction_pointer: usize,
+    pub command: char,
+    pub data_pointer: usize,
+}
+
+/// A change of a tape cell, passed to the hook of `Cpu::run_with_cell_hook`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellChange {
+    pub index: usize,
+    pub old: u32,
+    pub new: u32,
+    /// Offset of the command that changed the cell
+    pub instruction_pointer: usize,
+}
+
+/// The callbacks a run reports to, all optional.
+#[derive(Default)]
+struct Hooks<'a> {
+    step: Option<&'a mut dyn FnMut(StepInfo)>,
+    cell: Option<&'a mut dyn FnMut(CellChange)>,
+}
+
+/// Why the last run stopped, see `RunReport`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltReason {
+    /// The program ran to its end
+    Finished,
+    /// The Extended Type I `@` command ended the program
+    Break,
+    /// The debugger was told to quit
+    Quit,
+    /// `.` printed the byte of `Cpu::halt_on_output`
+    OutputMatched,
+    /// `BrainfuckError::StepLimitExceeded`
+    StepLimit,
+    /// `BrainfuckError::TimedOut`
+    Timeout,
+    /// `BrainfuckError::OutputLimitExceeded`
+    OutputLimit,
+    /// `BrainfuckError::Interrupted`
+    Interrupted,
+    /// Any other error
+    Error,
+}
+
+/// What the last run did, returned by `Cpu::report`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunReport {
+    /// Instructions executed, folded runs count once
+    pub steps: u64,
+    /// Bytes printed by `.`
+    pub output_len: u64,
+    /// Highest cell the data pointer reached
+    pub high_water_mark: usize,
+    pub halted_reason: HaltReason,
+}
+
+/// Execution counters collected by `Cpu::run` when stats are enabled.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub instructions: u64,
+    pub per_command: BTreeMap<char, u64>,
+    pub max_data_pointer: usize,
+    pub loop_iterations: u64,
+    // Value of the Extended Type I storage when the run ended, `None` in
+    // plain brainfuck, which has no storage
+    pub storage: Option<u32>,
+}
+
+impl Stats {
+    /// Counts an executed command. Runs with stats are never folded, so
+    /// every instruction is a single command.
+    fn record(&mut self, command: char) {
+        self.instructions += 1;
+        *self.per_command.entry(command).or_insert(0) += 1;
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Instructions executed: {}", self.instructions)?;
+        for (command, count) in &self.per_command {
+            writeln!(f, "  '{}': {}", command, count)?;
+        }
+        writeln!(f, "Max data pointer: {}", self.max_data_pointer)?;
+        write!(f, "Loop iterations: {}", self.loop_iterations)?;
+        if let Some(storage) = self.storage {
+            write!(f, "\nStorage: {}", storage)?;
+        }
+        Ok(())
+    }
+}
+
+/// Static counts of a program's commands, collected without running it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    pub commands: usize,
+    pub per_command: BTreeMap<char, usize>,
+    pub loops: usize,
+    pub max_depth: usize,
+    pub balanced: bool,
+}
+
+impl Metrics {
+    /// Scans the commands of `program` active at `level`.
+    pub fn new(program: &str, level: u8) -> Self {
+        Self::new_enabled(program, level, None)
+    }
+
+    /// Like `new`, but counts the commands `is_enabled` accepts.
+    pub fn new_enabled(program: &str, level: u8, enabled: Option<CommandSet>) -> Self {
+        let mut metrics = Metrics::default();
+        let mut depth: usize = 0;
+        let mut unmatched = false;
+
+        for command in program
+            .chars()
+            .filter(|&command| is_enabled(command, level, enabled))
+        {
+            metrics.commands += 1;
+            *metrics.per_command.entry(command).or_insert(0) += 1;
+            match command {
+                '[' => {
+                    metrics.loops += 1;
+                    depth += 1;
+                    metrics.max_depth = metrics.max_depth.max(depth);
+                }
+                ']' if depth == 0 => unmatched = true,
+                ']' => depth -= 1,
+                _ => (),
+            }
+        }
+
+        metrics.balanced = !unmatched && depth == 0;
+        metrics
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Commands: {}", self.commands)?;
+        for (command, count) in &self.per_command {
+            writeln!(f, "  '{}': {}", command, count)?;
+        }
+        writeln!(f, "Loops: {}", self.loops)?;
+        writeln!(f, "Max nesting depth: {}", self.max_depth)?;
+        write!(
+            f,
+            "Brackets balanced: {}",
+            if self.balanced { "yes" } else { "no" }
+        )
+    }
+}
+
+/// Which commands `Cpu::run` executed, collected when coverage is enabled.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    // Offsets of every command in the program
+    pub commands: Vec<usize>,
+    // Indexed by offset, set once the command there has executed
+    pub executed: Vec<bool>,
+}
+
+impl Coverage {
+    /// Offsets of the commands that never executed.
+    pub fn uncovered(&self) -> Vec<usize> {
+        self.commands
+            .iter()
+            .copied()
+            .filter(|&offset| !self.executed[offset])
+            .collect()
+    }
+
+    /// Share of commands that executed at least once, in percent.
+    pub fn percentage(&self) -> f64 {
+        if self.commands.is_empty() {
+            return 100.0;
+        }
+        let covered = self.commands.len() - self.uncovered().len();
+        covered as f64 * 100.0 / self.commands.len() as f64
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Coverage: {:.1}% of {} commands",
+            self.percentage(),
+            self.commands.len()
+        )?;
+        let uncovered: Vec<String> = self
+            .uncovered()
+            .iter()
+            .map(|offset| offset.to_string())
+            .collect();
+        match uncovered.is_empty() {
+            true => write!(f, "Never executed: none"),
+            false => write!(f, "Never executed: {}", uncovered.join(", ")),
+        }
+    }
+}
+
+/// Hashes everything written to it with 64-bit FNV-1a, e.g. the trace of a
+/// run, so that two runs can be compared command for command without keeping
+/// either trace. Clones share the hash.
+#[derive(Debug, Clone)]
+pub struct TraceHasher {
+    hash: Rc<Cell<u64>>,
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+impl TraceHasher {
+    pub fn new() -> Self {
+        Self {
+            hash: Rc::new(Cell::new(FNV_OFFSET_BASIS)),
+        }
+    }
+
+    /// The hash of everything written so far by any clone.
+    pub fn hash(&self) -> u64 {
+        self.hash.get()
+    }
+}
+
+impl Default for TraceHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for TraceHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let hash = buf.iter().fold(self.hash.get(), |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        });
+        self.hash.set(hash);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How often each loop jumped back to its start, collected when hotspots are
+/// enabled.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Hotspots {
+    // Keyed by the offset of the loop's `[`
+    pub iterations: BTreeMap<usize, u64>,
+}
+
+impl Hotspots {
+    /// The `count` loops that jumped back most often, by the offset of their
+    /// `[`, most iterations first.
+    pub fn top(&self, count: usize) -> Vec<(usize, u64)> {
+        let mut loops: Vec<(usize, u64)> = self
+            .iterations
+            .iter()
+            .map(|(&offset, &iterations)| (offset, iterations))
+            .collect();
+        // Stable, so loops with as many iterations stay in program order
+        loops.sort_by_key(|&(_, iterations)| std::cmp::Reverse(iterations));
+        loops.truncate(count);
+        loops
+    }
+}
+
+/// Which cells a run wrote, collected when memory profiling is enabled.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryProfile {
+    // Indexed by cell, set once an instruction wrote the cell
+    pub written: Vec<bool>,
+    // Highest cell the data pointer reached, set when the run ends
+    pub max_data_pointer: usize,
+}
+
+impl MemoryProfile {
+    /// Number of distinct cells written at least once.
+    pub fn cells_written(&self) -> usize {
+        self.written.iter().filter(|&&written| written).count()
+    }
+
+    /// The lowest and highest cell written, if any was.
+    pub fn written_range(&self) -> Option<(usize, usize)> {
+        let first = self.written.iter().position(|&written| written)?;
+        let last = self.written.iter().rposition(|&written| written)?;
+        Some((first, last))
+    }
+}
+
+impl fmt::Display for MemoryProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Cells written: {} of {}",
+            self.cells_written(),
+            self.written.len()
+        )?;
+        match self.written_range() {
+            Some((first, last)) => writeln!(
+                f,
+                "Written range: {}..={} ({} cells)",
+                first,
+                last,
+                last - first + 1
+            )?,
+            None => writeln!(f, "Written range: none")?,
+        }
+        // Cells the pointer only passed over still have to exist
+        write!(
+            f,
+            "Smallest tape that fits: {} cells",
+            self.max_data_pointer + 1
+        )
+    }
+}
+
+/// State of the interactive stepper enabled by `--debug`.
+pub struct Debugger {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    // Pause before every instruction, cleared by `c`
+    pub stepping: bool,
+    // The most recent instructions, newest last, undone by `b`
+    history: VecDeque<Delta>,
+    // Take a checkpoint every this many steps, so that `b N` can rewind
+    // further than the history reaches
+    pub checkpoint_interval: Option<u64>,
+    // The most recent checkpoints, newest last
+    checkpoints: VecDeque<Checkpoint>,
+}
+
+impl Debugger {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Self {
+            input,
+            output,
+            stepping: true,
+            history: VecDeque::new(),
+            checkpoint_interval: None,
+            checkpoints: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, delta: Delta) {
+        if self.history.len() == DEBUG_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(delta);
+    }
+
+    fn checkpoint(&mut self, checkpoint: Checkpoint) {
+        // E.g. after rewinding to a step that has one already
+        if self
+            .checkpoints
+            .back()
+            .is_some_and(|last| last.steps >= checkpoint.steps)
+        {
+            return;
+        }
+        if self.checkpoints.len() == MAX_CHECKPOINTS {
+            self.checkpoints.pop_front();
+        }
+        self.checkpoints.push_back(checkpoint);
+    }
+}
+
+/// Number of checkpoints kept by the debugger, the oldest are dropped first
+const MAX_CHECKPOINTS: usize = 64;
+
+/// The whole state before a step. `b N` restores the newest one at least `N`
+/// steps back and runs forward from there to the step it asked for.
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    // Instructions executed before it
+    steps: u64,
+    output_bytes: u64,
+    // `,` commands that had read from stdin before it, which can not be read
+    // again to run forward
+    stdin_reads: u64,
+    state: State,
+}
+
+/// Number of instructions the debugger can step back over
+const DEBUG_HISTORY: usize = 1024;
+
+/// What an instruction can change, recorded before it executes. Debugged
+/// programs are not folded, so an instruction writes at most the cell under
+/// the pointer.
+#[derive(Debug, Clone, Copy)]
+struct Delta {
+    instruction_pointer: usize,
+    steps: u64,
+    data_pointer: usize,
+    high_water_mark: usize,
+    cell: u32,
+    storage: u32,
+    input_index: usize,
+    // Bytes read from stdin can not be read again, so stepping back over a
+    // `,` that read one is refused
+    stdin_reads: u64,
+    output_len: usize,
+    output_bytes: u64,
+}
+
+/// How execution continues after `Cpu::apply`.
+enum Flow {
+    Next,
+    /// Execute the same instruction again
+    Repeat,
+    Halt(HaltReason),
+}
+
+/// What the user asked the debugger to do next.
+enum DebugAction {
+    Step,
+    Back,
+    /// Restore the checkpoint at `index` and run forward until `target`
+    /// instructions have been executed
+    Rewind {
+        index: usize,
+        target: u64,
+    },
+    Quit,
+}
+
+/// Number of cells printed by `#` with `Cpu::debug_hash`
+const DEBUG_HASH_WINDOW: usize = 10;
+
+/// Number of cells shown on each side of the data pointer by `p`
+const DEBUG_WINDOW: usize = 5;
+
+/// Pauses before an instruction and handles stepper commands until the user
+/// resumes execution, steps back or quits.
+/// `executed` and `stdin_reads` count the instructions executed so far and
+/// the `,` among them that read from stdin.
+fn debug_step(
+    debugger: &mut Debugger,
+    status: &str,
+    data_pointer: usize,
+    tape: &[u32],
+    executed: u64,
+    stdin_reads: u64,
+) -> Result<DebugAction, BrainfuckError> {
+    writeln!(debugger.output, "{}", status)?;
+
+    loop {
+        write!(debugger.output, "(debug) ")?;
+        debugger.output.flush()?;
+
+        let mut line = String::new();
+        if debugger.input.read_line(&mut line)? == 0 {
+            // No more stepper input, let the program run to completion
+            debugger.stepping = false;
+            return Ok(DebugAction::Step);
+        }
+
+        match line.trim() {
+            "s" | "" => return Ok(DebugAction::Step),
+            "c" => {
+                debugger.stepping = false;
+                return Ok(DebugAction::Step);
+            }
+            "b" => match debugger.history.back() {
+                None => writeln!(debugger.output, "No instruction to step back over")?,
+                Some(delta) if delta.stdin_reads != stdin_reads => {
+                    writeln!(debugger.output, "Can not step back over input from stdin")?
+                }
+                Some(_) => return Ok(DebugAction::Back),
+            },
+            command if command.starts_with("b ") => {
+                let count = command[2..].trim();
+                match count.parse::<u64>() {
+                    Ok(count) if count > 0 => {
+                        let target = executed.saturating_sub(count);
+                        match debugger
+                            .checkpoints
+                            .iter()
+                            .rposition(|checkpoint| checkpoint.steps <= target)
+                        {
+                            Some(index)
+                                if debugger.checkpoints[index].stdin_reads != stdin_reads =>
+                            {
+                                writeln!(debugger.output, "Can not rewind over input from stdin")?
+                            }
+                            Some(index) => return Ok(DebugAction::Rewind { index, target }),
+                            None => writeln!(
+                                debugger.output,
+                                "No checkpoint to rewind {} steps to",
+                                count
+                            )?,
+                        }
+                    }
+                    _ => writeln!(debugger.output, "Invalid step count: {}", count)?,
+                }
+            }
+            "p" => {
+                let start = data_pointer.saturating_sub(DEBUG_WINDOW);
+                let end = (data_pointer + DEBUG_WINDOW + 1).min(tape.len());
+                let cells: Vec<String> = (start..end)
+                    .map(|index| {
+                        if index == data_pointer {
+                            format!("[{}]", tape[index])
+                        } else {
+                            tape[index].to_string()
+                        }
+                    })
+                    .collect();
+                writeln!(debugger.output, "{}: {}", start, cells.join(" "))?;
+            }
+            "q" => return Ok(DebugAction::Quit),
+            other => writeln!(
+                debugger.output,
+                "Unknown command '{}' (s: step, b: step back, b N: rewind N steps, c: continue, p: print tape, q: quit)",
+                other
+            )?,
+        }
+    }
+}
+
+/// Describes what an Extended Type II instruction is about to compute from
+/// the current cell and the storage, e.g. `6 * 5 = 30`.
+fn describe_arithmetic(
+    instruction: Instruction,
+    cell: u32,
+    storage: u32,
+    mask: u32,
+) -> Option<String> {
+    let (operator, result) = match instruction {
+        Instruction::Multiply => ('*', Some(cell.wrapping_mul(storage) & mask)),
+        Instruction::Divide => ('/', cell.checked_div(storage)),
+        Instruction::AddStorage => ('+', Some(cell.wrapping_add(storage) & mask)),
+        Instruction::SubtractStorage => ('-', Some(cell.wrapping_sub(storage) & mask)),
+        Instruction::Modulo => ('%', cell.checked_rem(storage)),
+        _ => return None,
+    };
+    Some(match result {
+        Some(result) => format!("{} {} {} = {}", cell, operator, storage, result),
+        None => format!("{} {} {} divides by zero", cell, operator, storage),
+    })
+}
+
+/// Everything needed to continue a run later, see `Cpu::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub tape: Vec<u32>,
+    pub data_pointer: usize,
+    pub storage: u32,
+    pub instruction_pointer: usize,
+    pub input_index: usize,
+    pub output: Vec<u8>,
+    pub high_water_mark: usize,
+
+    pub tape_size: usize,
+    pub level: u8,
+    pub cell_width: CellWidth,
+    pub io_mode: IoMode,
+    pub pointer_policy: PointerPolicy,
+    pub cell_overflow: CellOverflow,
+    pub eof_policy: EofPolicy,
+    pub strict_division: bool,
+}
+
+pub struct Cpu {
+    pub feed_tape: String,
+    // Allocated fresh by every `run`, kept afterwards for inspection
+    tape: Vec<u32>,
+    pub data_pointer: usize,
+    // Index into the compiled program, only non-zero after a failed run
+    instruction_pointer: usize,
+    // The program compiled by the first `step`, until it finishes
+    stepped: Option<(Vec<Instruction>, Vec<usize>)>,
+    // Bytes of pre-defined input consumed so far
+    input_index: usize,
+    // Bytes printed by `.` so far, checked against `max_output`
+    output_bytes: u64,
+    // `,` commands executed so far that read from `stdin`
+    stdin_reads: u64,
+    // What the program printed, only collected with `one_shot_output`
+    pub output: Vec<u8>,
+    pub tape_size: usize,
+    // Value every cell of a fresh tape starts with
+    pub initial_value: u8,
+    // Fills a fresh tape in place of `initial_value`
+    pub poison: Option<Poison>,
+    // Largest data pointer reached during the last run
+    high_water_mark: usize,
+    // Why the last run stopped, `None` until a run has
+    halt_reason: Option<HaltReason>,
+    // Collect output in `output` and write it to `sink` when the run ends.
+    // Meant for capturing the output of short runs, e.g. in tests, as
+    // `output` grows with everything printed. Without it output is streamed
+    // to `sink` as it is printed and not kept.
+    pub one_shot_output: bool,
+    // Print non-graphic output characters as escapes
+    pub escape_output: bool,
+    pub cell_width: CellWidth,
+    pub io_mode: IoMode,
+    pub pointer_policy: PointerPolicy,
+    pub cell_overflow: CellOverflow,
+    // Only applies to `IoMode::Byte`, numeric input reads 0 at the end
+    pub eof_policy: EofPolicy,
+    // Only applies to `IoMode::Byte`, numbers are ASCII digits anyway
+    pub ascii_policy: AsciiPolicy,
+    // Instructions executed by the last run, folded runs count once
+    pub steps: u64,
+    pub max_steps: Option<u64>,
+    pub max_output: Option<u64>,
+    pub timeout: Option<Duration>,
+    // Stops the run with `BrainfuckError::Interrupted` once set, e.g. by a
+    // signal handler
+    pub interrupt: Option<&'static AtomicBool>,
+    // Stop with `BrainfuckError::DivisionByZero` instead of leaving the cell
+    // unchanged when `/` or `%` divide by zero
+    pub strict_division: bool,
+
+    // Only collected when set to `Some`, to keep the default path fast
+    pub stats: Option<Stats>,
+    pub coverage: Option<Coverage>,
+    pub hotspots: Option<Hotspots>,
+    pub memory_profile: Option<MemoryProfile>,
+
+    pub debugger: Option<Debugger>,
+    // Instruction offsets where the debugger starts stepping
+    pub breakpoints: HashSet<usize>,
+    // Cells whose modifications are reported to `diagnostics`
+    pub watchpoints: HashSet<usize>,
+    pub diagnostics: Box<dyn Write>,
+    // Receives one "step ip command dp cell" line before every command
+    pub trace: Option<Box<dyn Write>>,
+    // Where program output is written, stdout by default
+    pub sink: Box<dyn Write>,
+    // Where `,` reads from without pre-defined input, stdin by default
+    pub stdin: Box<dyn Read>,
+    // Write bytes read from `stdin` to `sink`, for terminals that do not
+    // show what was typed
+    pub echo_input: bool,
+    // Receives every byte `,` reads from `stdin`, so that a session can be
+    // replayed by reading them back as `stdin`
+    pub record_input: Option<Box<dyn Write>>,
+    // Stop the run right after `.` prints this byte, the low byte of the
+    // cell in `IoMode::Numeric` too
+    pub halt_on_output: Option<u8>,
+
+    // Extended Brainfuck level, 0 means plain brainfuck
+    pub level: u8,
+    // The extended commands that run regardless of `level`, if set
+    pub enabled_commands: Option<CommandSet>,
+    // Make `#` print the cells around the pointer to `diagnostics`
+    pub debug_hash: bool,
+    // Which compiler optimizations run, see `ir::MAX_OPT_LEVEL`
+    pub opt_level: u8,
+    // Reject programs with loops nested deeper than this
+    pub max_loop_depth: Option<usize>,
+
+    // Extended commands I
+    pub storage: u32,
+}
+
+impl Cpu {
+    /// Creates a CPU for a program given as bytes, which need not be UTF-8.
+    /// Commands are all ASCII, so if the bytes are not UTF-8 any other byte
+    /// is replaced by a space, which keeps the offsets of the commands.
+    pub fn from_bytes(program: &[u8], tape_size: usize) -> Self {
+        let feed_tape = match std::str::from_utf8(program) {
+            Ok(program) => program.to_string(),
+            Err(_) => program
+                .iter()
+                .map(|&byte| if byte.is_ascii() { byte as char } else { ' ' })
+                .collect(),
+        };
+        Self::new(feed_tape, tape_size)
+    }
+
+    pub fn new(feed_tape: String, tape_size: usize) -> Self {
+        Self {
+            feed_tape,
+            tape: Vec::new(),
+            data_pointer: 0,
+            instruction_pointer: 0,
+            stepped: None,
+            input_index: 0,
+            output_bytes: 0,
+            stdin_reads: 0,
+            output: Vec::new(),
+            tape_size,
+            initial_value: 0,
+            poison: None,
+            high_water_mark: 0,
+            halt_reason: None,
+            one_shot_output: false,
+            escape_output: false,
+            cell_width: CellWidth::U8,
+            io_mode: IoMode::Byte,
+            pointer_policy: PointerPolicy::Clamp,
+            cell_overflow: CellOverflow::Wrap,
+            eof_policy: EofPolicy::Error,
+            ascii_policy: AsciiPolicy::Any,
+            steps: 0,
+            max_steps: None,
+            max_output: None,
+            timeout: None,
+            interrupt: None,
+            strict_division: false,
+            stats: None,
+            coverage: None,
+            hotspots: None,
+            memory_profile: None,
+            debugger: None,
+            breakpoints: HashSet::new(),
+            watchpoints: HashSet::new(),
+            diagnostics: Box::new(io::stderr()),
+            trace: None,
+            sink: Box::new(io::stdout()),
+            stdin: Box::new(io::stdin()),
+            echo_input: false,
+            record_input: None,
+            halt_on_output: None,
+            level: 0,
+            enabled_commands: None,
+            debug_hash: false,
+            opt_level: ir::MAX_OPT_LEVEL,
+            max_loop_depth: None,
+            storage: 0,
+        }
+    }
+
+    /// Returns the text stored from cell `start` up to the first zero cell or
+    /// the end of the tape, with cells taken as characters by their low byte
+    /// like `.` prints them.
+    pub fn cstring(&self, start: usize) -> String {
+        self.tape
+            .iter()
+            .skip(start)
+            .take_while(|&&value| value != 0)
+            .map(|&value| value as u8 as char)
+            .collect()
+    }
+
+    /// Renders the cells in `range` as a table of index, decimal, hex and
+    /// character values. The range is clipped to the tape.
+    pub fn dump_tape(&self, range: Range<usize>) -> String {
+        let end = range.end.min(self.tape.len());
+        let start = range.start.min(end);
+
+        let mut dump = String::from("index    dec      hex  char\n");
+        for (index, &value) in self.tape[start..end].iter().enumerate() {
+            let character = match char::from_u32(value) {
+                Some(character) if character.is_ascii_graphic() => character,
+                _ => '.',
+            };
+            dump.push_str(&format!(
+                "{:>5} {:>6} {:>8}  {}\n",
+                start + index,
+                value,
+                format!("{:#x}", value),
+                character
+            ));
+        }
+        dump
+    }
+
+    /// Returns the largest data pointer reached during the last run, i.e. a
+    /// tape of `high_water_mark() + 1` cells would have been enough.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark
+    }
+
+    /// Lists the values of the cells up to the high water mark, writing runs
+    /// of three or more equal values as `value*count`, e.g. `72 0*5 1`.
+    fn tape_summary(&self) -> String {
+        let used = &self.tape[..(self.high_water_mark + 1).min(self.tape.len())];
+        let mut values = Vec::new();
+        let mut index = 0;
+        while index < used.len() {
+            let count = used[index..]
+                .iter()
+                .take_while(|&&value| value == used[index])
+                .count();
+            if count >= 3 {
+                values.push(format!("{}*{}", used[index], count));
+            } else {
+                values.extend(used[index..index + count].iter().map(u32::to_string));
+            }
+            index += count;
+        }
+        values.join(" ")
+    }
+
+    /// Renders `window` cells around the data pointer as a row of values with
+    /// a caret under the current cell. The window is shifted to stay on the
+    /// tape near either end.
+    pub fn render_tape(&self, window: usize) -> String {
+        let start = self
+            .data_pointer
+            .saturating_sub(window / 2)
+            .min(self.tape.len().saturating_sub(window));
+        let end = (start + window).min(self.tape.len());
+
+        let mut values = String::new();
+        let mut caret = String::new();
+        for index in start..end {
+            let value = format!(" {:>3}", self.tape[index]);
+            let marker = if index == self.data_pointer { "^" } else { " " };
+            caret.push_str(&format!("{:>width$}", marker, width = value.len()));
+            values.push_str(&value);
+        }
+        format!("{}\n{}\n", values, caret.trim_end())
+    }
+
+    /// Reports what the last run or `execute` did, also when it stopped with
+    /// an error. `None` before the first run, and when the program did not
+    /// compile.
+    pub fn report(&self) -> Option<RunReport> {
+        Some(RunReport {
+            steps: self.steps,
+            output_len: self.output_bytes,
+            high_water_mark: self.high_water_mark,
+            halted_reason: self.halt_reason?,
+        })
+    }
+
+    /// Clears the state left behind by a previous run, down to the counts
+    /// `report` shows and the collected coverage. `run` allocates a fresh
+    /// tape every call, but the output, data pointer, storage and stats
+    /// persist between calls until reset.
+    pub fn reset(&mut self) {
+        self.tape.clear();
+        self.data_pointer = 0;
+        self.instruction_pointer = 0;
+        self.stepped = None;
+        self.input_index = 0;
+        self.high_water_mark = 0;
+        self.output.clear();
+        self.storage = 0;
+        self.steps = 0;
+        self.output_bytes = 0;
+        self.stdin_reads = 0;
+        self.halt_reason = None;
+        if let Some(stats) = self.stats.as_mut() {
+            *stats = Stats::default();
+        }
+        if let Some(hotspots) = self.hotspots.as_mut() {
+            *hotspots = Hotspots::default();
+        }
+        if let Some(memory_profile) = self.memory_profile.as_mut() {
+            *memory_profile = MemoryProfile::default();
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            *coverage = Coverage::default();
+        }
+    }
+
+    /// Captures the execution state, e.g. after a run stopped at its step
+    /// limit. The program itself is not part of the snapshot.
+    pub fn snapshot(&self) -> State {
+        State {
+            tape: self.tape.clone(),
+            data_pointer: self.data_pointer,
+            storage: self.storage,
+            instruction_pointer: self.instruction_pointer,
+            input_index: self.input_index,
+            output: self.output.clone(),
+            high_water_mark: self.high_water_mark,
+            tape_size: self.tape_size,
+            level: self.level,
+            cell_width: self.cell_width,
+            io_mode: self.io_mode,
+            pointer_policy: self.pointer_policy,
+            cell_overflow: self.cell_overflow,
+            eof_policy: self.eof_policy,
+            strict_division: self.strict_division,
+        }
+    }
+
+    /// Replaces the execution state with `state`. Calling `execute` with the
+    /// same program and input afterwards continues where the snapshot left off.
+    pub fn restore(&mut self, state: State) {
+        self.tape = state.tape;
+        self.data_pointer = state.data_pointer;
+        self.storage = state.storage;
+        self.instruction_pointer = state.instruction_pointer;
+        self.input_index = state.input_index;
+        self.output = state.output;
+        self.high_water_mark = state.high_water_mark;
+        self.tape_size = state.tape_size;
+        self.level = state.level;
+        self.cell_width = state.cell_width;
+        self.io_mode = state.io_mode;
+        self.pointer_policy = state.pointer_policy;
+        self.cell_overflow = state.cell_overflow;
+        self.eof_policy = state.eof_policy;
+        self.strict_division = state.strict_division;
+    }
+
+    /// Checks that the program compiles, i.e. that its brackets are balanced.
+    pub fn validate(&self) -> Result<(), BrainfuckError> {
+        ir::compile_with_offsets(self.feed_tape.as_bytes(), &self.compile_options(0)).map(|_| ())
+    }
+
+    /// Compiles the program as `run` would without a debugger, trace or other
+    /// per-command reporting, returning the instructions and the offset of
+    /// the command each was compiled from.
+    pub fn compile(&self) -> Result<(Vec<Instruction>, Vec<usize>), BrainfuckError> {
+        ir::compile_with_offsets(
+            self.feed_tape.as_bytes(),
+            &self.compile_options(self.opt_level),
+        )
+    }
+
+    fn compile_options(&self, opt_level: u8) -> ir::Options {
+        ir::Options {
+            level: self.level,
+            enabled: self.enabled_commands,
+            opt_level,
+            debug_hash: self.debug_hash,
+            max_loop_depth: self.max_loop_depth,
+        }
+    }
+
+    /// Reports loops in the program that can never finish, see `lint::lint`.
+    pub fn lint(&self) -> Result<Vec<Lint>, BrainfuckError> {
+        lint::lint(self.feed_tape.as_bytes(), self.level, self.enabled_commands)
+    }
+
+    /// Reports code that can never run on a fresh tape because a loop before
+    /// it never ends, see `lint::unreachable`.
+    pub fn unreachable(&self) -> Result<Option<Unreachable>, BrainfuckError> {
+        lint::unreachable(
+            self.feed_tape.as_bytes(),
+            self.level,
+            self.enabled_commands,
+            self.tape_size,
+            self.cell_width,
+            self.poison.is_none().then_some(self.initial_value as u32),
+            self.pointer_policy,
+        )
+    }
+
+    /// Returns where the data pointer ends up after moving `amount` cells
+    /// from its current position, according to the pointer policy.
+    fn moved_pointer(&self, amount: isize, offset: usize) -> Result<usize, BrainfuckError> {
+        let target = self.data_pointer as isize + amount;
+        let last = self.tape_size as isize - 1;
+        match self.pointer_policy {
+            PointerPolicy::Clamp => Ok(target.clamp(0, last) as usize),
+            PointerPolicy::Wrap => Ok(target.rem_euclid(self.tape_size as isize) as usize),
+            PointerPolicy::Error if (0..=last).contains(&target) => Ok(target as usize),
+            // A folded move fails at the first cell off the tape, like the
+            // individual moves would
+            PointerPolicy::Error => Err(BrainfuckError::PointerOutOfBounds {
+                pos: offset,
+                pointer: target.clamp(-1, last + 1),
+            }),
+        }
+    }
+
+    pub fn run(&mut self, pre_defined_input: Option<Vec<u8>>) -> Result<(), BrainfuckError> {
+        self.start()?;
+        self.execute_with(pre_defined_input, Hooks::default(), None)
+    }
+
+    /// Like `run`, but calls `hook` before every command executes. Like the
+    /// trace, this runs the program without folding.
+    pub fn run_with_hook(
+        &mut self,
+        pre_defined_input: Option<Vec<u8>>,
+        mut hook: impl FnMut(StepInfo),
+    ) -> Result<(), BrainfuckError> {
+        self.start()?;
+        let hooks = Hooks {
+            step: Some(&mut hook),
+            ..Hooks::default()
+        };
+        self.execute_with(pre_defined_input, hooks, None)
+    }
+
+    /// Like `run`, but calls `hook` after every command that changed a cell,
+    /// with the value before and after. Runs the program without folding, so
+    /// that e.g. `++` reports two changes.
+    pub fn run_with_cell_hook(
+        &mut self,
+        pre_defined_input: Option<Vec<u8>>,
+        mut hook: impl FnMut(CellChange),
+    ) -> Result<(), BrainfuckError> {
+        self.start()?;
+        let hooks = Hooks {
+            cell: Some(&mut hook),
+            ..Hooks::default()
+        };
+        self.execute_with(pre_defined_input, hooks, None)
+    }
+
+    /// Executes the next instruction, starting from the state a previous run
+    /// or `reset` left behind. The program is compiled without folding on
+    /// the first call, so every call executes one command, and `,` reads
+    /// from `stdin`. Returns the executed command, or `None` once the program
+    /// has finished, after which the next call starts it again. Output is
+    /// collected as by `execute`, and one-shot output flushed at the end.
+    pub fn step(&mut self) -> Result<Option<StepInfo>, BrainfuckError> {
+        if self.stepped.is_none() {
+            let program =
+                ir::compile_with_offsets(self.feed_tape.as_bytes(), &self.compile_options(0))?;
+            if self.data_pointer >= self.tape_size {
+                return Err(BrainfuckError::PointerOutOfBounds {
+                    pos: 0,
+                    pointer: self.data_pointer as isize,
+                });
+            }
+            if self.tape.len() != self.tape_size {
+                self.tape = self.fresh_tape();
+            }
+            self.high_water_mark = self.high_water_mark.max(self.data_pointer);
+            self.steps = 0;
+            self.halt_reason = None;
+            self.stepped = Some(program);
+        }
+        let (instructions, offsets) = self.stepped.as_ref().unwrap();
+
+        let Some(&instruction) = instructions.get(self.instruction_pointer) else {
+            self.stepped = None;
+            self.instruction_pointer = 0;
+            self.halt_reason.get_or_insert(HaltReason::Finished);
+            self.flush_output()?;
+            return Ok(None);
+        };
+        let offset = offsets[self.instruction_pointer];
+        let step = StepInfo {
+            instruction_pointer: offset,
+            command: self.feed_tape.as_bytes()[offset] as char,
+            data_pointer: self.data_pointer,
+        };
+
+        self.steps += 1;
+        if self
+            .max_steps
+            .is_some_and(|max_steps| self.steps > max_steps)
+        {
+            return Err(BrainfuckError::StepLimitExceeded);
+        }
+        match self.apply(instruction, offset, &None)? {
+            Flow::Next => self.instruction_pointer += 1,
+            Flow::Repeat => (),
+            // Skip to the end, the next call finishes the program
+            Flow::Halt(reason) => {
+                self.halt_reason = Some(reason);
+                self.instruction_pointer = usize::MAX;
+            }
+        }
+        Ok(Some(step))
+    }
+
+    /// Returns the tape a run starts with.
+    fn fresh_tape(&self) -> Vec<u32> {
+        match self.poison {
+            Some(poison) => poison.fill(self.tape_size, self.cell_width),
+            None => vec![self.initial_value as u32; self.tape_size],
+        }
+    }
+
+    /// Validates the program and resets the state a run starts from.
+    fn start(&mut self) -> Result<(), BrainfuckError> {
+        self.halt_reason = None;
+        self.validate()?;
+        self.reset_run_state();
+        Ok(())
+    }
+
+    /// Resets the state a run starts from, for a program known to compile.
+    fn reset_run_state(&mut self) {
+        self.tape = self.fresh_tape();
+        self.high_water_mark = self.data_pointer;
+        self.instruction_pointer = 0;
+        self.stepped = None;
+        self.input_index = 0;
+        self.output.clear();
+        self.output_bytes = 0;
+    }
+
+    /// Runs the program against the tape left behind by the previous run or
+    /// `execute`, so that state carries over between calls. A tape is only
+    /// allocated if there is none of the right size yet. A run stopped by an
+    /// error resumes at the instruction that failed.
+    pub fn execute(&mut self, pre_defined_input: Option<Vec<u8>>) -> Result<(), BrainfuckError> {
+        self.execute_with(pre_defined_input, Hooks::default(), None)
+    }
+
+    /// Runs the program, and on an error still prints the output collected
+    /// before it. `compiled` is used instead of compiling the program if it
+    /// was compiled the same way.
+    fn execute_with(
+        &mut self,
+        pre_defined_input: Option<Vec<u8>>,
+        hooks: Hooks,
+        compiled: Option<&Program>,
+    ) -> Result<(), BrainfuckError> {
+        self.halt_reason = None;
+        let result = self.execute_program(pre_defined_input, hooks, compiled);
+        self.halt_reason = Some(match &result {
+            // Set by `execute_program` if the program stopped early
+            Ok(()) => self.halt_reason.unwrap_or(HaltReason::Finished),
+            Err(BrainfuckError::StepLimitExceeded) => HaltReason::StepLimit,
+            Err(BrainfuckError::TimedOut) => HaltReason::Timeout,
+            Err(BrainfuckError::OutputLimitExceeded) => HaltReason::OutputLimit,
+            Err(BrainfuckError::Interrupted { .. }) => HaltReason::Interrupted,
+            Err(_) => HaltReason::Error,
+        });
+        // Summarizing a large tape is not free, so only when it is logged
+        if log::log_enabled!(log::Level::Trace) {
+            log::trace!(
+                "Final tape, cells 0 to {}: {}",
+                self.high_water_mark,
+                self.tape_summary()
+            );
+        }
+        if result.is_err() {
+            // The error of the run is reported over one while flushing
+            let _ = self.flush_output();
+            if let Some(trace) = self.trace.as_mut() {
+                let _ = trace.flush();
+            }
+        }
+        result
+    }
+
+    fn execute_program(
+        &mut self,
+        pre_defined_input: Option<Vec<u8>>,
+        mut hooks: Hooks,
+        compiled: Option<&Program>,
+    ) -> Result<(), BrainfuckError> {
+        // The debugger, watchpoints, trace, hooks, stats and coverage report
+        // on individual commands, and cells that must not overflow are checked
+        // after every `+` and `-`, so they run the program without folding
+        let opt_level = if self.debugger.is_none()
+            && self.watchpoints.is_empty()
+            && self.trace.is_none()
+            && hooks.step.is_none()
+            && hooks.cell.is_none()
+            && self.coverage.is_none()
+            && self.stats.is_none()
+            && self.cell_overflow == CellOverflow::Wrap
+        {
+            self.opt_level
+        } else {
+            0
+        };
+        // Level 2 turns clear and scan loops into single instructions that
+        // never jump back, so hotspots would miss them
+        let opt_level = match self.hotspots {
+            Some(_) => opt_level.min(1),
+            None => opt_level,
+        };
+        let options = self.compile_options(opt_level);
+        let compiled_here;
+        let (instructions, offsets) =
+            match compiled.and_then(|program| program.compiled_as(&options)) {
+                Some(compiled) => compiled,
+                None => {
+                    compiled_here = ir::compile_with_offsets(self.feed_tape.as_bytes(), &options)?;
+                    (&compiled_here.0[..], &compiled_here.1[..])
+                }
+            };
+
+        if let Some(coverage) = self.coverage.as_mut() {
+            // Keep what a resumed run already covered
+            if coverage.commands != offsets {
+                coverage.commands = offsets.to_vec();
+                coverage.executed = vec![false; self.feed_tape.len()];
+            }
+        }
+
+        // E.g. with a tape size of 0 there is no cell to start on
+        if self.data_pointer >= self.tape_size {
+            return Err(BrainfuckError::PointerOutOfBounds {
+                pos: 0,
+                pointer: self.data_pointer as isize,
+            });
+        }
+        if self.tape.len() != self.tape_size {
+            self.tape = self.fresh_tape();
+        }
+        if let Some(memory_profile) = self.memory_profile.as_mut() {
+            // Keep what a resumed run already wrote
+            memory_profile.written.resize(self.tape_size, false);
+        }
+        self.high_water_mark = self.high_water_mark.max(self.data_pointer);
+        self.steps = 0;
+        // Checkpoints count steps from here
+        if let Some(debugger) = self.debugger.as_mut() {
+            debugger.checkpoints.clear();
+        }
+        let started = Instant::now();
+
+        while self.instruction_pointer < instructions.len() {
+            let instruction = instructions[self.instruction_pointer];
+            let offset = offsets[self.instruction_pointer];
+
+            self.steps += 1;
+            if self
+                .max_steps
+                .is_some_and(|max_steps| self.steps > max_steps)
+            {
+                return Err(BrainfuckError::StepLimitExceeded);
+            }
+            if self.steps.is_multiple_of(CHECK_INTERVAL) {
+                if self
+                    .timeout
+                    .is_some_and(|timeout| started.elapsed() > timeout)
+                {
+                    return Err(BrainfuckError::TimedOut);
+                }
+                if self
+                    .interrupt
+                    .is_some_and(|interrupt| interrupt.load(Ordering::Relaxed))
+                {
+                    return Err(BrainfuckError::Interrupted { pos: offset });
+                }
+            }
+
+            let checkpoint_interval = self
+                .debugger
+                .as_ref()
+                .and_then(|debugger| debugger.checkpoint_interval);
+            if checkpoint_interval.is_some_and(|interval| (self.steps - 1).is_multiple_of(interval))
+            {
+                let checkpoint = Checkpoint {
+                    steps: self.steps - 1,
+                    output_bytes: self.output_bytes,
+                    stdin_reads: self.stdin_reads,
+                    state: self.snapshot(),
+                };
+                if let Some(debugger) = self.debugger.as_mut() {
+                    debugger.checkpoint(checkpoint);
+                }
+            }
+
+            if let Some(debugger) = self.debugger.as_mut() {
+                if self.breakpoints.contains(&offset) {
+                    debugger.stepping = true;
+                }
+
+                let action = if debugger.stepping {
+                    let mut status = format!(
+                        "ip: {}, command: '{}', dp: {}",
+                        offset,
+                        self.feed_tape.as_bytes()[offset] as char,
+                        self.data_pointer
+                    );
+                    // Plain brainfuck has no storage to show
+                    if self.level > 0 {
+                        status += &format!(", storage: {}", self.storage);
+                    }
+                    if let Some(arithmetic) = describe_arithmetic(
+                        instruction,
+                        self.tape[self.data_pointer],
+                        self.storage,
+                        self.cell_width.mask(),
+                    ) {
+                        status += &format!(", {}", arithmetic);
+                    }
+                    debug_step(
+                        debugger,
+                        &status,
+                        self.data_pointer,
+                        &self.tape,
+                        self.steps - 1,
+                        self.stdin_reads,
+                    )?
+                } else {
+                    DebugAction::Step
+                };
+
+                match action {
+                    DebugAction::Step => debugger.record(Delta {
+                        instruction_pointer: self.instruction_pointer,
+                        steps: self.steps,
+                        data_pointer: self.data_pointer,
+                        high_water_mark: self.high_water_mark,
+                        cell: self.tape[self.data_pointer],
+                        storage: self.storage,
+                        input_index: self.input_index,
+                        stdin_reads: self.stdin_reads,
+                        output_len: self.output.len(),
+                        output_bytes: self.output_bytes,
+                    }),
+                    DebugAction::Back => {
+                        // Output that already reached the sink stays printed,
+                        // only collected one-shot output is taken back
+                        let delta = debugger.history.pop_back().unwrap();
+                        self.instruction_pointer = delta.instruction_pointer;
+                        self.steps = delta.steps - 1;
+                        self.data_pointer = delta.data_pointer;
+                        self.high_water_mark = delta.high_water_mark;
+                        self.tape[delta.data_pointer] = delta.cell;
+                        self.storage = delta.storage;
+                        self.input_index = delta.input_index;
+                        self.output.truncate(delta.output_len);
+                        self.output_bytes = delta.output_bytes;
+                        continue;
+                    }
+                    DebugAction::Rewind { index, target } => {
+                        let checkpoint = debugger.checkpoints[index].clone();
+                        debugger.checkpoints.truncate(index + 1);
+                        debugger.history.retain(|delta| delta.steps <= target);
+                        self.restore(checkpoint.state);
+                        self.output_bytes = checkpoint.output_bytes;
+                        self.replay(
+                            instructions,
+                            offsets,
+                            target - checkpoint.steps,
+                            &pre_defined_input,
+                        )?;
+                        // Counted again at the top of the loop
+                        self.steps = target;
+                        continue;
+                    }
+                    DebugAction::Quit => {
+                        self.halt_reason = Some(HaltReason::Quit);
+                        break;
+                    }
+                }
+            }
+
+            if let Some(trace) = self.trace.as_mut() {
+                writeln!(
+                    trace,
+                    "{} {} {} {} {}",
+                    self.steps,
+                    offset,
+                    self.feed_tape.as_bytes()[offset] as char,
+                    self.data_pointer,
+                    self.tape[self.data_pointer]
+                )?;
+            }
+
+            if let Some(hook) = hooks.step.as_mut() {
+                hook(StepInfo {
+                    instruction_pointer: offset,
+                    command: self.feed_tape.as_bytes()[offset] as char,
+                    data_pointer: self.data_pointer,
+                });
+            }
+
+            if let Some(coverage) = self.coverage.as_mut() {
+                coverage.executed[offset] = true;
+            }
+
+            if let Some(stats) = self.stats.as_mut() {
+                stats.record(self.feed_tape.as_bytes()[offset] as char);
+            }
+
+            if let Some(memory_profile) = self.memory_profile.as_mut() {
+                if instruction.writes_cell() {
+                    memory_profile.written[self.data_pointer] = true;
+                }
+            }
+
+            if let (Some(hotspots), Instruction::LoopEnd(start)) =
+                (self.hotspots.as_mut(), instruction)
+            {
+                if self.tape[self.data_pointer] != 0 {
+                    *hotspots.iterations.entry(offsets[start]).or_insert(0) += 1;
+                }
+            }
+
+            // Write commands never move the pointer, so it is enough to
+            // compare the current cell before and after the command
+            let watched_cell =
+                if !self.watchpoints.is_empty() && self.watchpoints.contains(&self.data_pointer) {
+                    Some((self.data_pointer, self.tape[self.data_pointer]))
+                } else {
+                    None
+                };
+            let hooked_cell = if hooks.cell.is_some() && instruction.writes_cell() {
+                Some((self.data_pointer, self.tape[self.data_pointer]))
+            } else {
+                None
+            };
+
+            let flow = match instruction {
+                Instruction::MultiplyLoop(end)
+                    if self.multiply_loop(&instructions[self.instruction_pointer + 1..end]) =>
+                {
+                    self.instruction_pointer = end;
+                    Flow::Next
+                }
+                _ => self.apply(instruction, offset, &pre_defined_input)?,
+            };
+            match flow {
+                Flow::Next => (),
+                Flow::Repeat => continue,
+                Flow::Halt(reason) => {
+                    self.halt_reason = Some(reason);
+                    break;
+                }
+            }
+
+            if let Some((cell, old_value)) = watched_cell {
+                if self.tape[cell] != old_value {
+                    writeln!(
+                        self.diagnostics,
+                        "watch: cell {} changed {} -> {} at ip {}",
+                        cell, old_value, self.tape[cell], offset
+                    )?;
+                }
+            }
+
+            if let (Some(hook), Some((index, old))) = (hooks.cell.as_mut(), hooked_cell) {
+                if self.tape[index] != old {
+                    hook(CellChange {
+                        index,
+                        old,
+                        new: self.tape[index],
+                        instruction_pointer: offset,
+                    });
+                }
+            }
+
+            self.instruction_pointer += 1;
+        }
+        // The next call starts the program from the beginning again
+        self.instruction_pointer = 0;
+
+        if let Some(stats) = self.stats.as_mut() {
+            stats.max_data_pointer = self.high_water_mark;
+            stats.storage = (self.level > 0).then_some(self.storage);
+        }
+        if let Some(memory_profile) = self.memory_profile.as_mut() {
+            memory_profile.max_data_pointer = self.high_water_mark;
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            trace.flush()?;
+        }
+
+        self.flush_output()?;
+
+        Ok(())
+    }
+
+    /// Runs the multiply loop with `body` in one go, returning whether it
+    /// did. A loop that is not entered, or whose body would leave the tape or
+    /// wrap around onto its own cells, is left to run literally.
+    fn multiply_loop(&mut self, body: &[Instruction]) -> bool {
+        let counter = self.tape[self.data_pointer];
+        if counter == 0 {
+            return false;
+        }
+
+        let (mut pointer, mut lowest, mut highest) = (0isize, 0isize, 0isize);
+        for instruction in body {
+            if let Instruction::Move(amount) = instruction {
+                pointer += amount;
+                lowest = lowest.min(pointer);
+                highest = highest.max(pointer);
+            }
+        }
+        let start = self.data_pointer as isize;
+        let size = self.tape_size as isize;
+        let on_tape = start + lowest >= 0 && start + highest < size;
+        let wraps = self.pointer_policy == PointerPolicy::Wrap && highest - lowest < size;
+        if !on_tape && !wraps {
+            return false;
+        }
+
+        // Every iteration adds the same to a cell, so `counter` of them add
+        // `counter` times as much
+        let mask = self.cell_width.mask();
+        let mut pointer = 0;
+        for instruction in body {
+            match *instruction {
+                Instruction::Move(amount) => {
+                    pointer += amount;
+                    let cell = (start + pointer).rem_euclid(size) as usize;
+                    self.high_water_mark = self.high_water_mark.max(cell);
+                }
+                Instruction::Add(amount) if pointer != 0 => {
+                    let cell = (start + pointer).rem_euclid(size) as usize;
+                    self.tape[cell] =
+                        self.tape[cell].wrapping_add((amount as u32).wrapping_mul(counter)) & mask;
+                    if let Some(memory_profile) = self.memory_profile.as_mut() {
+                        memory_profile.written[cell] = true;
+                    }
+                }
+                _ => (),
+            }
+        }
+        self.tape[self.data_pointer] = 0;
+        if let Some(memory_profile) = self.memory_profile.as_mut() {
+            memory_profile.written[self.data_pointer] = true;
+        }
+        true
+    }
+
+    /// Executes the next `count` instructions again after restoring a
+    /// checkpoint, without pausing or reporting them. Output that already
+    /// reached the sink is not written again.
+    fn replay(
+        &mut self,
+        instructions: &[Instruction],
+        offsets: &[usize],
+        count: u64,
+        pre_defined_input: &Option<Vec<u8>>,
+    ) -> Result<(), BrainfuckError> {
+        let sink = std::mem::replace(&mut self.sink, Box::new(io::sink()));
+        let mut result = Ok(());
+        for _ in 0..count {
+            let index = self.instruction_pointer;
+            match self.apply(instructions[index], offsets[index], pre_defined_input) {
+                Ok(Flow::Next) => self.instruction_pointer += 1,
+                // A halt was not reached the first time either
+                Ok(_) => (),
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            }
+        }
+        self.sink = sink;
+        result
+    }
+
+    /// Executes a single instruction, leaving the instruction pointer to the
+    /// caller unless the instruction jumps.
+    fn apply(
+        &mut self,
+        instruction: Instruction,
+        offset: usize,
+        pre_defined_input: &Option<Vec<u8>>,
+    ) -> Result<Flow, BrainfuckError> {
+        let mask = self.cell_width.mask();
+        match instruction {
+            // Basic Commands
+            // Only run unfolded, so every `+` and `-` is checked on its own
+            Instruction::Add(amount) if self.cell_overflow == CellOverflow::Error => {
+                let value = self.tape[self.data_pointer] as i64 + amount as i64;
+                if !(0..=mask as i64).contains(&value) {
+                    return Err(BrainfuckError::CellOutOfRange { pos: offset, value });
+                }
+                self.tape[self.data_pointer] = value as u32;
+            }
+            Instruction::Add(amount) => {
+                self.tape[self.data_pointer] =
+                    self.tape[self.data_pointer].wrapping_add(amount as u32) & mask
+            }
+            Instruction::Move(amount) => {
+                self.data_pointer = self.moved_pointer(amount, offset)?;
+                self.high_water_mark = self.high_water_mark.max(self.data_pointer);
+            }
+            Instruction::Output => {
+                let value = self.tape[self.data_pointer];
+                self.output_bytes += match self.io_mode {
+                    IoMode::Byte => 1,
+                    IoMode::Numeric => value.to_string().len() as u64 + 1,
+                };
+                if self
+                    .max_output
+                    .is_some_and(|max_output| self.output_bytes > max_output)
+                {
+                    return Err(BrainfuckError::OutputLimitExceeded);
+                }
+                // Wider cells are emitted by their low byte
+                match self.io_mode {
+                    IoMode::Byte if self.one_shot_output => self.output.push(value as u8),
+                    IoMode::Byte => write_output(&mut self.sink, value as u8, self.escape_output)?,
+                    IoMode::Numeric if self.one_shot_output => {
+                        write!(self.output, "{}{}", value, NUMERIC_SEPARATOR)?;
+                    }
+                    IoMode::Numeric => write!(self.sink, "{}{}", value, NUMERIC_SEPARATOR)?,
+                }
+                if self.halt_on_output == Some(value as u8) {
+                    return Ok(Flow::Halt(HaltReason::OutputMatched));
+                }
+
+                // log::debug!("Output: '{}'", self.tape[self.data_pointer] as char);
+
+                // #[cfg(debug_assertions)]
+                // if (self.tape[self.data_pointer] as char).is_ascii_graphic() {
+                //     println!("Pushing char: {}", self.tape[self.data_pointer] as char);
+                // } else {
+                //     println!("Pushing char(u8): {:?}", self.tape[self.data_pointer]);
+                // }
+            }
+            Instruction::Input if self.io_mode == IoMode::Numeric => {
+                if pre_defined_input.is_none() {
+                    self.stdin_reads += 1;
+                }
+                let echo = self.echo_input.then_some(&mut *self.sink as &mut dyn Write);
+                let record = self
+                    .record_input
+                    .as_mut()
+                    .map(|record| &mut **record as &mut dyn Write);
+                match read_input_number(
+                    pre_defined_input,
+                    &mut self.input_index,
+                    &mut *self.stdin,
+                    self.interrupt.map(|interrupt| (interrupt, offset)),
+                    echo,
+                    record,
+                )? {
+                    Some(value) => self.tape[self.data_pointer] = value & mask,
+                    None => self.end_of_input(offset, mask)?,
+                }
+            }
+            Instruction::Input => {
+                if pre_defined_input.is_none() {
+                    self.stdin_reads += 1;
+                }
+                let echo = self.echo_input.then_some(&mut *self.sink as &mut dyn Write);
+                let record = self
+                    .record_input
+                    .as_mut()
+                    .map(|record| &mut **record as &mut dyn Write);
+                match read_input_byte(
+                    pre_defined_input,
+                    &mut self.input_index,
+                    &mut *self.stdin,
+                    self.interrupt.map(|interrupt| (interrupt, offset)),
+                    echo,
+                    record,
+                )? {
+                    Some(byte) if byte.is_ascii() => self.tape[self.data_pointer] = byte as u32,
+                    Some(byte) => match self.ascii_policy {
+                        AsciiPolicy::Any => self.tape[self.data_pointer] = byte as u32,
+                        AsciiPolicy::Error => {
+                            return Err(BrainfuckError::NonAsciiInput { pos: offset, byte })
+                        }
+                        AsciiPolicy::Mask => self.tape[self.data_pointer] = (byte & 0x7f) as u32,
+                    },
+                    None => self.end_of_input(offset, mask)?,
+                }
+            }
+            Instruction::Clear => self.tape[self.data_pointer] = 0,
+            Instruction::ScanRight => {
+                let found = self.tape[self.data_pointer..]
+                    .iter()
+                    .position(|&cell| cell == 0)
+                    .map(|distance| self.data_pointer + distance);
+                let found = match (found, self.pointer_policy) {
+                    (None, PointerPolicy::Wrap) => self.tape.iter().position(|&cell| cell == 0),
+                    (found, _) => found,
+                };
+
+                match found {
+                    Some(index) => self.data_pointer = index,
+                    None if self.pointer_policy == PointerPolicy::Error => {
+                        return Err(BrainfuckError::PointerOutOfBounds {
+                            pos: offset,
+                            pointer: self.tape_size as isize,
+                        })
+                    }
+                    None => {
+                        // Like `[>]`, loop forever without finding a zero
+                        self.data_pointer = self.moved_pointer(1, offset)?;
+                        self.high_water_mark = self.high_water_mark.max(self.data_pointer);
+                        return Ok(Flow::Repeat);
+                    }
+                }
+                self.high_water_mark = self.high_water_mark.max(self.data_pointer);
+            }
+            Instruction::ScanLeft => {
+                let found = self.tape[..=self.data_pointer]
+                    .iter()
+                    .rposition(|&cell| cell == 0);
+                let found = match (found, self.pointer_policy) {
+                    (None, PointerPolicy::Wrap) => self.tape.iter().rposition(|&cell| cell == 0),
+                    (found, _) => found,
+                };
+
+                match found {
+                    Some(index) => self.data_pointer = index,
+                    None if self.pointer_policy == PointerPolicy::Error => {
+                        return Err(BrainfuckError::PointerOutOfBounds {
+                            pos: offset,
+                            pointer: -1,
+                        })
+                    }
+                    None => {
+                        // Like `[<]`, loop forever without finding a zero
+                        self.data_pointer = self.moved_pointer(-1, offset)?;
+                        return Ok(Flow::Repeat);
+                    }
+                }
+                // Wrapping around can pass the end of the tape
+                self.high_water_mark = self.high_water_mark.max(self.data_pointer);
+            }
+            // A multiply loop gets here when it has to run literally
+            Instruction::LoopStart(end) | Instruction::MultiplyLoop(end) => {
+                if self.tape[self.data_pointer] == 0 {
+                    self.instruction_pointer = end;
+                } else if let Some(stats) = self.stats.as_mut() {
+                    stats.loop_iterations += 1;
+                }
+            }
+            Instruction::LoopEnd(start) => {
+                if self.tape[self.data_pointer] != 0 {
+                    self.instruction_pointer = start;
+                    if let Some(stats) = self.stats.as_mut() {
+                        stats.loop_iterations += 1;
+                    }
+                }
+            }
+            // Extended commands I (https://esolangs.org/wiki/Extended_Brainfuck#Extended_Type_I)
+            Instruction::Halt => return Ok(Flow::Halt(HaltReason::Break)),
+            Instruction::Store => self.storage = self.tape[self.data_pointer],
+            Instruction::Load => self.tape[self.data_pointer] = self.storage,
+            Instruction::ShiftRight => self.tape[self.data_pointer] >>= 1,
+            Instruction::ShiftLeft => {
+                self.tape[self.data_pointer] = (self.tape[self.data_pointer] << 1) & mask
+            }
+            Instruction::Not => self.tape[self.data_pointer] = !self.tape[self.data_pointer] & mask,
+            Instruction::Xor => self.tape[self.data_pointer] ^= self.storage,
+            Instruction::And => self.tape[self.data_pointer] &= self.storage,
+            Instruction::Or => self.tape[self.data_pointer] |= self.storage,
+
+            // Extended commands II (https://esolangs.org/wiki/Extended_Brainfuck#Extended_Type_II)
+            Instruction::Unimplemented(command) => {
+                return Err(BrainfuckError::UnimplementedCommand {
+                    command,
+                    pos: offset,
+                })
+            }
+
+            // Extended commands III (https://esolangs.org/wiki/Extended_Brainfuck#Extended_Type_III)
+            Instruction::Set(value) => self.tape[self.data_pointer] = value & mask,
+
+            Instruction::DebugPrint => {
+                write!(self.diagnostics, "{}", self.render_tape(DEBUG_HASH_WINDOW))?
+            }
+            Instruction::Multiply => {
+                self.tape[self.data_pointer] =
+                    self.tape[self.data_pointer].wrapping_mul(self.storage) & mask
+            }
+            Instruction::Divide => {
+                if self.storage != 0 {
+                    self.tape[self.data_pointer] =
+                        self.tape[self.data_pointer].wrapping_div(self.storage)
+                } else if self.strict_division {
+                    return Err(BrainfuckError::DivisionByZero { pos: offset });
+                } else {
+                    log::error!(
+                        "Division by zero, instruction pointer: {}, current char: /",
+                        offset
+                    );
+                }
+            }
+            Instruction::AddStorage => {
+                self.tape[self.data_pointer] =
+                    self.tape[self.data_pointer].wrapping_add(self.storage) & mask
+            }
+            Instruction::SubtractStorage => {
+                self.tape[self.data_pointer] =
+                    self.tape[self.data_pointer].wrapping_sub(self.storage) & mask
+            }
+            Instruction::Modulo => {
+                if self.storage != 0 {
+                    self.tape[self.data_pointer] =
+                        self.tape[self.data_pointer].wrapping_rem(self.storage)
+                } else if self.strict_division {
+                    return Err(BrainfuckError::DivisionByZero { pos: offset });
+                } else {
+                    log::error!(
+                        "Division by zero, instruction pointer: {}, current char: %",
+                        offset
+                    );
+                }
+            }
+        }
+
+        Ok(Flow::Next)
+    }
+
+    /// Applies the EOF policy for a `,` at `offset` that found no input.
+    fn end_of_input(&mut self, offset: usize, mask: u32) -> Result<(), BrainfuckError> {
+        match self.eof_policy {
+            EofPolicy::Error => return Err(BrainfuckError::EndOfInput { pos: offset }),
+            EofPolicy::Unchanged => (),
+            EofPolicy::Zero => self.tape[self.data_pointer] = 0,
+            EofPolicy::MinusOne => self.tape[self.data_pointer] = mask,
+        }
+        Ok(())
+    }
+
+    /// Writes the collected one-shot output to the sink and flushes it.
+    fn flush_output(&mut self) -> io::Result<()> {
+        if self.escape_output {
+            for &byte in &self.output {
+                write_output(&mut self.sink, byte, true)?;
+            }
+        } else {
+            self.sink.write_all(&self.output)?;
+        }
+        self.sink.flush()
+    }
+}
+
+/// Number of output bytes shown by the `Debug` impl of `Cpu`
+const DEBUG_OUTPUT_PREVIEW: usize = 32;
+
+/// A summary of the state, leaving out the tape and the I/O handles.
+impl fmt::Debug for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let preview_end = self.output.len().min(DEBUG_OUTPUT_PREVIEW);
+        f.debug_struct("Cpu")
+            .field("data_pointer", &self.data_pointer)
+            .field("storage", &self.storage)
+            .field("level", &self.level)
+            .field("tape_size", &self.tape_size)
+            .field("steps", &self.steps)
+            .field(
+                "output",
+                &String::from_utf8_lossy(&self.output[..preview_end]),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+/// Chainable construction of a configured `Cpu`. Options not set keep the
+/// defaults of `Cpu::new`.
+pub struct CpuBuilder {
+    cpu: Cpu,
+}
+
+impl CpuBuilder {
+    pub fn new(program: &str) -> Self {
+        Self {
+            cpu: Cpu::new(program.to_string(), DEFAULT_TAPE_SIZE),
+        }
+    }
+
+    pub fn tape_size(mut self, tape_size: usize) -> Self {
+        self.cpu.tape_size = tape_size;
+        self
+    }
+
+    pub fn initial_value(mut self, initial_value: u8) -> Self {
+        self.cpu.initial_value = initial_value;
+        self
+    }
+
+    pub fn poison(mut self, poison: Poison) -> Self {
+        self.cpu.poison = Some(poison);
+        self
+    }
+
+    pub fn level(mut self, level: u8) -> Self {
+        self.cpu.level = level;
+        self
+    }
+
+    pub fn enabled_commands(mut self, enabled_commands: CommandSet) -> Self {
+        self.cpu.enabled_commands = Some(enabled_commands);
+        self
+    }
+
+    pub fn cell_width(mut self, cell_width: CellWidth) -> Self {
+        self.cpu.cell_width = cell_width;
+        self
+    }
+
+    pub fn io_mode(mut self, io_mode: IoMode) -> Self {
+        self.cpu.io_mode = io_mode;
+        self
+    }
+
+    pub fn pointer_policy(mut self, pointer_policy: PointerPolicy) -> Self {
+        self.cpu.pointer_policy = pointer_policy;
+        self
+    }
+
+    pub fn cell_overflow(mut self, cell_overflow: CellOverflow) -> Self {
+        self.cpu.cell_overflow = cell_overflow;
+        self
+    }
+
+    /// Sets both the cell overflow and the pointer policy from `compat`.
+    pub fn compat(self, compat: Compat) -> Self {
+        let (cell_overflow, pointer_policy) = compat.policies();
+        self.cell_overflow(cell_overflow)
+            .pointer_policy(pointer_policy)
+    }
+
+    pub fn eof_policy(mut self, eof_policy: EofPolicy) -> Self {
+        self.cpu.eof_policy = eof_policy;
+        self
+    }
+
+    pub fn ascii_policy(mut self, ascii_policy: AsciiPolicy) -> Self {
+        self.cpu.ascii_policy = ascii_policy;
+        self
+    }
+
+    pub fn opt_level(mut self, opt_level: u8) -> Self {
+        self.cpu.opt_level = opt_level;
+        self
+    }
+
+    pub fn strict_division(mut self, strict_division: bool) -> Self {
+        self.cpu.strict_division = strict_division;
+        self
+    }
+
+    pub fn max_steps(mut self, max_steps: u64) -> Self {
+        self.cpu.max_steps = Some(max_steps);
+        self
+    }
+
+    pub fn max_loop_depth(mut self, max_loop_depth: usize) -> Self {
+        self.cpu.max_loop_depth = Some(max_loop_depth);
+        self
+    }
+
+    pub fn max_output(mut self, max_output: u64) -> Self {
+        self.cpu.max_output = Some(max_output);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.cpu.timeout = Some(timeout);
+        self
+    }
+
+    /// Collect output in `Cpu::output` and print it when the run ends.
+    pub fn one_shot_output(mut self, one_shot_output: bool) -> Self {
+        self.cpu.one_shot_output = one_shot_output;
+        self
+    }
+
+    pub fn escape_output(mut self, escape_output: bool) -> Self {
+        self.cpu.escape_output = escape_output;
+        self
+    }
+
+    pub fn sink(mut self, sink: Box<dyn Write>) -> Self {
+        self.cpu.sink = sink;
+        self
+    }
+
+    pub fn stdin(mut self, stdin: Box<dyn Read>) -> Self {
+        self.cpu.stdin = stdin;
+        self
+    }
+
+    pub fn echo_input(mut self, echo_input: bool) -> Self {
+        self.cpu.echo_input = echo_input;
+        self
+    }
+
+    pub fn record_input(mut self, record_input: Box<dyn Write>) -> Self {
+        self.cpu.record_input = Some(record_input);
+        self
+    }
+
+    pub fn halt_on_output(mut self, byte: u8) -> Self {
+        self.cpu.halt_on_output = Some(byte);
+        self
+    }
+
+    pub fn build(self) -> Cpu {
+        self.cpu
+    }
+}
+
+/// Number of cells shown by the REPL's `:view`
+const REPL_WINDOW: usize = 16;
+
+/// Reads programs line by line from `input` and executes each against the
+/// persistent state of `cpu`. Besides brainfuck, a line can be `:tape` to
+/// print the used cells, `:view` to show the cells around the pointer,
+/// `:reset` to clear all state or `:quit` to stop.
+/// Prompts, dumps and errors go to `output`, program output to `cpu.sink`.
+pub fn repl(cpu: &mut Cpu, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+    loop {
+        write!(output, "bf> ")?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+
+        match line.trim() {
+            ":quit" => return Ok(()),
+            ":reset" => cpu.reset(),
+            ":tape" => write!(output, "{}", cpu.dump_tape(0..cpu.high_water_mark() + 1))?,
+            ":view" => write!(output, "{}", cpu.render_tape(REPL_WINDOW))?,
+            snippet => {
+                cpu.feed_tape = snippet.to_string();
+                if let Err(error) = cpu.execute(None) {
+                    writeln!(output, "error: {}", error)?;
+                    // Start the next snippet from its beginning
+                    cpu.instruction_pointer = 0;
+                }
+                cpu.output.clear();
+            }
+        }
+    }
+}
+
+/// Runs a plain brainfuck `program` on a tape of `DEFAULT_TAPE_SIZE` cells
+/// and returns everything it printed. Use `Cpu` directly for anything else.
+///
+/// ```
+/// let program = "++++++++++[>+++++++>++++++++++>+++>+<<<<-]>++.>+.+++++++..+++.>++.\
+///     <<+++++++++++++++.>.+++.------.--------.>+.>.";
+/// assert_eq!(
+///     brainfuck_interpreter::interpret(program, "").unwrap(),
+///     b"Hello World!\n"
+/// );
+/// ```
+pub fn interpret(program: &str, input: &str) -> Result<Vec<u8>, BrainfuckError> {
+    run_program(program, input, 0, DEFAULT_TAPE_SIZE)
+}
+
+/// Runs `program` with `input` feeding `,` and returns everything it printed.
+/// Nothing is written to stdout, which makes this the entry point for
+/// embedding the interpreter, e.g. in a web playground.
+pub fn run_program(
+    program: &str,
+    input: &str,
+    level: u8,
+    tape_size: usize,
+) -> Result<Vec<u8>, BrainfuckError> {
+    run_bytes(program.as_bytes(), input, level, tape_size)
+}
+
+/// Like `run_program`, for a program read as bytes, e.g. from a file that is
+/// not UTF-8. See `Cpu::from_bytes`.
+pub fn run_bytes(
+    program: &[u8],
+    input: &str,
+    level: u8,
+    tape_size: usize,
+) -> Result<Vec<u8>, BrainfuckError> {
+    let mut cpu = Cpu::from_bytes(program, tape_size);
+    cpu.level = level;
+    cpu.one_shot_output = true;
+    cpu.sink = Box::new(io::sink());
+    cpu.run(Some(input.as_bytes().to_vec()))?;
+    Ok(cpu.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+
+    /// Writer whose contents can still be inspected after being boxed
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.borrow_mut().flush()
+        }
+    }
+
+    /// Counts the bytes written to it without keeping them.
+    #[derive(Clone, Default)]
+    struct CountingSink(Rc<RefCell<usize>>);
+
+    impl Write for CountingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            *self.0.borrow_mut() += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Hands out one byte per read like a terminal in raw mode, counting the
+    /// bytes read so far.
+    struct ByteAtATime(io::Cursor<Vec<u8>>, Rc<RefCell<usize>>);
+
+    impl Read for ByteAtATime {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let end = buf.len().min(1);
+            let count = self.0.read(&mut buf[..end])?;
+            *self.1.borrow_mut() += count;
+            Ok(count)
+        }
+    }
+
+    /// Fails its first read as if a signal arrived while waiting for input.
+    struct InterruptedOnce(io::Cursor<Vec<u8>>, bool);
+
+    impl Read for InterruptedOnce {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !std::mem::replace(&mut self.1, true) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            self.0.read(buf)
+        }
+    }
+
+    fn scripted_debugger(script: &str) -> (Debugger, SharedBuffer) {
+        let output = SharedBuffer::default();
+        let debugger = Debugger::new(
+            Box::new(io::Cursor::new(script.to_string())),
+            Box::new(output.clone()),
+        );
+        (debugger, output)
+    }
+
+    #[test]
+    fn test_new_cpu() {
+        let cpu = Cpu::new(String::from("++++++++++"), 2048);
+        assert_eq!(cpu.feed_tape, "++++++++++");
+        assert_eq!(cpu.data_pointer, 0);
+    }
+
+    #[test]
+    fn test_run_cpu() {
+        let mut cpu = Cpu::new(String::from("++++++++++"), 2048);
+        let result = cpu.run(None);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_hello_world() {
+        let mut cpu = Cpu::new(
+            String::from(
+                "++++++++++[>+++++++>++++++++++>+++>+<<<<-]>++.>+.+++++++\
+            ..+++.>++.<<+++++++++++++++.>.+++.------.--------.>+.>.",
+            ),
+            2048,
+        );
+        cpu.one_shot_output = true;
+        let result = cpu.run(None);
+        assert!(result.is_ok());
+        assert_eq!(cpu.output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn test_hello_world_file() {
+        let program = r#"
+        [ This program prints "Hello World!" and a newline to the screen, its
+        length is 106 active command characters. [It is not the shortest.]
+
+        This loop is an "initial comment loop", a simple way of adding a comment
+        to a BF program such that you don't have to worry about any command
+        characters. Any ".", ",", "+", "-", "<" and ">" characters are simply
+        ignored, the "[" and "]" characters just have to be balanced. This
+        loop and the commands it contains are ignored because the current cell
+        defaults to a value of 0; the 0 value causes this loop to be skipped.
+        ]
+        ++++++++               Set Cell #0 to 8
+        [
+            >++++               Add 4 to Cell #1; this will always set Cell #1 to 4
+            [                   as the cell will be cleared by the loop
+                >++             Add 2 to Cell #2
+                >+++            Add 3 to Cell #3
+                >+++            Add 3 to Cell #4
+                >+              Add 1 to Cell #5
+                <<<<-           Decrement the loop counter in Cell #1
+            ]                   Loop until Cell #1 is zero; number of iterations is 4
+            >+                  Add 1 to Cell #2
+            >+                  Add 1 to Cell #3
+            >-                  Subtract 1 from Cell #4
+            >>+                 Add 1 to Cell #6
+            [<]                 Move back to the first zero cell you find; this will
+                                be Cell #1 which was cleared by the previous loop
+            <-                  Decrement the loop Counter in Cell #0
+        ]                       Loop until Cell #0 is zero; number of iterations is 8
+
+        The result of this is:
+        Cell no :   0   1   2   3   4   5   6
+        Contents:   0   0  72 104  88  32   8
+        Pointer :   ^
+
+        >>.                     Cell #2 has value 72 which is 'H'
+        >---.                   Subtract 3 from Cell #3 to get 101 which is 'e'
+        +++++++..+++.           Likewise for 'llo' from Cell #3
+        >>.                     Cell #5 is 32 for the space
+        <-.                     Subtract 1 from Cell #4 for 87 to give a 'W'
+        <.                      Cell #3 was set to 'o' from the end of 'Hello'
+        +++.------.--------.    Cell #3 for 'rl' and 'd'
+        >>+.                    Add 1 to Cell #5 gives us an exclamation point
+        >++.                    And finally a newline from Cell #6
+        "#;
+        let mut cpu = Cpu::new(String::from(program), 2048);
+        cpu.one_shot_output = true;
+        let result = cpu.run(None);
+        assert!(result.is_ok());
+        assert_eq!(cpu.output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn test_add_numbers() {
+        let program = r#"
+        ++       Cell c0 = 2
+        > +++++  Cell c1 = 5
+
+        [        Start your loops with your cell pointer on the loop counter (c1 in our case)
+        < +      Add 1 to c0
+        > -      Subtract 1 from c1
+        ]        End your loops with the cell pointer on the loop counter
+
+        At this point our program has added 5 to 2 leaving 7 in c0 and 0 in c1
+        but we cannot output this value to the terminal since it is not ASCII encoded
+
+        To display the ASCII character "7" we must add 48 to the value 7
+        We use a loop to compute 48 = 6 * 8
+
+        ++++ ++++  c1 = 8 and this will be our loop counter again
+        [
+        < +++ +++  Add 6 to c0
+        > -        Subtract 1 from c1
+        ]
+        < .        Print out c0 which has the value 55 which translates to "7"!
+        "#;
+        let mut cpu = Cpu::new(String::from(program), 2048);
+        cpu.one_shot_output = true;
+        let result = cpu.run(None);
+        assert!(result.is_ok());
+        assert_eq!(cpu.output, b"7");
+    }
+
+    #[test]
+    fn test_input() {
+        let program = r#"
+        ,.
+        "#;
+        let mut cpu = Cpu::new(String::from(program), 2048);
+        cpu.one_shot_output = true;
+        let result = cpu.run(Some("1".into()));
+        assert!(result.is_ok());
+        assert_eq!(cpu.output, b"1");
+    }
+
+    #[test]
+    fn test_u16_cells_do_not_wrap_at_256() {
+        let program = format!("{}[[-]>{}<]>.", "+".repeat(256), "+".repeat(49));
+
+        let mut cpu = Cpu::new(program.clone(), 2048);
+        cpu.one_shot_output = true;
+        cpu.cell_width = CellWidth::U16;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"1");
+
+        let mut cpu = Cpu::new(program, 2048);
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"\0");
+    }
+
+    #[test]
+    fn test_u16_cells_wrap_at_65536() {
+        // 0 - 1 wraps to 0xFFFF, whose low byte is 0xFF
+        let mut cpu = Cpu::new(String::from("-."), 2048);
+        cpu.one_shot_output = true;
+        cpu.cell_width = CellWidth::U16;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"\xff");
+    }
+
+    #[test]
+    fn test_cell_width_from_bits() {
+        assert_eq!(CellWidth::from_bits(16).unwrap(), CellWidth::U16);
+        assert!(CellWidth::from_bits(12).is_err());
+    }
+
+    #[test]
+    fn test_numeric_round_trip() {
+        let mut cpu = Cpu::new(String::from(",.,+."), 2048);
+        cpu.one_shot_output = true;
+        cpu.io_mode = IoMode::Numeric;
+        let result = cpu.run(Some(" 42\n99\n".into()));
+        assert!(result.is_ok());
+        assert_eq!(cpu.output, b"42\n100\n");
+    }
+
+    #[test]
+    fn test_numeric_input_is_masked_to_cell_width() {
+        let mut cpu = Cpu::new(String::from(",."), 2048);
+        cpu.one_shot_output = true;
+        cpu.io_mode = IoMode::Numeric;
+        assert!(cpu.run(Some("300".into())).is_ok());
+        assert_eq!(cpu.output, b"44\n");
+
+        let mut cpu = Cpu::new(String::from(",."), 2048);
+        cpu.one_shot_output = true;
+        cpu.io_mode = IoMode::Numeric;
+        cpu.cell_width = CellWidth::U16;
+        assert!(cpu.run(Some("300".into())).is_ok());
+        assert_eq!(cpu.output, b"300\n");
+    }
+
+    #[test]
+    fn test_one_shot_output_per_run() {
+        let sink = SharedBuffer::default();
+        let mut cpu = Cpu::new(String::from("+++++++++++++++++++++++++++++++++."), 1);
+        cpu.one_shot_output = true;
+        cpu.sink = Box::new(sink.clone());
+        assert!(cpu.run(None).is_ok());
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(sink.contents(), "!!");
+        assert_eq!(cpu.output, b"!");
+    }
+
+    #[test]
+    fn test_numeric_eof_policies() {
+        // Whitespace alone is no number
+        let run_with = |eof_policy| {
+            let mut cpu = Cpu::new(String::from("+++,"), 16);
+            cpu.one_shot_output = true;
+            cpu.io_mode = IoMode::Numeric;
+            cpu.cell_width = CellWidth::U16;
+            cpu.eof_policy = eof_policy;
+            cpu.run(Some(" \n".into())).map(|_| cpu.tape[0])
+        };
+
+        assert!(matches!(
+            run_with(EofPolicy::Error),
+            Err(BrainfuckError::EndOfInput { pos: 3 })
+        ));
+        assert_eq!(run_with(EofPolicy::Unchanged).unwrap(), 3);
+        assert_eq!(run_with(EofPolicy::Zero).unwrap(), 0);
+        assert_eq!(run_with(EofPolicy::MinusOne).unwrap(), 0xffff);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut cpu = Cpu::new(String::from("++ [>+<-] done"), 2048);
+        cpu.one_shot_output = true;
+        cpu.stats = Some(Stats::default());
+        assert!(cpu.run(None).is_ok());
+
+        let stats = cpu.stats.unwrap();
+        assert_eq!(stats.instructions, 13);
+        assert_eq!(stats.per_command[&'+'], 4);
+        assert_eq!(stats.per_command[&'['], 1);
+        assert_eq!(stats.per_command[&']'], 2);
+        assert_eq!(stats.per_command[&'>'], 2);
+        assert_eq!(stats.max_data_pointer, 1);
+        assert_eq!(stats.loop_iterations, 2);
+        assert_eq!(stats.storage, None);
+        assert!(stats.to_string().ends_with("Loop iterations: 2"));
+    }
+
+    #[test]
+    fn test_stats_count_folded_loops() {
+        // Clear, scan and multiply loops are counted command for command
+        let mut cpu = Cpu::new(String::from("+++++[-] +[>] ++[->+<]"), 16);
+        cpu.opt_level = ir::MAX_OPT_LEVEL;
+        cpu.stats = Some(Stats::default());
+        assert!(cpu.run(None).is_ok());
+
+        let stats = cpu.stats.unwrap();
+        assert_eq!(stats.instructions, 33);
+        assert_eq!(stats.per_command[&'['], 3);
+        assert_eq!(stats.per_command[&']'], 8);
+        assert_eq!(stats.per_command[&'-'], 7);
+        assert_eq!(stats.loop_iterations, 8);
+    }
+
+    #[test]
+    fn test_stats_disabled_by_default() {
+        let mut cpu = Cpu::new(String::from("+++"), 2048);
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+        assert!(cpu.stats.is_none());
+    }
+
+    #[test]
+    fn test_debugger_step_print_continue() {
+        let (debugger, output) = scripted_debugger("s\ns\np\nc\n");
+        let mut cpu = Cpu::new(String::from("+ > ++."), 2048);
+        cpu.one_shot_output = true;
+        cpu.debugger = Some(debugger);
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"\x02");
+
+        let output = output.contents();
+        assert!(output.contains("ip: 0, command: '+', dp: 0"));
+        assert!(output.contains("ip: 2, command: '>', dp: 0"));
+        assert!(output.contains("ip: 4, command: '+', dp: 1"));
+        assert!(output.contains("0: 1 [0] 0"));
+        // Continuing runs to the end without stopping again
+        assert!(!output.contains("ip: 5"));
+    }
+
+    #[test]
+    fn test_debugger_shows_storage() {
+        let (debugger, output) = scripted_debugger(&"s\n".repeat(9));
+        let mut cpu = Cpu::new(String::from("+++$>++*/"), 8);
+        cpu.one_shot_output = true;
+        cpu.level = 2;
+        cpu.debugger = Some(debugger);
+        assert!(cpu.run(None).is_ok());
+
+        let output = output.contents();
+        assert!(output.contains("ip: 3, command: '$', dp: 0, storage: 0\n"));
+        assert!(output.contains("ip: 4, command: '>', dp: 0, storage: 3\n"));
+        assert!(output.contains("ip: 7, command: '*', dp: 1, storage: 3, 2 * 3 = 6\n"));
+        assert!(output.contains("ip: 8, command: '/', dp: 1, storage: 3, 6 / 3 = 2\n"));
+    }
+
+    #[test]
+    fn test_debugger_steps_back() {
+        let (debugger, output) = scripted_debugger("s\ns\ns\ns\nb\nb\nb\np\nq\n");
+        let mut cpu = Cpu::new(String::from("+>+.<"), 8);
+        cpu.one_shot_output = true;
+        cpu.debugger = Some(debugger);
+        assert!(cpu.run(None).is_ok());
+
+        // Back to before the first `>`, with the `.` taken back too
+        assert_eq!(cpu.tape, vec![1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(cpu.data_pointer, 0);
+        assert_eq!(cpu.output, b"");
+        let output = output.contents();
+        assert_eq!(output.matches("ip: 1, command: '>', dp: 0").count(), 2);
+        assert!(output.contains("0: [1] 0 0 0 0 0"));
+    }
+
+    #[test]
+    fn test_debugger_steps_back_over_input() {
+        // Back over `,` and `>`, so neither the byte read nor the cell moved
+        // to are left behind
+        let (debugger, _) = scripted_debugger("s\ns\nb\nb\nq\n");
+        let mut cpu = Cpu::new(String::from(">,."), 8);
+        cpu.one_shot_output = true;
+        cpu.debugger = Some(debugger);
+        assert!(cpu.run(Some("ab".into())).is_ok());
+        assert_eq!(cpu.high_water_mark(), 0);
+
+        // The `,` after stepping back reads the same byte again
+        let (debugger, _) = scripted_debugger("s\ns\nb\nc\n");
+        cpu.debugger = Some(debugger);
+        assert!(cpu.run(Some("ab".into())).is_ok());
+        assert_eq!(cpu.output, b"a");
+
+        // Except from stdin, which can not be read again
+        let (debugger, output) = scripted_debugger("s\nb\nc\n");
+        let mut cpu = CpuBuilder::new(",.")
+            .stdin(Box::new(io::Cursor::new("x")))
+            .one_shot_output(true)
+            .sink(Box::new(io::sink()))
+            .build();
+        cpu.debugger = Some(debugger);
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"x");
+        assert!(output
+            .contents()
+            .contains("Can not step back over input from stdin"));
+    }
+
+    #[test]
+    fn test_debugger_history_is_bounded() {
+        let script = format!("{}{}p\nq\n", "s\n".repeat(1100), "b\n".repeat(1100));
+        let (debugger, output) = scripted_debugger(&script);
+        let mut cpu = Cpu::new("+".repeat(1100) + ">", 8);
+        cpu.debugger = Some(debugger);
+        assert!(cpu.run(None).is_ok());
+
+        // Only the last DEBUG_HISTORY steps can be undone
+        let output = output.contents();
+        assert_eq!(cpu.tape[0], 1100 - DEBUG_HISTORY as u32);
+        assert!(output.contains("No instruction to step back over"));
+    }
+
+    #[test]
+    fn test_debugger_rewinds_to_checkpoints() {
+        // Prints every 100th step, and pauses after 2000 at the `>`
+        let program = format!("{}>+", format!("{}.", "+".repeat(99)).repeat(20));
+        let (mut debugger, output) = scripted_debugger("b 1000\nb 100000\nq\n");
+        debugger.stepping = false;
+        debugger.checkpoint_interval = Some(16);
+        let mut cpu = CpuBuilder::new(&program)
+            .cell_width(CellWidth::U16)
+            .sink(Box::new(io::sink()))
+            .build();
+        cpu.one_shot_output = true;
+        cpu.debugger = Some(debugger);
+        cpu.breakpoints.insert(2000);
+        assert!(cpu.run(None).is_ok());
+
+        // The state after the first 1000 steps
+        let mut expected = CpuBuilder::new(&program)
+            .cell_width(CellWidth::U16)
+            .opt_level(0)
+            .max_steps(1000)
+            .sink(Box::new(io::sink()))
+            .build();
+        expected.one_shot_output = true;
+        assert!(matches!(
+            expected.run(None),
+            Err(BrainfuckError::StepLimitExceeded)
+        ));
+        assert_eq!(cpu.tape[..2], expected.tape[..2]);
+        assert_eq!(cpu.tape[0], 990);
+        assert_eq!(cpu.data_pointer, expected.data_pointer);
+        assert_eq!(cpu.output, expected.output);
+        assert_eq!(cpu.output.len(), 10);
+
+        // Rewinding pauses where it got to. Only the newest MAX_CHECKPOINTS
+        // are kept, so the start of the run is out of reach
+        let output = output.contents();
+        assert_eq!(output.matches("ip: 2000, command: '>'").count(), 1);
+        assert!(output.contains("ip: 1000, command: '+'"));
+        assert!(output.contains("No checkpoint to rewind 100000 steps to"));
+    }
+
+    #[test]
+    fn test_debugger_quit() {
+        let (debugger, _) = scripted_debugger("s\nq\n");
+        let mut cpu = Cpu::new(String::from("+.+."), 2048);
+        cpu.one_shot_output = true;
+        cpu.debugger = Some(debugger);
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"");
+    }
+
+    #[test]
+    fn test_breakpoint_pauses_execution() {
+        let (mut debugger, output) = scripted_debugger("p\nc\n");
+        debugger.stepping = false;
+        let mut cpu = Cpu::new(String::from("+++>++.<."), 2048);
+        cpu.one_shot_output = true;
+        cpu.debugger = Some(debugger);
+        cpu.breakpoints.insert(5);
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"\x02\x03");
+
+        let output = output.contents();
+        assert!(output.starts_with("ip: 5, command: '+', dp: 1"));
+        assert!(output.contains("0: 3 [1] 0"));
+        assert_eq!(output.matches("ip: ").count(), 1);
+    }
+
+    #[test]
+    fn test_watchpoints_report_changes() {
+        let diagnostics = SharedBuffer::default();
+        let mut cpu = Cpu::new(String::from("+>++<-[-]>>+"), 2048);
+        cpu.one_shot_output = true;
+        cpu.diagnostics = Box::new(diagnostics.clone());
+        cpu.watchpoints.insert(1);
+        assert!(cpu.run(None).is_ok());
+
+        assert_eq!(
+            diagnostics.contents(),
+            "watch: cell 1 changed 0 -> 1 at ip 2\nwatch: cell 1 changed 1 -> 2 at ip 3\n"
+        );
+    }
+
+    #[test]
+    fn test_trace_hasher() {
+        let mut hasher = TraceHasher::new();
+        assert_eq!(hasher.hash(), FNV_OFFSET_BASIS);
+        hasher.write_all(b"a").unwrap();
+        assert_eq!(hasher.hash(), 0xaf63_dc4c_8601_ec8c);
+
+        let trace_hash = |input: &str| {
+            let hasher = TraceHasher::new();
+            let mut cpu = Cpu::new(String::from(",[>+<-]>."), 4);
+            cpu.one_shot_output = true;
+            cpu.trace = Some(Box::new(hasher.clone()));
+            assert!(cpu.run(Some(input.into())).is_ok());
+            hasher.hash()
+        };
+        assert_eq!(trace_hash("\u{3}"), trace_hash("\u{3}"));
+        assert_ne!(trace_hash("\u{3}"), trace_hash("\u{4}"));
+    }
+
+    #[test]
+    fn test_line_input() {
+        let run_on = |program: &str| {
+            let read = Rc::new(RefCell::new(0));
+            let reader = ByteAtATime(io::Cursor::new(b"hi\nthere".to_vec()), read.clone());
+            let mut cpu = CpuBuilder::new(program)
+                .stdin(Box::new(LineInput::new(io::BufReader::new(reader))))
+                .eof_policy(EofPolicy::Zero)
+                .one_shot_output(true)
+                .build();
+            assert!(cpu.run(None).is_ok());
+            let read = *read.borrow();
+            (cpu.output, read)
+        };
+
+        // The first `,` waits for the whole line
+        assert_eq!(run_on(",."), (b"h".to_vec(), 3));
+        assert_eq!(run_on(",.,.,."), (b"hi\n".to_vec(), 3));
+        // The last line ends without a newline
+        assert_eq!(run_on(",[.,]"), (b"hi\nthere".to_vec(), 8));
+    }
+
+    #[test]
+    fn test_cstring() {
+        // Writes "Hi" after a cell of its own and ends on the zero after it
+        let program = format!(">{}>{}>", "+".repeat(72), "+".repeat(105));
+        let mut cpu = Cpu::new(program, 8);
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.cstring(1), "Hi");
+        assert_eq!(cpu.cstring(2), "i");
+        assert_eq!(cpu.cstring(0), "");
+        assert_eq!(cpu.cstring(cpu.data_pointer), "");
+        assert_eq!(cpu.cstring(100), "");
+
+        // Without a zero the string ends with the tape
+        let mut cpu = Cpu::new(String::from("+>+"), 2);
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.cstring(0), "\u{1}\u{1}");
+    }
+
+    #[test]
+    fn test_dump_tape() {
+        let program = format!("{}>+>{}", "+".repeat(72), "+".repeat(300));
+        let mut cpu = Cpu::new(program, 2048);
+        cpu.one_shot_output = true;
+        cpu.cell_width = CellWidth::U16;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.high_water_mark(), 2);
+        assert_eq!(
+            cpu.dump_tape(0..cpu.high_water_mark() + 1),
+            "index    dec      hex  char\n\
+             \x20   0     72     0x48  H\n\
+             \x20   1      1      0x1  .\n\
+             \x20   2    300    0x12c  .\n"
+        );
+        // Ranges past the end of the tape are clipped
+        assert_eq!(cpu.dump_tape(2047..4096).lines().count(), 2);
+    }
+
+    #[test]
+    fn test_reset_between_runs() {
+        let mut cpu = Cpu::new(String::from("+++++[>++++++++++<-]>-."), 2048);
+        cpu.one_shot_output = true;
+        cpu.stats = Some(Stats::default());
+        cpu.coverage = Some(Coverage::default());
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"1");
+        assert_eq!(cpu.data_pointer, 1);
+
+        cpu.reset();
+        assert_eq!(cpu.output, b"");
+        assert_eq!(cpu.data_pointer, 0);
+        assert_eq!(cpu.steps, 0);
+        assert_eq!(cpu.output_bytes, 0);
+        assert_eq!(cpu.stats, Some(Stats::default()));
+        assert!(cpu.coverage.as_ref().unwrap().executed.is_empty());
+
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"1");
+        assert_eq!(cpu.data_pointer, 1);
+        assert_eq!(cpu.stats.as_ref().unwrap().per_command[&'.'], 1);
+    }
+
+    #[test]
+    fn test_write_output_escaping() {
+        let mut raw = Vec::new();
+        let mut escaped = Vec::new();
+        for &byte in b"Hi \n\x07\t\xff" {
+            write_output(&mut raw, byte, false).unwrap();
+            write_output(&mut escaped, byte, true).unwrap();
+        }
+        assert_eq!(raw, b"Hi \n\x07\t\xff");
+        assert_eq!(escaped, b"Hi \n\\x07\\x09\\xff");
+    }
+
+    #[test]
+    fn test_high_bytes_are_written_raw() {
+        // Prints 128 to 255, none of which may be encoded as UTF-8
+        let program = "+".repeat(128) + "." + &"+.".repeat(127);
+        let expected: Vec<u8> = (128..=255).collect();
+
+        let mut one_shot = Cpu::new(program.clone(), 1);
+        one_shot.one_shot_output = true;
+        one_shot.sink = Box::new(io::sink());
+        assert!(one_shot.run(None).is_ok());
+        assert_eq!(one_shot.output, expected);
+
+        let sink = SharedBuffer::default();
+        let mut streamed = Cpu::new(program, 1);
+        streamed.sink = Box::new(sink.clone());
+        assert!(streamed.run(None).is_ok());
+        assert_eq!(*sink.0.borrow(), expected);
+    }
+
+    #[test]
+    fn test_minify() {
+        let program = r#"
+        [ A "hello" comment, with punctuation. ]
+        ++++++++[>++++<-]>.  Print a space! Then $store it
+        "#;
+        assert_eq!(minify(program, 0), "[,.]++++++++[>++++<-]>.");
+        assert_eq!(minify(program, 1), "[,.]++++++++[>++++<-]>.!$");
+    }
+
+    #[test]
+    fn test_enabled_commands() {
+        // At level 2 `/` would divide the 9 by the stored 3
+        let enabled = CommandSet::parse("$*").unwrap();
+        let mut cpu = CpuBuilder::new("+++$*/.")
+            .level(2)
+            .enabled_commands(enabled)
+            .build();
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"\x09");
+
+        assert_eq!(minify_enabled("+$*/@ 0", 3, Some(enabled)), "+$*");
+        assert!(enabled.contains('*') && !enabled.contains('/'));
+        assert!(CommandSet::parse("+").is_err());
+        assert!(CommandSet::parse("x").is_err());
+    }
+
+    #[test]
+    fn test_inactive_extended_commands_are_comments() {
+        let plain = "++++++++[>++++++<-]>+.";
+        let commented = "{}++++++++[>+++@+++<-]$>+.{";
+
+        let mut expected = Cpu::new(String::from(plain), 2048);
+        expected.one_shot_output = true;
+        expected.stats = Some(Stats::default());
+        assert!(expected.run(None).is_ok());
+
+        let mut cpu = Cpu::new(String::from(commented), 2048);
+        cpu.one_shot_output = true;
+        cpu.stats = Some(Stats::default());
+        assert!(cpu.run(None).is_ok());
+
+        assert_eq!(cpu.output, b"1");
+        assert_eq!(cpu.output, expected.output);
+        assert_eq!(cpu.stats, expected.stats);
+    }
+
+    #[test]
+    fn test_unmatched_bracket_is_an_error() {
+        let mut cpu = Cpu::new(String::from("+[>+<-"), 2048);
+        cpu.one_shot_output = true;
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::UnmatchedBracket { pos: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_scan_loops_run() {
+        let mut cpu = Cpu::new(String::from("+>+>+>>+<<<<[>]>[<]>."), 2048);
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"\x01");
+        assert_eq!(cpu.data_pointer, 4);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Cpu::new(String::from("[[]]"), 2048).validate().is_ok());
+        assert!(matches!(
+            Cpu::new(String::from("[]]"), 2048).validate(),
+            Err(BrainfuckError::UnmatchedBracket { pos: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_pointer_policies() {
+        let mut cpu = Cpu::new(String::from(">>>>+<<<<<<+"), 4);
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.data_pointer, 0);
+        assert_eq!(
+            cpu.dump_tape(0..4)
+                .lines()
+                .nth(1)
+                .unwrap()
+                .split_whitespace()
+                .nth(1),
+            Some("1")
+        );
+
+        let mut cpu = Cpu::new(String::from(">>>>+<<"), 4);
+        cpu.one_shot_output = true;
+        cpu.pointer_policy = PointerPolicy::Wrap;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.data_pointer, 2);
+
+        let mut cpu = Cpu::new(String::from("+>>>>+"), 4);
+        cpu.one_shot_output = true;
+        cpu.pointer_policy = PointerPolicy::Error;
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::PointerOutOfBounds { pos: 1, pointer: 4 })
+        ));
+
+        let mut cpu = Cpu::new(String::from("+ <"), 4);
+        cpu.one_shot_output = true;
+        cpu.pointer_policy = PointerPolicy::Error;
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::PointerOutOfBounds {
+                pos: 2,
+                pointer: -1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_cell_overflow_is_independent_of_the_pointer() {
+        // The cell goes below zero with the pointer on the tape, then the
+        // pointer leaves the tape with the cell in range
+        let run_with = |program: &str, compat| {
+            let mut cpu = CpuBuilder::new(program)
+                .tape_size(4)
+                .compat(compat)
+                .sink(Box::new(io::sink()))
+                .build();
+            cpu.one_shot_output = true;
+            cpu.run(None).map(|()| (cpu.data_pointer, cpu.tape.clone()))
+        };
+
+        assert_eq!(
+            run_with("-", Compat::Classic).unwrap(),
+            (0, vec![255, 0, 0, 0])
+        );
+        assert!(matches!(
+            run_with("<+", Compat::Classic),
+            Err(BrainfuckError::PointerOutOfBounds {
+                pos: 0,
+                pointer: -1
+            })
+        ));
+
+        assert_eq!(
+            run_with("-", Compat::Wrapping).unwrap(),
+            (0, vec![255, 0, 0, 0])
+        );
+        assert_eq!(
+            run_with("<+", Compat::Wrapping).unwrap(),
+            (3, vec![0, 0, 0, 1])
+        );
+
+        assert!(matches!(
+            run_with(">-", Compat::Strict),
+            Err(BrainfuckError::CellOutOfRange { pos: 1, value: -1 })
+        ));
+        assert!(matches!(
+            run_with("<+", Compat::Strict),
+            Err(BrainfuckError::PointerOutOfBounds {
+                pos: 0,
+                pointer: -1
+            })
+        ));
+
+        // Either policy can be set without the other
+        for opt_level in 0..=ir::MAX_OPT_LEVEL {
+            let mut cpu = CpuBuilder::new(&format!("{}[->+<]", "+".repeat(200)))
+                .tape_size(2)
+                .cell_overflow(CellOverflow::Error)
+                .pointer_policy(PointerPolicy::Wrap)
+                .opt_level(opt_level)
+                .build();
+            assert!(cpu.run(None).is_ok());
+            assert_eq!(cpu.tape, vec![0, 200]);
+
+            cpu.feed_tape = format!("{}<<+", "+".repeat(255));
+            assert!(matches!(
+                cpu.run(None),
+                Err(BrainfuckError::CellOutOfRange { value: 256, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_folded_moves_stop_at_the_edge() {
+        for opt_level in 0..=ir::MAX_OPT_LEVEL {
+            // Eight steps right from cell 1 of 4, then back
+            let mut cpu = Cpu::new(String::from(">+>>>>>>>>+<<"), 4);
+            cpu.one_shot_output = true;
+            cpu.opt_level = opt_level;
+            assert!(cpu.run(None).is_ok());
+            assert_eq!(cpu.data_pointer, 1);
+            assert_eq!(cpu.tape, vec![0, 1, 0, 1]);
+
+            let mut cpu = Cpu::new(String::from(">>> comment <<<<<<<<"), 4);
+            cpu.pointer_policy = PointerPolicy::Error;
+            cpu.opt_level = opt_level;
+            assert!(matches!(
+                cpu.run(None),
+                Err(BrainfuckError::PointerOutOfBounds { pointer: -1, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_scan_policies() {
+        // Every cell is non-zero, so there is nothing to scan to
+        let program = String::from("+>+>+>+[>]");
+
+        let mut cpu = Cpu::new(program.clone(), 4);
+        cpu.one_shot_output = true;
+        cpu.pointer_policy = PointerPolicy::Error;
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::PointerOutOfBounds { pos: 7, pointer: 4 })
+        ));
+
+        let mut cpu = Cpu::new(program, 4);
+        cpu.one_shot_output = true;
+        cpu.max_steps = Some(1000);
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::StepLimitExceeded)
+        ));
+        assert_eq!(cpu.data_pointer, 3);
+
+        // Wrapping scans continue from the start of the tape
+        let mut cpu = Cpu::new(String::from(">+>+>+[>]"), 4);
+        cpu.one_shot_output = true;
+        cpu.pointer_policy = PointerPolicy::Wrap;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.data_pointer, 0);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let mut cpu = Cpu::new(String::from("+[]"), 2048);
+        cpu.one_shot_output = true;
+        cpu.max_steps = Some(100);
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::StepLimitExceeded)
+        ));
+        assert_eq!(cpu.steps, 101);
+
+        let mut cpu = Cpu::new(String::from("+++"), 2048);
+        cpu.one_shot_output = true;
+        cpu.max_steps = Some(1);
+        assert!(cpu.run(None).is_ok());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut cpu = Cpu::new(String::from("+[]"), 2048);
+        cpu.one_shot_output = true;
+        cpu.timeout = Some(Duration::from_millis(10));
+        assert!(matches!(cpu.run(None), Err(BrainfuckError::TimedOut)));
+    }
+
+    #[test]
+    fn test_end_of_input() {
+        // Running out of pre-defined input is not an I/O error
+        let mut cpu = Cpu::new(String::from(",,"), 2048);
+        cpu.one_shot_output = true;
+        assert!(matches!(
+            cpu.run(Some("a".into())),
+            Err(BrainfuckError::EndOfInput { pos: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_lenient_division_by_zero() {
+        // Storage is still zero, so both divisions leave the cell alone
+        let mut cpu = Cpu::new(String::from("+++++/%"), 2048);
+        cpu.one_shot_output = true;
+        cpu.level = 2;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.tape[0], 5);
+    }
+
+    #[test]
+    fn test_strict_division_by_zero() {
+        let mut cpu = Cpu::new(String::from("+++++ /"), 2048);
+        cpu.one_shot_output = true;
+        cpu.level = 2;
+        cpu.strict_division = true;
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::DivisionByZero { pos: 6 })
+        ));
+
+        let mut cpu = Cpu::new(String::from("+%"), 2048);
+        cpu.one_shot_output = true;
+        cpu.level = 2;
+        cpu.strict_division = true;
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::DivisionByZero { pos: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_run_program() {
+        assert_eq!(run_program(",+.,+.", "ab", 0, 16).unwrap(), b"bc");
+        assert_eq!(run_program("+++$*.", "", 2, 16).unwrap(), b"\x09");
+        assert!(matches!(
+            run_program("[", "", 0, 16),
+            Err(BrainfuckError::UnmatchedBracket { pos: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_run_bytes() {
+        // Latin-1 comments around the commands are not UTF-8
+        let program = b"\xe9t\xe9 ++++++[>+++++++++++<-]>. \xff[";
+        assert_eq!(
+            Cpu::from_bytes(program, 16).feed_tape,
+            " t  ++++++[>+++++++++++<-]>.  ["
+        );
+        assert!(matches!(
+            run_bytes(program, "", 0, 16),
+            Err(BrainfuckError::UnmatchedBracket { pos: 30 })
+        ));
+        assert_eq!(run_bytes(&program[..28], "", 0, 16).unwrap(), b"B");
+        assert_eq!(run_bytes("é,.".as_bytes(), "x", 0, 16).unwrap(), b"x");
+    }
+
+    #[test]
+    fn test_output_goes_to_sink() {
+        let output = SharedBuffer::default();
+        let mut cpu = Cpu::new(String::from("++++++++[>++++++++<-]>+.+."), 16);
+        cpu.sink = Box::new(output.clone());
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(output.contents(), "AB");
+        assert_eq!(cpu.output, b"");
+    }
+
+    #[test]
+    fn test_execute_keeps_state() {
+        let mut cpu = Cpu::new(String::from("+++>++"), 16);
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+
+        cpu.feed_tape = String::from("<+$");
+        cpu.level = 1;
+        assert!(cpu.execute(None).is_ok());
+        assert_eq!(cpu.data_pointer, 0);
+        assert_eq!(cpu.storage, 4);
+        assert_eq!(cpu.tape[..2], [4, 2]);
+
+        // `run` still starts from a blank tape
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.tape[..2], [1, 0]);
+    }
+
+    #[test]
+    fn test_repl() {
+        let program_output = SharedBuffer::default();
+        let mut cpu = Cpu::new(String::new(), 16);
+        cpu.sink = Box::new(program_output.clone());
+
+        let mut input =
+            io::Cursor::new(":tape\n++++++++[>++++++++<-]\n>+.\n[\n:tape\n:view\n:reset\n:tape\n");
+        let mut output = Vec::new();
+        repl(&mut cpu, &mut input, &mut output).unwrap();
+
+        assert_eq!(program_output.contents(), "A");
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "bf> index    dec      hex  char\n\
+             bf> bf> bf> error: Unmatched bracket at position 0\n\
+             bf> index    dec      hex  char\n    0      0      0x0  .\n    1     65     0x41  A\n\
+             bf>    0  65   0   0   0   0   0   0   0   0   0   0   0   0   0   0\n       ^\n\
+             bf> bf> index    dec      hex  char\n\
+             bf> "
+        );
+    }
+
+    #[test]
+    fn test_eof_policies() {
+        let run_with = |eof_policy| {
+            let mut cpu = Cpu::new(String::from("+++,"), 16);
+            cpu.one_shot_output = true;
+            cpu.cell_width = CellWidth::U16;
+            cpu.eof_policy = eof_policy;
+            cpu.run(Some(Vec::new())).map(|_| cpu.tape[0])
+        };
+
+        assert!(matches!(
+            run_with(EofPolicy::Error),
+            Err(BrainfuckError::EndOfInput { pos: 3 })
+        ));
+        assert_eq!(run_with(EofPolicy::Unchanged).unwrap(), 3);
+        assert_eq!(run_with(EofPolicy::Zero).unwrap(), 0);
+        assert_eq!(run_with(EofPolicy::MinusOne).unwrap(), 0xffff);
+    }
+
+    #[test]
+    fn test_ascii_policies() {
+        // `é` is the two bytes 0xc3 0xa9 in UTF-8
+        let run_with = |ascii_policy| {
+            let mut cpu = CpuBuilder::new(",>,>,")
+                .tape_size(4)
+                .ascii_policy(ascii_policy)
+                .build();
+            cpu.one_shot_output = true;
+            cpu.run(Some("aé".into())).map(|_| cpu.tape.clone())
+        };
+
+        assert_eq!(
+            run_with(AsciiPolicy::Any).unwrap(),
+            vec![b'a' as u32, 0xc3, 0xa9, 0]
+        );
+        assert_eq!(
+            run_with(AsciiPolicy::Mask).unwrap(),
+            vec![b'a' as u32, 0x43, 0x29, 0]
+        );
+        assert!(matches!(
+            run_with(AsciiPolicy::Error),
+            Err(BrainfuckError::NonAsciiInput { pos: 2, byte: 0xc3 })
+        ));
+    }
+
+    #[test]
+    fn test_eof_without_input() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("device gone"))
+            }
+        }
+
+        // No pre-defined input, and stdin has nothing to read
+        let run_with = |eof_policy, stdin: Box<dyn Read>| {
+            let mut cpu = Cpu::new(String::from("+++,+"), 16);
+            cpu.one_shot_output = true;
+            cpu.eof_policy = eof_policy;
+            cpu.stdin = stdin;
+            cpu.run(None).map(|_| cpu.tape[0])
+        };
+
+        assert_eq!(run_with(EofPolicy::Zero, Box::new(io::empty())).unwrap(), 1);
+        assert_eq!(
+            run_with(EofPolicy::Unchanged, Box::new(io::empty())).unwrap(),
+            4
+        );
+        assert!(matches!(
+            run_with(EofPolicy::Error, Box::new(io::empty())),
+            Err(BrainfuckError::EndOfInput { pos: 3 })
+        ));
+        // A reader that fails is not the end of the input, whatever the policy
+        assert!(matches!(
+            run_with(EofPolicy::Zero, Box::new(Failing)),
+            Err(BrainfuckError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_restore_continues_the_run() {
+        let program = String::from(",[>+++<-]>[$<+>-]<!%.,.");
+        let input = b"\x05x".to_vec();
+
+        let mut uninterrupted = Cpu::new(program.clone(), 16);
+        uninterrupted.one_shot_output = true;
+        uninterrupted.level = 2;
+        assert!(uninterrupted.run(Some(input.clone())).is_ok());
+
+        let mut first = Cpu::new(program.clone(), 16);
+        first.one_shot_output = true;
+        first.level = 2;
+        first.max_steps = Some(20);
+        assert!(matches!(
+            first.run(Some(input.clone())),
+            Err(BrainfuckError::StepLimitExceeded)
+        ));
+        let state = first.snapshot();
+        assert_ne!(state.instruction_pointer, 0);
+
+        let mut second = Cpu::new(program, 4);
+        second.one_shot_output = true;
+        second.restore(state);
+        assert!(second.execute(Some(input)).is_ok());
+        assert_eq!(second.output, uninterrupted.output);
+        assert_eq!(second.snapshot(), uninterrupted.snapshot());
+    }
+
+    #[test]
+    fn test_trace() {
+        let trace = SharedBuffer::default();
+        let mut cpu = Cpu::new(String::from("++[>+<-] done"), 16);
+        cpu.one_shot_output = true;
+        cpu.trace = Some(Box::new(trace.clone()));
+        assert!(cpu.run(None).is_ok());
+
+        // `]` jumps back past `[`, so only the first iteration runs it
+        let trace = trace.contents();
+        assert_eq!(trace.lines().count(), 2 + 6 + 5);
+        assert_eq!(trace.lines().next(), Some("1 0 + 0 0"));
+        assert_eq!(trace.lines().nth(3), Some("4 3 > 0 2"));
+        assert_eq!(trace.lines().last(), Some("13 7 ] 0 0"));
+    }
+
+    #[test]
+    fn test_zero_tape_size() {
+        let mut cpu = Cpu::new(String::from("+"), 0);
+        cpu.one_shot_output = true;
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::PointerOutOfBounds { pos: 0, pointer: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_render_tape() {
+        let mut cpu = Cpu::new(String::from("+>++>+++>++++++++++[>++++++++++<-]>-<<"), 8);
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+
+        assert_eq!(cpu.render_tape(3), "   2   3   0\n       ^\n");
+        // Near either end the window stays on the tape
+        assert_eq!(
+            cpu.render_tape(8).lines().next(),
+            Some("   1   2   3   0  99   0   0   0")
+        );
+        cpu.data_pointer = 0;
+        assert_eq!(cpu.render_tape(2), "   1   2\n   ^\n");
+        cpu.data_pointer = 7;
+        assert_eq!(cpu.render_tape(3), "   0   0   0\n           ^\n");
+    }
+
+    #[test]
+    fn test_coverage() {
+        let mut cpu = Cpu::new(String::from("+. >[->+<] -"), 16);
+        cpu.one_shot_output = true;
+        cpu.coverage = Some(Coverage::default());
+        assert!(cpu.run(None).is_ok());
+
+        // The second cell is 0, so `[` jumps past the body and its `]`
+        let coverage = cpu.coverage.unwrap();
+        assert_eq!(coverage.uncovered(), vec![5, 6, 7, 8, 9]);
+        assert_eq!(
+            coverage.to_string(),
+            "Coverage: 50.0% of 10 commands\nNever executed: 5, 6, 7, 8, 9"
+        );
+    }
+
+    #[test]
+    fn test_hotspots() {
+        // The inner loop runs 3 times for each of the 4 outer iterations
+        let mut cpu = Cpu::new(String::from("++++[>+++[>+<-]<-] ++[-]"), 16);
+        cpu.one_shot_output = true;
+        cpu.hotspots = Some(Hotspots::default());
+        assert!(cpu.run(None).is_ok());
+
+        let hotspots = cpu.hotspots.unwrap();
+        assert_eq!(hotspots.top(1), vec![(9, 8)]);
+        // `[-]` counts too, although level 2 would clear the cell in one go
+        assert_eq!(hotspots.top(5), vec![(9, 8), (4, 3), (21, 1)]);
+    }
+
+    #[test]
+    fn test_memory_profile() {
+        // Writes cells 0 to 9 and then walks two cells further without
+        // writing, which the smallest tape has to include
+        let mut cpu = Cpu::new(String::from("+++++++++[[>+<-]>-]>>"), 64);
+        cpu.one_shot_output = true;
+        cpu.memory_profile = Some(MemoryProfile::default());
+        assert!(cpu.run(None).is_ok());
+
+        let memory_profile = cpu.memory_profile.unwrap();
+        assert_eq!(memory_profile.cells_written(), 10);
+        assert_eq!(memory_profile.written_range(), Some((0, 9)));
+        assert_eq!(
+            memory_profile.to_string(),
+            "Cells written: 10 of 64\nWritten range: 0..=9 (10 cells)\nSmallest tape that fits: 12 cells"
+        );
+    }
+
+    #[test]
+    fn test_unimplemented_command() {
+        let mut cpu = Cpu::new(String::from("41.(.)"), 16);
+        cpu.one_shot_output = true;
+        cpu.level = 3;
+        let error = cpu.run(None).unwrap_err();
+
+        assert!(matches!(
+            error,
+            BrainfuckError::UnimplementedCommand {
+                command: '(',
+                pos: 3
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Command '(' at position 3 is not implemented yet"
+        );
+        // Output up to the command is kept
+        assert_eq!(cpu.output, b"A");
+    }
+
+    #[test]
+    fn test_hex_literals() {
+        assert_eq!(run_program("48.65.6C.6C.6F.", "", 3, 16).unwrap(), b"Hello");
+        // Literals are masked to the cell width
+        let mut cpu = Cpu::new(String::from("1FF"), 16);
+        cpu.one_shot_output = true;
+        cpu.level = 3;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.tape[0], 0xff);
+    }
+
+    #[test]
+    fn test_output_limit() {
+        let output = SharedBuffer::default();
+        let mut cpu = Cpu::new(String::from("++++++++[>++++++++<-]>+[.]"), 16);
+        cpu.sink = Box::new(output.clone());
+        cpu.max_output = Some(10);
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::OutputLimitExceeded)
+        ));
+        assert_eq!(output.contents(), "AAAAAAAAAA");
+
+        // Numeric output counts the digits and separators
+        let mut cpu = Cpu::new(String::from("++++++++++..."), 16);
+        cpu.one_shot_output = true;
+        cpu.io_mode = IoMode::Numeric;
+        cpu.max_output = Some(6);
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::OutputLimitExceeded)
+        ));
+        assert_eq!(cpu.output, b"10\n10\n");
+    }
+
+    #[test]
+    fn test_builder() {
+        let output = SharedBuffer::default();
+        let mut cpu = CpuBuilder::new("<++++++++$**.")
+            .tape_size(4)
+            .level(2)
+            .cell_width(CellWidth::U16)
+            .io_mode(IoMode::Numeric)
+            .pointer_policy(PointerPolicy::Wrap)
+            .max_steps(1000)
+            .sink(Box::new(output.clone()))
+            .build();
+        assert!(cpu.run(None).is_ok());
+
+        // Wrapped to the last cell, and 8 * 8 * 8 does not fit a byte
+        assert_eq!(cpu.data_pointer, 3);
+        assert_eq!(output.contents(), "512\n");
+    }
+
+    #[test]
+    fn test_builder_defaults() {
+        let cpu = CpuBuilder::new("+").build();
+        assert_eq!(cpu.tape_size, DEFAULT_TAPE_SIZE);
+        assert_eq!(cpu.level, 0);
+        assert_eq!(cpu.pointer_policy, PointerPolicy::Clamp);
+        assert_eq!(cpu.eof_policy, EofPolicy::Error);
+        assert_eq!(cpu.max_steps, None);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new("++[>[-]<-] comment. $", 0);
+        assert_eq!(metrics.commands, 11);
+        assert_eq!(
+            metrics.per_command,
+            BTreeMap::from([
+                ('+', 2),
+                ('-', 2),
+                ('.', 1),
+                ('<', 1),
+                ('>', 1),
+                ('[', 2),
+                (']', 2)
+            ])
+        );
+        assert_eq!(metrics.loops, 2);
+        assert_eq!(metrics.max_depth, 2);
+        assert!(metrics.balanced);
+
+        assert!(!Metrics::new("+[", 0).balanced);
+        assert!(!Metrics::new("+][", 0).balanced);
+        assert_eq!(
+            Metrics::new("[]", 0).to_string(),
+            "Commands: 2\n  '[': 1\n  ']': 1\nLoops: 1\nMax nesting depth: 1\nBrackets balanced: yes"
+        );
+
+        // Commands enabled one by one count, the rest of their level does not
+        let enabled = CommandSet::parse("$").unwrap();
+        let metrics = Metrics::new_enabled("+$*", 0, Some(enabled));
+        assert_eq!(metrics.commands, 2);
+        assert_eq!(metrics.per_command, BTreeMap::from([('$', 1), ('+', 1)]));
+    }
+
+    #[test]
+    fn test_reset_clears_storage() {
+        let mut cpu = Cpu::new(String::from("+++$"), 16);
+        cpu.level = 1;
+        cpu.one_shot_output = true;
+        cpu.stats = Some(Stats::default());
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.storage, 3);
+        assert_eq!(cpu.snapshot().storage, 3);
+        assert_eq!(cpu.stats.as_ref().unwrap().storage, Some(3));
+
+        cpu.reset();
+        assert_eq!(cpu.storage, 0);
+        cpu.feed_tape = String::from("!.");
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"\0");
+    }
+
+    #[test]
+    fn test_initial_value() {
+        let mut cpu = CpuBuilder::new("+.>.")
+            .tape_size(4)
+            .initial_value(255)
+            .one_shot_output(true)
+            .build();
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"\0\xff");
+        assert_eq!(cpu.tape, vec![0, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_poison() {
+        // Prints a cell it never wrote
+        let mut cpu = CpuBuilder::new(">.")
+            .tape_size(3)
+            .initial_value(1)
+            .poison(Poison::Pattern(POISON_PATTERN))
+            .one_shot_output(true)
+            .build();
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"\xaa");
+        assert_eq!(cpu.tape, vec![0xaa; 3]);
+
+        let mut cpu = CpuBuilder::new("")
+            .tape_size(3)
+            .cell_width(CellWidth::U16)
+            .poison(Poison::Pattern(0x12))
+            .build();
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.tape, vec![0x1212; 3]);
+
+        let poisoned = |seed| {
+            let mut cpu = CpuBuilder::new("").tape_size(64).build();
+            cpu.poison = Some(Poison::Random(seed));
+            assert!(cpu.run(None).is_ok());
+            cpu.tape
+        };
+        assert_eq!(poisoned(7), poisoned(7));
+        assert_ne!(poisoned(7), poisoned(8));
+        assert!(poisoned(7).iter().all(|&cell| cell <= 0xff));
+        assert!(poisoned(7).iter().any(|&cell| cell != 0));
+
+        assert_eq!(Poison::from_name("random:3").unwrap(), Poison::Random(3));
+        assert!(Poison::from_name("random:x").is_err());
+        assert!(Poison::from_name("zero").is_err());
+    }
+
+    #[test]
+    fn test_debug_summary() {
+        let mut cpu = Cpu::new("+".repeat(65) + "$" + &".".repeat(40) + ">", 16);
+        cpu.level = 1;
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+
+        assert_eq!(
+            format!("{:?}", cpu),
+            format!(
+                "Cpu {{ data_pointer: 1, storage: 65, level: 1, tape_size: 16, steps: 43, \
+                 output: {:?}, .. }}",
+                "A".repeat(DEBUG_OUTPUT_PREVIEW)
+            )
+        );
+    }
+
+    #[test]
+    fn test_step() {
+        let sink = SharedBuffer::default();
+        let mut cpu = CpuBuilder::new("+[>,.<-] @ never runs +")
+            .tape_size(4)
+            .level(1)
+            .stdin(Box::new(io::Cursor::new("x")))
+            .sink(Box::new(sink.clone()))
+            .build();
+
+        let mut commands = String::new();
+        let mut data_pointers = Vec::new();
+        while let Some(step) = cpu.step().unwrap() {
+            commands.push(step.command);
+            data_pointers.push(step.data_pointer);
+            // The state is visible between steps
+            assert_eq!(cpu.steps as usize, commands.len());
+        }
+        assert_eq!(commands, "+[>,.<-]@");
+        assert_eq!(data_pointers, vec![0, 0, 0, 1, 1, 1, 0, 0, 0]);
+        assert_eq!(sink.contents(), "x");
+        assert_eq!(cpu.tape, vec![0, 120, 0, 0]);
+        assert_eq!(cpu.steps, 9);
+
+        // Finished programs start over
+        assert_eq!(cpu.step().unwrap().map(|step| step.command), Some('+'));
+    }
+
+    #[test]
+    fn test_output_before_an_error_is_kept() {
+        let sink = SharedBuffer::default();
+        let mut cpu = CpuBuilder::new("++++++++[>++++++++<-]>+.+.[]")
+            .one_shot_output(true)
+            .max_steps(100)
+            .sink(Box::new(sink.clone()))
+            .build();
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::StepLimitExceeded)
+        ));
+        assert_eq!(cpu.output, b"AB");
+        assert_eq!(sink.contents(), "AB");
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("0").unwrap(), 0);
+        assert_eq!(parse_level("3").unwrap(), 3);
+        assert_eq!(
+            parse_level("4").unwrap_err().to_string(),
+            "Invalid level: 4 (expected 0 to 3)"
+        );
+        assert!(parse_level("-1").is_err());
+
+        // A level set directly is rejected when the program compiles
+        let mut cpu = Cpu::new(String::from("+"), 4);
+        cpu.level = 9;
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::InvalidLevel { level: 9 })
+        ));
+    }
+
+    #[test]
+    fn test_strip_bom() {
+        assert_eq!(strip_bom("\u{feff}+.\r\n"), "+.\r\n");
+        assert_eq!(strip_bom("+.\u{feff}"), "+.\u{feff}");
+    }
+
+    #[test]
+    fn test_strip_shebang() {
+        assert_eq!(strip_shebang("#!/usr/bin/env bf -l 1\n+.\n"), "\n+.\n");
+        assert_eq!(strip_shebang("#!/usr/bin/env bf"), "");
+        assert_eq!(strip_shebang("+.\n#!not a shebang"), "+.\n#!not a shebang");
+    }
+
+    #[test]
+    fn test_split_input() {
+        assert_eq!(
+            split_input(",[.,]\n!\nhello\n!\n"),
+            (",[.,]\n", Some("hello\n!\n"))
+        );
+        assert_eq!(split_input("+.\r\n!\r\nab"), ("+.\r\n", Some("ab")));
+        assert_eq!(split_input("!\n"), ("", Some("")));
+        // `!` is a command from level 1 on, and only a line of its own splits
+        assert_eq!(split_input("+!.\n !\n!-"), ("+!.\n !\n!-", None));
+    }
+
+    #[test]
+    fn test_disable_spans() {
+        let program = "+`+ é\n-`+";
+        let enabled = disable_spans(program).unwrap();
+        assert_eq!(enabled, "+     \n  +");
+        assert_eq!(enabled.len(), program.len());
+
+        let mut cpu = Cpu::new(enabled, 4);
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.tape[0], 2);
+
+        assert_eq!(disable_spans("+`-`.`[").unwrap_err(), 5);
+    }
+
+    #[test]
+    fn test_debug_hash() {
+        let diagnostics = SharedBuffer::default();
+        let mut cpu = Cpu::new(String::from("+>++#"), 16);
+        cpu.one_shot_output = true;
+        cpu.debug_hash = true;
+        cpu.diagnostics = Box::new(diagnostics.clone());
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(
+            diagnostics.contents(),
+            "   1   2   0   0   0   0   0   0   0   0\n       ^\n"
+        );
+    }
+
+    #[test]
+    fn test_high_water_mark() {
+        let mut cpu = Cpu::new(format!("{}+{}", ">".repeat(100), "<".repeat(60)), 2048);
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.data_pointer, 40);
+        assert_eq!(cpu.high_water_mark(), 100);
+    }
+
+    /// Keeps what is logged, for every test at once
+    struct CapturingLogger(Mutex<Vec<String>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_final_tape_is_logged_at_trace_level() {
+        static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        // Cells past the high water mark are not part of the summary
+        let mut cpu = Cpu::new(format!("+++>>>>>>+{}<<", "+".repeat(7)), 2048);
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.tape_summary(), "3 0*5 8");
+
+        let logged = LOGGER.0.lock().unwrap();
+        assert!(logged.contains(&String::from("Final tape, cells 0 to 6: 3 0*5 8")));
+    }
+
+    #[test]
+    fn test_interrupt() {
+        static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+        let output = SharedBuffer::default();
+        let mut cpu = Cpu::new(String::from("+.+[]"), 16);
+        cpu.one_shot_output = true;
+        cpu.sink = Box::new(output.clone());
+        cpu.interrupt = Some(&INTERRUPT);
+        INTERRUPT.store(true, Ordering::Relaxed);
+
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::Interrupted { pos: 3 | 4 })
+        ));
+        assert_eq!(output.contents(), "\u{1}");
+    }
+
+    #[test]
+    fn test_interrupt_while_waiting_for_input() {
+        static INTERRUPT: AtomicBool = AtomicBool::new(false);
+        let run_on = |stdin: Box<dyn Read>| {
+            let mut cpu = CpuBuilder::new(",.")
+                .stdin(stdin)
+                .one_shot_output(true)
+                .sink(Box::new(io::sink()))
+                .build();
+            cpu.interrupt = Some(&INTERRUPT);
+            cpu.run(None).map(|()| cpu.output)
+        };
+        let reader = || InterruptedOnce(io::Cursor::new(b"a\n".to_vec()), false);
+
+        // Any other signal only makes the read start over
+        assert_eq!(run_on(Box::new(reader())).unwrap(), b"a");
+        assert_eq!(
+            run_on(Box::new(LineInput::new(io::BufReader::new(reader())))).unwrap(),
+            b"a"
+        );
+
+        INTERRUPT.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            run_on(Box::new(reader())),
+            Err(BrainfuckError::Interrupted { pos: 0 })
+        ));
+        assert!(matches!(
+            run_on(Box::new(LineInput::new(io::BufReader::new(reader())))),
+            Err(BrainfuckError::Interrupted { pos: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_opt_levels_agree() {
+        let programs = [
+            "++++++++++[>+++++++>++++++++++>+++>+<<<<-]>++.>+.+++++++\
+             ..+++.>++.<<+++++++++++++++.>.+++.------.--------.>+.>.",
+            "+>+>+>>+<[<]>[.>]",
+            "++++[>+++[-]<-]>>--[+]<<<<<.",
+            ",[.,]",
+        ];
+
+        for program in programs {
+            let outputs: Vec<(Vec<u8>, u64)> = (0..=ir::MAX_OPT_LEVEL)
+                .map(|opt_level| {
+                    let mut cpu = Cpu::new(program.to_string(), 16);
+                    cpu.one_shot_output = true;
+                    cpu.eof_policy = EofPolicy::Zero;
+                    cpu.opt_level = opt_level;
+                    cpu.run(Some("echo".into())).unwrap();
+                    (cpu.output, cpu.steps)
+                })
+                .collect();
+
+            assert!(outputs.iter().all(|(output, _)| *output == outputs[0].0));
+            // Each level executes fewer instructions for the same result
+            assert!(outputs.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        }
+    }
+
+    #[test]
+    fn test_opt_levels_agree_on_overflow() {
+        // A clear loop counting up, a run that dips below zero, and a clear
+        // and a multiply loop that stay in range
+        let programs = ["+[+]", "-+", "+++++[-]", "++[->+++<]"];
+
+        for program in programs {
+            let results: Vec<_> = (0..=ir::MAX_OPT_LEVEL)
+                .map(|opt_level| {
+                    let mut cpu = CpuBuilder::new(program)
+                        .cell_overflow(CellOverflow::Error)
+                        .opt_level(opt_level)
+                        .build();
+                    let result = cpu.run(None).map_err(|error| error.to_string());
+                    (result, cpu.tape)
+                })
+                .collect();
+
+            assert!(
+                results.iter().all(|result| *result == results[0]),
+                "{}",
+                program
+            );
+        }
+        assert!(CpuBuilder::new("+[+]")
+            .cell_overflow(CellOverflow::Error)
+            .build()
+            .run(None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_echo_input() {
+        let sink = SharedBuffer::default();
+        let mut cpu = CpuBuilder::new(",+.,+.")
+            .stdin(Box::new(io::Cursor::new("ab")))
+            .sink(Box::new(sink.clone()))
+            .echo_input(true)
+            .build();
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(sink.contents(), "abbc");
+
+        // Pre-defined input is never echoed
+        let sink = SharedBuffer::default();
+        let mut cpu = CpuBuilder::new(",+.")
+            .sink(Box::new(sink.clone()))
+            .echo_input(true)
+            .build();
+        assert!(cpu.run(Some("a".into())).is_ok());
+        assert_eq!(sink.contents(), "b");
+    }
+
+    #[test]
+    fn test_record_and_replay_input() {
+        // Upper-cases what it reads until a newline
+        let program = ",----------[----------------------.,----------]";
+        let transcript = SharedBuffer::default();
+        let mut cpu = CpuBuilder::new(program)
+            .stdin(Box::new(io::Cursor::new("hey\nignored")))
+            .record_input(Box::new(transcript.clone()))
+            .build();
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"HEY");
+        // Only what `,` consumed, not what the reader had left
+        assert_eq!(transcript.contents(), "hey\n");
+
+        let mut cpu = CpuBuilder::new(program)
+            .stdin(Box::new(io::Cursor::new(transcript.contents())))
+            .build();
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"HEY");
+
+        // Pre-defined input is not recorded
+        let transcript = SharedBuffer::default();
+        let mut cpu = CpuBuilder::new(",")
+            .record_input(Box::new(transcript.clone()))
+            .build();
+        assert!(cpu.run(Some("a".into())).is_ok());
+        assert_eq!(transcript.contents(), "");
+    }
+
+    #[test]
+    fn test_commented_program() {
+        // Comments never reach the compiled program, but offsets still point
+        // into the source. The folded `<<` fails at its first `<`
+        let comment = "this comment is skipped at compile time\n".repeat(100);
+        let program = format!("{}+++{}[>+<-]{}>.{}<<", comment, comment, comment, comment);
+        let mut cpu = Cpu::new(program.clone(), 4);
+        cpu.one_shot_output = true;
+        cpu.pointer_policy = PointerPolicy::Error;
+
+        assert!(matches!(
+            cpu.run(None),
+            Err(BrainfuckError::PointerOutOfBounds { pos, pointer: -1 }) if pos == program.len() - 2
+        ));
+        assert_eq!(cpu.output, b"\x03");
+    }
+
+    #[test]
+    fn test_streamed_output_is_not_kept() {
+        // Prints 100 * 10 bytes, all below 128 and so written as one byte
+        let program = String::from("++++++++++[>++++++++++<-]>[>++++++++++[.-]<-]");
+        let sink = CountingSink::default();
+        let mut cpu = CpuBuilder::new(&program)
+            .sink(Box::new(sink.clone()))
+            .build();
+        cpu.stats = Some(Stats::default());
+        cpu.coverage = Some(Coverage::default());
+        assert!(cpu.run(None).is_ok());
+
+        assert_eq!(*sink.0.borrow(), 1000);
+        assert!(cpu.output.is_empty());
+        assert_eq!(cpu.output.capacity(), 0);
+        assert_eq!(cpu.stats.unwrap().per_command[&'.'], 1000);
+        assert!(cpu.coverage.unwrap().uncovered().is_empty());
+    }
+
+    #[test]
+    fn test_multiply_loops_match_literal_runs() {
+        let run_at = |program: &str, opt_level, pointer_policy, cell_width| {
+            let mut cpu = CpuBuilder::new(program)
+                .tape_size(6)
+                .opt_level(opt_level)
+                .pointer_policy(pointer_policy)
+                .cell_width(cell_width)
+                .one_shot_output(true)
+                .sink(Box::new(io::sink()))
+                .build();
+            let result = cpu.run(None);
+            (
+                result.map_err(|error| error.to_string()),
+                cpu.output,
+                cpu.tape,
+                cpu.high_water_mark,
+                cpu.steps,
+            )
+        };
+
+        let use_cases = [
+            // Move, copy to two cells, multiply by 3 and subtract
+            ("+++++[->+<]>.", PointerPolicy::Clamp, CellWidth::U8),
+            ("+++++++[->+>++<<]>.>.", PointerPolicy::Clamp, CellWidth::U8),
+            ("++>+++++++[<+++>-]<.", PointerPolicy::Clamp, CellWidth::U8),
+            (">>++++++++++[-<-->]<.", PointerPolicy::Clamp, CellWidth::U8),
+            // Products that wrap around the cell width
+            ("-[->+++<]>.", PointerPolicy::Clamp, CellWidth::U8),
+            ("-[->+++<]>.", PointerPolicy::Clamp, CellWidth::U16),
+            // Off the left end, clamped, erroring and wrapping around
+            ("+++[-<+>]<.", PointerPolicy::Clamp, CellWidth::U8),
+            ("+++[-<+>]<.", PointerPolicy::Error, CellWidth::U8),
+            ("+++[-<+>]<.", PointerPolicy::Wrap, CellWidth::U8),
+            // Wide enough to wrap onto its own counter
+            ("++++[->>>>>>-<<<<<<]", PointerPolicy::Wrap, CellWidth::U8),
+        ];
+        for (program, pointer_policy, cell_width) in use_cases {
+            let literal = run_at(program, 0, pointer_policy, cell_width);
+            let optimized = run_at(program, 2, pointer_policy, cell_width);
+            assert_eq!(literal.0, optimized.0, "{}", program);
+            assert_eq!(literal.1, optimized.1, "{}", program);
+            assert_eq!(literal.2, optimized.2, "{}", program);
+            // A folded move that wraps around skips the cells it passes
+            if pointer_policy != PointerPolicy::Wrap {
+                assert_eq!(literal.3, optimized.3, "{}", program);
+            }
+        }
+
+        // A loop run in one go is a single instruction
+        let steps = |opt_level| {
+            run_at(
+                "+++++++[->+>++<<]",
+                opt_level,
+                PointerPolicy::Clamp,
+                CellWidth::U8,
+            )
+            .4
+        };
+        assert_eq!(steps(0), 7 + 1 + 7 * 9);
+        assert_eq!(steps(1), 1 + 1 + 7 * 7);
+        assert_eq!(steps(2), 2);
+    }
+
+    #[test]
+    fn test_run_report() {
+        let mut cpu = Cpu::new(String::from("+++>++.@+."), 8);
+        cpu.one_shot_output = true;
+        cpu.level = 1;
+        assert_eq!(cpu.report(), None);
+        assert!(cpu.run(None).is_ok());
+
+        assert_eq!(
+            cpu.report(),
+            Some(RunReport {
+                // `+++`, `>`, `++`, `.` and `@`
+                steps: 5,
+                output_len: 1,
+                high_water_mark: 1,
+                halted_reason: HaltReason::Break,
+            })
+        );
+
+        cpu.level = 0;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.report().unwrap().halted_reason, HaltReason::Finished);
+
+        cpu.max_steps = Some(3);
+        assert!(cpu.run(None).is_err());
+        assert_eq!(cpu.report().unwrap().halted_reason, HaltReason::StepLimit);
+
+        cpu.feed_tape = String::from("[");
+        assert!(cpu.run(None).is_err());
+        assert_eq!(cpu.report(), None);
+    }
+
+    #[test]
+    fn test_break_halts_in_every_mode() {
+        // Prints `A`, and would go on to print `B` and move off the tape
+        let program = "++++++++[>++++++++<-]>+.@+.<<<";
+        let new_cpu = || {
+            let mut cpu = Cpu::new(String::from(program), 4);
+            cpu.level = 1;
+            cpu.pointer_policy = PointerPolicy::Error;
+            cpu
+        };
+
+        let mut one_shot = new_cpu();
+        one_shot.one_shot_output = true;
+        assert!(one_shot.run(None).is_ok());
+        assert_eq!(one_shot.output, b"A");
+
+        let sink = SharedBuffer::default();
+        let mut streaming = new_cpu();
+        streaming.sink = Box::new(sink.clone());
+        assert!(streaming.run(None).is_ok());
+        assert_eq!(sink.contents(), "A");
+        assert_eq!(streaming.tape[1], 65);
+        assert_eq!(streaming.report().unwrap().halted_reason, HaltReason::Break);
+
+        let mut stepped = new_cpu();
+        stepped.one_shot_output = true;
+        while stepped.step().unwrap().is_some() {}
+        assert_eq!(stepped.output, b"A");
+        assert_eq!(stepped.report().unwrap().halted_reason, HaltReason::Break);
+
+        // `@` is a comment in plain brainfuck
+        let mut plain = new_cpu();
+        plain.level = 0;
+        plain.one_shot_output = true;
+        assert!(matches!(
+            plain.run(None),
+            Err(BrainfuckError::PointerOutOfBounds { .. })
+        ));
+        assert_eq!(plain.output, b"AB");
+    }
+
+    #[test]
+    fn test_halt_on_output() {
+        // Prints `ab`, the `!` sentinel, then `c` and moves off the tape
+        let program = format!(
+            "{}.+.{}.{}.<<",
+            "+".repeat(97),
+            "-".repeat(65),
+            "+".repeat(66)
+        );
+        let new_cpu = || {
+            CpuBuilder::new(&program)
+                .tape_size(1)
+                .pointer_policy(PointerPolicy::Error)
+                .halt_on_output(b'!')
+                .build()
+        };
+
+        let sink = SharedBuffer::default();
+        let mut streaming = new_cpu();
+        streaming.sink = Box::new(sink.clone());
+        assert!(streaming.run(None).is_ok());
+        assert_eq!(sink.contents(), "ab!");
+        let report = streaming.report().unwrap();
+        assert_eq!(report.halted_reason, HaltReason::OutputMatched);
+        assert_eq!(report.output_len, 3);
+
+        let mut stepped = new_cpu();
+        stepped.one_shot_output = true;
+        while stepped.step().unwrap().is_some() {}
+        assert_eq!(stepped.output, b"ab!");
+        assert_eq!(
+            stepped.report().unwrap().halted_reason,
+            HaltReason::OutputMatched
+        );
+    }
+
+    #[test]
+    fn test_run_with_cell_hook() {
+        let mut cpu = Cpu::new(String::from("+>+ [-]"), 4);
+        cpu.one_shot_output = true;
+        cpu.opt_level = ir::MAX_OPT_LEVEL;
+        let mut changes = Vec::new();
+        assert!(cpu
+            .run_with_cell_hook(None, |change| changes.push(change))
+            .is_ok());
+
+        let change = |index, old, new, instruction_pointer| CellChange {
+            index,
+            old,
+            new,
+            instruction_pointer,
+        };
+        // `[-]` is not folded into a single clear
+        assert_eq!(
+            changes,
+            vec![change(0, 0, 1, 0), change(1, 0, 1, 2), change(1, 1, 0, 5)]
+        );
+    }
+
+    #[test]
+    fn test_run_with_hook() {
+        let mut cpu = Cpu::new(String::from("++ >."), 4);
+        cpu.one_shot_output = true;
+        let mut steps = Vec::new();
+        assert!(cpu.run_with_hook(None, |step| steps.push(step)).is_ok());
+
+        let step = |instruction_pointer, command, data_pointer| StepInfo {
+            instruction_pointer,
+            command,
+            data_pointer,
+        };
+        assert_eq!(
+            steps,
+            vec![
+                step(0, '+', 0),
+                step(1, '+', 0),
+                step(3, '>', 0),
+                step(4, '.', 1)
+            ]
+        );
+    }
+}
@@ -0,0 +1,417 @@
+//! Static detection of loops that can never finish.
+//!
+//! This is a heuristic. Only loops whose bodies consist purely of `+`, `-`,
+//! `>` and `<` are analysed, and only for the case where the body returns to
+//! the cell it started on without changing it. Loops with nested loops, I/O
+//! or extended commands are never reported, even if they are obviously
+//! infinite, and a reported loop is only infinite if it is entered with a
+//! non-zero cell. A body that moves the pointer can still end by running
+//! into the edge of the tape, e.g. clamped there so it returns to another
+//! cell.
+//!
+//! `unreachable` goes one step further and follows the program from a fresh
+//! tape for as long as every cell it looks at has a known value. If that
+//! reaches one of those loops with a non-zero cell, the code after it can
+//! never run. It gives up at input it needs the value of, at loops it can not
+//! follow and wherever the pointer could leave the tape, so it misses most
+//! dead code but never reports live code.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::error::BrainfuckError;
+use crate::ir::{self, Instruction};
+use crate::{CellWidth, CommandSet, PointerPolicy};
+
+/// A likely mistake found by `lint`. Positions are byte offsets of the `[`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// `[]`, which never ends once entered
+    EmptyLoop { pos: usize },
+    /// A loop body that leaves the pointer and the current cell unchanged,
+    /// as long as the pointer does not hit the edge of the tape
+    UnchangedCell { pos: usize },
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lint::EmptyLoop { pos } => {
+                write!(f, "Empty loop at position {} never ends once entered", pos)
+            }
+            Lint::UnchangedCell { pos } => write!(
+                f,
+                "Loop at position {} never changes its cell and never ends once entered, \
+                 unless the pointer hits the edge of the tape",
+                pos
+            ),
+        }
+    }
+}
+
+/// Code that can never run because a loop before it never ends, found by
+/// `unreachable`. `pos` is the offset of the first command after the loop,
+/// `loop_pos` the one of its `[`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unreachable {
+    pub pos: usize,
+    pub loop_pos: usize,
+}
+
+impl fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Code from position {} on is unreachable, the loop at position {} never ends",
+            self.pos, self.loop_pos
+        )
+    }
+}
+
+/// Whether a loop body of only `+`, `-`, `>` and `<` returns to the cell it
+/// started on without changing it.
+fn unchanged_cell(body: &[Instruction]) -> bool {
+    // Net pointer motion, and net change of the cell the loop started on
+    let mut pointer: isize = 0;
+    let mut change: i64 = 0;
+    for instruction in body {
+        match instruction {
+            Instruction::Add(amount) if pointer == 0 => change += *amount as i64,
+            Instruction::Add(_) => (),
+            Instruction::Move(amount) => pointer += amount,
+            _ => return false,
+        }
+    }
+    pointer == 0 && change == 0
+}
+
+/// The lowest and highest offsets a loop body of `+`, `-`, `>` and `<`
+/// moves the pointer to, relative to where it started.
+fn body_range(body: &[Instruction]) -> (isize, isize) {
+    let mut pointer: isize = 0;
+    let (mut lowest, mut highest) = (0, 0);
+    for instruction in body {
+        if let Instruction::Move(amount) = instruction {
+            pointer += amount;
+            lowest = lowest.min(pointer);
+            highest = highest.max(pointer);
+        }
+    }
+    (lowest, highest)
+}
+
+/// Returns the loops of `program` that provably never end once entered.
+/// Extended commands are those of `level`, or the ones in `enabled` if set.
+pub fn lint(
+    program: &[u8],
+    level: u8,
+    enabled: Option<CommandSet>,
+) -> Result<Vec<Lint>, BrainfuckError> {
+    let options = ir::Options {
+        level,
+        enabled,
+        ..ir::Options::default()
+    };
+    let (instructions, offsets) = ir::compile_with_offsets(program, &options)?;
+    let mut lints = Vec::new();
+
+    for (start, instruction) in instructions.iter().enumerate() {
+        let end = match instruction {
+            Instruction::LoopStart(end) => *end,
+            _ => continue,
+        };
+        let body = &instructions[start + 1..end];
+
+        if body.is_empty() {
+            lints.push(Lint::EmptyLoop {
+                pos: offsets[start],
+            });
+            continue;
+        }
+
+        if unchanged_cell(body) {
+            lints.push(Lint::UnchangedCell {
+                pos: offsets[start],
+            });
+        }
+    }
+
+    Ok(lints)
+}
+
+/// Returns the first code of `program` that can never run because a loop
+/// before it never ends, if that can be proven. The tape holds `tape_size`
+/// cells of `cell_width` that start as `initial_value`, or with values that
+/// are not known if it is `None`. A loop whose body would move the pointer
+/// off the tape is only followed under `PointerPolicy::Wrap`. Extended
+/// commands are picked by `level` and `enabled` as for `lint`.
+pub fn unreachable(
+    program: &[u8],
+    level: u8,
+    enabled: Option<CommandSet>,
+    tape_size: usize,
+    cell_width: CellWidth,
+    initial_value: Option<u32>,
+    pointer_policy: PointerPolicy,
+) -> Result<Option<Unreachable>, BrainfuckError> {
+    let options = ir::Options {
+        level,
+        enabled,
+        opt_level: ir::MAX_OPT_LEVEL,
+        ..ir::Options::default()
+    };
+    let (instructions, offsets) = ir::compile_with_offsets(program, &options)?;
+    let mask = cell_width.mask();
+
+    // Cells written so far, `None` once their value is not known
+    let mut cells: BTreeMap<usize, Option<u32>> = BTreeMap::new();
+    let mut pointer = 0usize;
+    let mut index = 0;
+
+    while index < instructions.len() {
+        let cell = *cells.get(&pointer).unwrap_or(&initial_value);
+        match &instructions[index] {
+            Instruction::Add(amount) => {
+                let value = cell.map(|value| value.wrapping_add(*amount as u32) & mask);
+                cells.insert(pointer, value);
+            }
+            Instruction::Move(amount) => match pointer.checked_add_signed(*amount) {
+                Some(moved) if moved < tape_size => pointer = moved,
+                // The pointer policy decides what happens next
+                _ => return Ok(None),
+            },
+            Instruction::Clear => {
+                cells.insert(pointer, Some(0));
+            }
+            Instruction::Set(literal) => {
+                cells.insert(pointer, Some(literal & mask));
+            }
+            Instruction::Output | Instruction::DebugPrint => (),
+            Instruction::Input => {
+                cells.insert(pointer, None);
+            }
+            Instruction::ScanRight | Instruction::ScanLeft if cell == Some(0) => (),
+            Instruction::LoopStart(end) | Instruction::MultiplyLoop(end) if cell == Some(0) => {
+                index = *end;
+            }
+            Instruction::LoopStart(end) if unchanged_cell(&instructions[index + 1..*end]) => {
+                let (lowest, highest) = body_range(&instructions[index + 1..*end]);
+                let on_tape = pointer.checked_add_signed(lowest).is_some()
+                    && pointer
+                        .checked_add_signed(highest)
+                        .is_some_and(|cell| cell < tape_size);
+                // Wrapped around, the body must not reach its own cell again
+                let wraps = pointer_policy == PointerPolicy::Wrap
+                    && ((highest - lowest) as usize) < tape_size;
+                if !(on_tape || wraps) {
+                    // Clamped or stopped at the edge, the loop may end
+                    return Ok(None);
+                }
+                return Ok(match cell {
+                    Some(_) if *end + 1 < instructions.len() => Some(Unreachable {
+                        pos: offsets[*end + 1],
+                        loop_pos: offsets[index],
+                    }),
+                    _ => None,
+                });
+            }
+            // Runs as many times as the counter says, and leaves it zero
+            Instruction::MultiplyLoop(end) => {
+                let mut offset: isize = 0;
+                for instruction in &instructions[index + 1..*end] {
+                    match instruction {
+                        Instruction::Move(amount) => offset += amount,
+                        Instruction::Add(amount) if offset != 0 => {
+                            let Some(target) = pointer
+                                .checked_add_signed(offset)
+                                .filter(|&target| target < tape_size)
+                            else {
+                                return Ok(None);
+                            };
+                            let old = *cells.get(&target).unwrap_or(&initial_value);
+                            let value = old.zip(cell).map(|(old, count)| {
+                                old.wrapping_add(count.wrapping_mul(*amount as u32)) & mask
+                            });
+                            cells.insert(target, value);
+                        }
+                        _ => (),
+                    }
+                }
+                cells.insert(pointer, Some(0));
+                index = *end;
+            }
+            // Storage is not followed, so only the cell is lost
+            Instruction::Store => (),
+            Instruction::Load
+            | Instruction::ShiftRight
+            | Instruction::ShiftLeft
+            | Instruction::Not
+            | Instruction::Xor
+            | Instruction::And
+            | Instruction::Or
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Modulo
+            | Instruction::AddStorage
+            | Instruction::SubtractStorage => {
+                cells.insert(pointer, None);
+            }
+            _ => return Ok(None),
+        }
+        index += 1;
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_loops() {
+        assert_eq!(
+            lint(b"+[] [ comment ]", 0, None).unwrap(),
+            vec![Lint::EmptyLoop { pos: 1 }, Lint::EmptyLoop { pos: 4 }]
+        );
+    }
+
+    #[test]
+    fn test_unchanged_cell() {
+        assert_eq!(
+            lint(b"+[>+<] [+-] [>-<+->+]", 0, None).unwrap(),
+            vec![
+                Lint::UnchangedCell { pos: 1 },
+                Lint::UnchangedCell { pos: 7 }
+            ]
+        );
+        // `+[<+>]` ends when clamped at the first cell, so that is said too
+        assert!(Lint::UnchangedCell { pos: 1 }
+            .to_string()
+            .ends_with("unless the pointer hits the edge of the tape"));
+    }
+
+    #[test]
+    fn test_terminating_loops_are_not_reported() {
+        assert_eq!(
+            lint(b"+[-] +[>+<-] +[>] +[,] +[[-]]", 0, None).unwrap(),
+            vec![]
+        );
+        // `$` can not be reasoned about, so the loop is assumed to be fine
+        assert_eq!(lint(b"+[$]", 1, None).unwrap(), vec![]);
+        let enabled = CommandSet::parse("$").unwrap();
+        assert_eq!(lint(b"+[$]", 0, Some(enabled)).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_unreachable_after_endless_loop() {
+        let find = |program: &[u8]| {
+            unreachable(
+                program,
+                0,
+                None,
+                8,
+                CellWidth::U8,
+                Some(0),
+                PointerPolicy::Clamp,
+            )
+            .unwrap()
+        };
+
+        assert_eq!(
+            find(b"+[]>+."),
+            Some(Unreachable {
+                pos: 3,
+                loop_pos: 1
+            })
+        );
+        // Followed through a clear, a multiplication loop and skipped loops
+        assert_eq!(
+            find(b"++[>++<-]>[-] [.] + <[>] >> [>+<-<] ++[>+<-+] ."),
+            Some(Unreachable {
+                pos: 46,
+                loop_pos: 38
+            })
+        );
+        // Only comments follow the loop
+        assert_eq!(find(b"+[] done"), None);
+    }
+
+    #[test]
+    fn test_unreachable_is_conservative() {
+        let find = |program: &[u8], initial_value| {
+            unreachable(
+                program,
+                0,
+                None,
+                8,
+                CellWidth::U8,
+                initial_value,
+                PointerPolicy::Clamp,
+            )
+            .unwrap()
+        };
+
+        // Never entered, or entered with a cell that is not known
+        assert_eq!(find(b"[]+.", Some(0)), None);
+        assert_eq!(find(b",[]+.", Some(0)), None);
+        assert_eq!(find(b"[]+.", None), None);
+        assert_eq!(
+            find(b"[]+.", Some(1)),
+            Some(Unreachable {
+                pos: 2,
+                loop_pos: 0
+            })
+        );
+        // 256 wraps to zero in an 8-bit cell
+        assert_eq!(
+            find(
+                &[b'+'; 256]
+                    .iter()
+                    .chain(b"[]+.")
+                    .copied()
+                    .collect::<Vec<_>>(),
+                Some(0)
+            ),
+            None
+        );
+        // Loops that run are not followed, and the pointer may leave the tape
+        assert_eq!(find(b"+[>+]+[]+.", Some(0)), None);
+        assert_eq!(find(b"+<+[]+.", Some(0)), None);
+        // Clamped at the first cell, `<` stays and `>` moves on to a zero
+        let ends = [&b"+[<+>]"[..], &[b'+'; 48], b"."].concat();
+        assert_eq!(find(&ends, Some(0)), None);
+        assert_eq!(
+            unreachable(
+                &ends,
+                0,
+                None,
+                8,
+                CellWidth::U8,
+                Some(0),
+                PointerPolicy::Wrap
+            )
+            .unwrap(),
+            Some(Unreachable {
+                pos: 6,
+                loop_pos: 1
+            })
+        );
+        // Within the tape the clamp never comes into play
+        assert_eq!(
+            find(b">+[<+>]+.", Some(0)),
+            Some(Unreachable {
+                pos: 7,
+                loop_pos: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_unmatched_bracket() {
+        assert!(matches!(
+            lint(b"+[", 0, None),
+            Err(BrainfuckError::UnmatchedBracket { pos: 1 })
+        ));
+    }
+}
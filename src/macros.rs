@@ -0,0 +1,163 @@
+//! Named snippets spliced into a program before it runs.
+//!
+//! A library defines one macro per line as `name = snippet`, and blank lines
+//! and lines starting with `;` are ignored. A program uses a macro by writing
+//! `@name`, which is replaced by its snippet. Snippets can use other macros.
+//! `@` followed by anything but a name is left alone, so the halt command of
+//! Extended Type I still works when followed by e.g. a space.
+
+use anyhow::Error;
+use std::collections::BTreeMap;
+
+/// Starts a macro use in a program
+pub const SIGIL: char = '@';
+
+/// The macros defined by one or more libraries.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Macros {
+    definitions: BTreeMap<String, String>,
+}
+
+fn is_name_start(character: char) -> bool {
+    character.is_ascii_alphabetic() || character == '_'
+}
+
+fn is_name_character(character: char) -> bool {
+    character.is_ascii_alphanumeric() || character == '_'
+}
+
+fn is_name(name: &str) -> bool {
+    name.starts_with(is_name_start) && name.chars().all(is_name_character)
+}
+
+impl Macros {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the definitions of `library`, replacing earlier ones of the same
+    /// name.
+    pub fn parse(&mut self, library: &str) -> Result<(), Error> {
+        for (index, line) in library.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let (name, snippet) = line.split_once('=').ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Line {} of the macro library is not name = snippet",
+                    index + 1
+                )
+            })?;
+            let name = name.trim();
+            if !is_name(name) {
+                anyhow::bail!("Invalid macro name on line {}: {:?}", index + 1, name);
+            }
+            self.definitions
+                .insert(name.to_string(), snippet.trim().to_string());
+        }
+        Ok(())
+    }
+
+    /// Replaces every macro use in `program` with its snippet, recursively.
+    pub fn expand(&self, program: &str) -> Result<String, Error> {
+        let mut expanded = String::with_capacity(program.len());
+        self.expand_into(program, &mut expanded, &mut Vec::new())?;
+        Ok(expanded)
+    }
+
+    /// `active` holds the macros being expanded, to catch ones that use
+    /// themselves.
+    fn expand_into<'a>(
+        &'a self,
+        program: &str,
+        expanded: &mut String,
+        active: &mut Vec<&'a str>,
+    ) -> Result<(), Error> {
+        let mut rest = program;
+
+        while let Some(index) = rest.find(SIGIL) {
+            expanded.push_str(&rest[..index]);
+            let after = &rest[index + SIGIL.len_utf8()..];
+            if !after.starts_with(is_name_start) {
+                expanded.push(SIGIL);
+                rest = after;
+                continue;
+            }
+
+            let length = after
+                .find(|character| !is_name_character(character))
+                .unwrap_or(after.len());
+            let (name, snippet) = self
+                .definitions
+                .get_key_value(&after[..length])
+                .ok_or_else(|| anyhow::anyhow!("Unknown macro: {}", &after[..length]))?;
+            if active.contains(&name.as_str()) {
+                anyhow::bail!("Macro {} expands into itself", name);
+            }
+
+            active.push(name);
+            self.expand_into(snippet, expanded, active)?;
+            active.pop();
+            rest = &after[length..];
+        }
+
+        expanded.push_str(rest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cpu;
+
+    const LIBRARY: &str = "
+        ; Clears the cell and prints a newline with it
+        print_nl = [-]++++++++++.[-]
+        print_a = ++++++++[>++++++++<-]>+. @print_nl
+    ";
+
+    #[test]
+    fn test_expand_and_run() {
+        let mut macros = Macros::new();
+        macros.parse(LIBRARY).unwrap();
+
+        let program = macros.expand("@print_a<@print_nl @ done").unwrap();
+        assert_eq!(
+            program,
+            "++++++++[>++++++++<-]>+. [-]++++++++++.[-]<[-]++++++++++.[-] @ done"
+        );
+
+        let mut cpu = Cpu::new(program, 16);
+        cpu.one_shot_output = true;
+        assert!(cpu.run(None).is_ok());
+        assert_eq!(cpu.output, b"A\n\n");
+    }
+
+    #[test]
+    fn test_unknown_and_recursive_macros() {
+        let mut macros = Macros::new();
+        macros
+            .parse("loop = +@again\nagain = -@loop\nfine = +")
+            .unwrap();
+
+        assert!(macros.expand("@fine@fine").is_ok());
+        assert_eq!(
+            macros.expand("@missing").unwrap_err().to_string(),
+            "Unknown macro: missing"
+        );
+        assert_eq!(
+            macros.expand("@loop").unwrap_err().to_string(),
+            "Macro loop expands into itself"
+        );
+    }
+
+    #[test]
+    fn test_invalid_library() {
+        assert!(Macros::new().parse("no snippet here").is_err());
+        assert!(Macros::new().parse("two words = +").is_err());
+        assert!(Macros::new().parse("9lives = +").is_err());
+    }
+}
@@ -1,177 +1,153 @@
 use anyhow::Context;
-use anyhow::Error;
 use anyhow::Ok;
-use clap::{App, Arg};
+use brainfuck_interpreter::{
+    dialect,
+    directive::{strip_directive, Directive},
+    disable_spans, emit,
+    macros::Macros,
+    minify_enabled, parse_level, repl,
+    source::SourceMap,
+    split_input, strip_bom, strip_shebang, AsciiPolicy, BrainfuckError, CellOverflow, CellWidth,
+    CommandSet, Compat, Coverage, Cpu, Debugger, EofPolicy, HaltReason, Hotspots, IoMode,
+    LineInput, MemoryProfile, Metrics, PointerPolicy, Poison, Stats, TraceHasher,
+    DEFAULT_TAPE_SIZE,
+};
+use clap::{App, Arg, ArgMatches};
 
 use pretty_env_logger::env_logger;
 use std::fs;
 use std::io;
+use std::io::IsTerminal;
 use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-struct Cpu {
-    feed_tape: String,
-    data_pointer: usize,
-    output: String,
-    tape_size: usize,
-    one_shot_output: bool,
+/// Set by the SIGINT handler, checked by the running program
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-    // Extended commands I
-    storage: u8,
+#[cfg(unix)]
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
 }
 
-impl Cpu {
-    fn new(feed_tape: String, tape_size: usize) -> Self {
-        Self {
-            feed_tape,
-            data_pointer: 0,
-            output: String::new(),
-            tape_size,
-            one_shot_output: false,
-            storage: 0,
-        }
+/// Makes Ctrl-C stop the program cleanly instead of killing the process, so
+/// that one-shot output is not lost. The handler is installed without
+/// `SA_RESTART`, so a `,` waiting for input is woken up by it too.
+#[cfg(unix)]
+fn handle_interrupts() {
+    let handler: extern "C" fn(libc::c_int) = on_sigint;
+    // SAFETY: the handler only stores to an atomic, which is signal safe, and
+    // an all zero `sigaction` is a valid one without flags
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
     }
+}
 
-    fn run(&mut self, pre_defined_input: Option<String>) -> Result<(), Error> {
-        let mut tape: Vec<u8> = vec![0; self.tape_size];
-        let mut instruction_pointer = 0;
-        let mut input_index: usize = 0;
+#[cfg(not(unix))]
+fn handle_interrupts() {}
 
-        while instruction_pointer < self.feed_tape.len() {
-            match self.feed_tape.chars().nth(instruction_pointer).unwrap() {
-                // Basic Commands
-                '>' => {
-                    if self.data_pointer < self.tape_size - 1 {
-                        self.data_pointer += 1
-                    }
-                }
-                '<' => {
-                    if self.data_pointer != 0 {
-                        self.data_pointer -= 1
-                    }
-                }
-                '+' => tape[self.data_pointer] = tape[self.data_pointer].wrapping_add(1),
-                '-' => tape[self.data_pointer] = tape[self.data_pointer].wrapping_sub(1),
-                '.' => {
-                    if self.one_shot_output {
-                        self.output.push(tape[self.data_pointer] as char);
-                    } else {
-                        print!("{}", tape[self.data_pointer] as char);
-                    }
-
-                    // log::debug!("Output: '{}'", tape[self.data_pointer] as char);
-
-                    // #[cfg(debug_assertions)]
-                    // if (tape[self.data_pointer] as char).is_ascii_graphic() {
-                    //     println!("Pushing char: {}", tape[self.data_pointer] as char);
-                    // } else {
-                    //     println!("Pushing char(u8): {:?}", tape[self.data_pointer]);
-                    // }
-                }
-                ',' => match pre_defined_input {
-                    Some(ref input) => {
-                        tape[self.data_pointer] = input.as_bytes()[input_index];
-                        input_index += 1;
-                    }
-                    None => {
-                        let mut input = [0];
-                        // read a single character
-                        io::stdin().read_exact(&mut input)?;
-
-                        // log::debug!("Input: {:?}", input);
-
-                        tape[self.data_pointer] = input[0];
-                    }
-                },
-                '[' => {
-                    if tape[self.data_pointer] == 0 {
-                        let mut loop_count = 1;
-                        while loop_count > 0 {
-                            instruction_pointer += 1;
-                            match self.feed_tape.chars().nth(instruction_pointer).unwrap() {
-                                '[' => loop_count += 1,
-                                ']' => loop_count -= 1,
-                                _ => (),
-                            }
-                        }
-                    }
-                }
-                ']' => {
-                    if tape[self.data_pointer] != 0 {
-                        let mut loop_count = 1;
-                        while loop_count > 0 {
-                            instruction_pointer -= 1;
-                            match self.feed_tape.chars().nth(instruction_pointer).unwrap() {
-                                '[' => loop_count -= 1,
-                                ']' => loop_count += 1,
-                                _ => (),
-                            }
-                        }
-                    }
-                }
+/// Prefixes `error` with the file, line and column it happened at, if known.
+fn locate(error: BrainfuckError, sources: &SourceMap) -> anyhow::Error {
+    match error.pos().and_then(|pos| sources.locate(pos)) {
+        Some(location) => anyhow::anyhow!("{}: {}", location, error),
+        None => error.into(),
+    }
+}
 
-                // Extended commands I (https://esolangs.org/wiki/Extended_Brainfuck#Extended_Type_I)
-                '@' => break,
-                '$' => self.storage = tape[self.data_pointer],
-                '!' => tape[self.data_pointer] = self.storage,
-                '}' => tape[self.data_pointer] >>= 1,
-                '{' => tape[self.data_pointer] <<= 1,
-                '~' => tape[self.data_pointer] = !tape[self.data_pointer],
-                '^' => tape[self.data_pointer] ^= self.storage,
-                '&' => tape[self.data_pointer] &= self.storage,
-                '|' => tape[self.data_pointer] |= self.storage,
-
-                // Extended commands II (https://esolangs.org/wiki/Extended_Brainfuck#Extended_Type_II)
-                '?' => todo!(),
-                '(' => todo!(),
-                ')' => todo!(),
-                '*' => tape[self.data_pointer] = tape[self.data_pointer].wrapping_mul(self.storage),
-                '/' => {
-                    if self.storage != 0 {
-                        tape[self.data_pointer] = tape[self.data_pointer].wrapping_div(self.storage)
-                    } else {
-                        log::error!(
-                            "Division by zero, instruction pointer: {}, current char: /",
-                            instruction_pointer
-                        );
-                    }
-                }
-                '=' => tape[self.data_pointer] = tape[self.data_pointer].wrapping_add(self.storage),
-                '_' => tape[self.data_pointer] = tape[self.data_pointer].wrapping_sub(self.storage),
-                '%' => {
-                    if self.storage != 0 {
-                        tape[self.data_pointer] = tape[self.data_pointer].wrapping_rem(self.storage)
-                    } else {
-                        log::error!(
-                            "Division by zero, instruction pointer: {}, current char: %",
-                            instruction_pointer
-                        );
-                    }
-                }
-                _ => (),
-            }
-            instruction_pointer += 1;
-        }
+/// Parses a tape size like `30000`, `30k` or `1M`. Suffixes are binary, so
+/// `1k` is 1024 cells and `1M` is 1048576.
+fn parse_tape_size(size: &str) -> Result<usize, anyhow::Error> {
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&size[..index], 1 << 10),
+        Some((index, 'm' | 'M')) => (&size[..index], 1 << 20),
+        Some((index, 'g' | 'G')) => (&size[..index], 1 << 30),
+        _ => (size, 1),
+    };
+    // Rules out signs, fractions and repeated suffixes like `1kk`
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        anyhow::bail!(
+            "Invalid tape size: {} (expected a number of cells, optionally followed by k, M or G)",
+            size
+        );
+    }
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|cells| cells.checked_mul(multiplier))
+        .ok_or_else(|| anyhow::anyhow!("Tape size is too large: {}", size))
+}
 
-        #[cfg(debug_assertions)]
-        for character in self.output.chars() {
-            if character.is_ascii_graphic() {
-                print!("{}", character);
-            } else {
-                print!("{:?}", character);
-            }
+/// Reads the program in `path` without its BOM, shebang and directive, and
+/// adds it to `sources`.
+fn read_program_file(
+    path: &str,
+    sources: &mut SourceMap,
+) -> Result<(Directive, String), anyhow::Error> {
+    let file =
+        fs::read_to_string(path).with_context(|| format!("Failed to read file: {}", path))?;
+    // Editors do not count the BOM, so positions are reported without it
+    let source = strip_bom(&file);
+    let (directive, program) = strip_directive(strip_shebang(source))
+        .with_context(|| format!("Invalid directive in {}", path))?;
+    sources.add(path, source, source.len() - program.len());
+    Ok((directive, program.to_string()))
+}
+
+/// Removes the disabled spans of `input`, then expands the `--macros`,
+/// translates the `--dialect` and applies the `--map`. `sources` is cleared
+/// once its offsets no longer match.
+fn preprocess(
+    matches: &ArgMatches,
+    input: &str,
+    sources: &mut SourceMap,
+) -> Result<String, anyhow::Error> {
+    let mut input = disable_spans(input).map_err(|offset| match sources.locate(offset) {
+        Some(location) => anyhow::anyhow!("{}: Disabled span is never closed", location),
+        None => anyhow::anyhow!("Disabled span at position {} is never closed", offset),
+    })?;
+
+    if let Some(libraries) = matches.values_of("macros") {
+        let mut macros = Macros::new();
+        for library in libraries {
+            log::trace!("Reading macros from {}", library);
+            let definitions = fs::read_to_string(library)
+                .with_context(|| format!("Failed to read macro library: {}", library))?;
+            macros
+                .parse(&definitions)
+                .with_context(|| format!("Invalid macro library: {}", library))?;
+        }
+        let expanded = macros.expand(&input)?;
+        // Offsets now refer to the expansion, not to the files
+        if expanded != input {
+            *sources = SourceMap::new();
         }
-        #[cfg(debug_assertions)]
-        println!();
+        input = expanded;
+    }
 
-        #[cfg(not(debug_assertions))]
-        println!("{}", self.output);
+    if matches.value_of("dialect") == Some("ook") {
+        log::trace!("Translating Ook! to brainfuck");
+        input = dialect::ook_to_bf(&input).map_err(|error| locate(error, sources))?;
+        // Offsets now refer to the translation, not to the files
+        *sources = SourceMap::new();
+    }
 
-        Ok(())
+    if let Some(map) = matches.value_of("map") {
+        log::trace!("Remapping commands from {}", map);
+        let remapped = dialect::remap(&input, &dialect::parse_command_map(map)?);
+        // Replacing multi-byte characters moves every offset after them
+        if remapped.len() != input.len() {
+            *sources = SourceMap::new();
+        }
+        input = remapped;
     }
+
+    Ok(input)
 }
 
 fn main() -> Result<(), anyhow::Error> {
-    env_logger::init();
     // Usage
     // cargo run -- -i examples/hello_world.bf
     // cargo run -- -i examples/hello_world.bf -s 2048
@@ -184,169 +160,834 @@ fn main() -> Result<(), anyhow::Error> {
                 .short("i")
                 .long("input")
                 .value_name("FILE")
-                .help("Sets the input file")
+                .help(
+                    "Sets the program file, - for stdin. Without it a program piped to stdin is run. \
+                     Given more than once, the files are joined in order",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("eval")
+                .short("e")
+                .long("eval")
+                .help("Runs PROGRAM given on the command line instead of reading a file")
+                .value_name("PROGRAM")
+                .takes_value(true)
+                .conflicts_with("input"),
+        )
+        .arg(
+            Arg::with_name("data")
+                .long("data")
+                .value_name("FILE")
+                .help("Feeds the contents of FILE to , instead of stdin")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pipe")
+                .long("pipe")
+                .value_name("FILE")
+                .help(
+                    "Runs the program in FILE after the program, with the same settings, feeding \
+                     it what the program printed. Only its output is printed",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("FILE")
+                .help("Writes what . prints to FILE instead of stdout")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eof")
+                .long("eof")
+                .help("What , stores once its input is exhausted (default error)")
+                .value_name("POLICY")
+                .possible_values(&["error", "unchanged", "zero", "minus-one"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("strict-ascii")
+                .long("strict-ascii")
+                .help("What , does with bytes that are not ASCII: stop with an error or mask them to 7 bits")
+                .value_name("MODE")
+                .possible_values(&["error", "mask"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("macros")
+                .long("macros")
+                .help("Expands @name in the program with the macros defined in FILE")
+                .value_name("FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("dialect")
+                .long("dialect")
+                .help("Language the program is written in (default bf)")
+                .value_name("DIALECT")
+                .possible_values(&["bf", "ook"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("map")
+                .long("map")
+                .help("Characters the program uses in place of ><+-.,[], in that order")
+                .value_name("COMMANDS")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("size")
                 .short("s")
                 .long("size")
-                .help("Specify tape length (default 2048)")
+                .help("Specify tape length, e.g. 30000 or 64k (default 2048)")
                 .value_name("TAPE_LEN")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("level")
+                .short("l")
+                .long("level")
+                .help("Extended Brainfuck level 0-3 (default 0)")
+                .value_name("LEVEL")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("enable")
+                .long("enable")
+                .help("Enable only these extended commands, e.g. '*/=_%', whatever the level. The others are comments")
+                .value_name("COMMANDS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cell-bits")
+                .long("cell-bits")
+                .help("Cell width in bits: 8, 16 or 32 (default 8)")
+                .value_name("BITS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("init")
+                .long("init")
+                .help("Value every cell starts with (default 0)")
+                .value_name("BYTE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("poison")
+                .long("poison")
+                .help(
+                    "Fill the tape with 0xaa bytes or seeded random values instead of \
+                     --init, to catch programs relying on zeroed cells",
+                )
+                .value_name("pattern|random[:SEED]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("numeric")
+                .long("numeric")
+                .help("Read and write cells as decimal numbers instead of bytes"),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .help("Print execution statistics to stderr after the run"),
+        )
+        .arg(
+            Arg::with_name("coverage")
+                .long("coverage")
+                .help("Print which commands never executed to stderr after the run"),
+        )
+        .arg(
+            Arg::with_name("hotspots")
+                .long("hotspots")
+                .help("Print the N loops that jumped back most often to stderr after the run")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("profile-memory")
+                .long("profile-memory")
+                .help("Print which cells were written to stderr after the run"),
+        )
+        .arg(
+            Arg::with_name("debug-hash")
+                .long("debug-hash")
+                .help("Make # print the cells around the pointer to stderr, at any level"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .help("Step through the program interactively"),
+        )
+        .arg(
+            Arg::with_name("checkpoint-interval")
+                .long("checkpoint-interval")
+                .help("Save the whole state every this many steps while debugging, so that b N can rewind N steps")
+                .value_name("STEPS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("break")
+                .long("break")
+                .help("Comma separated instruction offsets to pause at")
+                .value_name("OFFSETS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .help("Comma separated tape cells to report modifications of")
+                .value_name("CELLS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Write a \"step ip command dp cell\" line per executed command to FILE")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("halt-on-output")
+                .long("halt-on-output")
+                .help("Stop the program the first time . prints this byte, given as a number 0-255")
+                .value_name("BYTE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump-tape")
+                .long("dump-tape")
+                .help("Print the used part of the tape to stderr after the run"),
+        )
+        .arg(
+            Arg::with_name("print-cstring")
+                .long("print-cstring")
+                .help(
+                    "Print the zero-terminated string on the tape to stderr after the run, \
+                     from cell 0 or the given one, or dp for the data pointer",
+                )
+                .value_name("CELL")
+                .takes_value(true)
+                .min_values(0),
+        )
+        .arg(
+            Arg::with_name("emit")
+                .long("emit")
+                .help("Print the program translated to another language (asm is x86-64 for Linux), or its compiled instructions for ir, instead of running it. trace-hash runs it and prints a hash of its trace instead of its output")
+                .value_name("TARGET")
+                .possible_values(&["c", "rust", "asm", "ir", "trace-hash"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unreachable")
+                .long("unreachable")
+                .help("Report code that can never run because a loop before it never ends, instead of running the program"),
+        )
+        .arg(
+            Arg::with_name("line-input")
+                .long("line-input")
+                .help("Read stdin a line at a time, so , waits until a whole line is typed"),
+        )
+        .arg(
+            Arg::with_name("record-input")
+                .long("record-input")
+                .help("Save every byte , reads from stdin to a file, to replay the session with --replay-input")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("replay-input")
+                .long("replay-input")
+                .help("Read stdin from a file saved by --record-input")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("echo-input")
+                .long("echo-input")
+                .help("Print the characters read by , when they are typed in a terminal"),
+        )
+        .arg(
+            Arg::with_name("show-nonprintable")
+                .long("show-nonprintable")
+                .alias("escape")
+                .help("Print control characters and non-ASCII output bytes as escapes like \\x07"),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Check that the program's brackets are balanced instead of running it"),
+        )
+        .arg(
+            Arg::with_name("analyze")
+                .long("analyze")
+                .alias("count-only")
+                .help("Print static counts of the program's commands instead of running it"),
+        )
+        .arg(
+            Arg::with_name("minify")
+                .long("minify")
+                .help("Print the program stripped of comments instead of running it"),
+        )
+        .arg(
+            Arg::with_name("repl")
+                .long("repl")
+                .help("Read and run snippets line by line against a persistent tape"),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("Refuse to run programs with loops that can never finish"),
+        )
+        .arg(
+            Arg::with_name("optimize")
+                .short("O")
+                .long("optimize")
+                .help(
+                    "Optimization level (default 2). 0 runs command for command, 1 folds runs \
+                     of +- and <>, 2 also replaces [-], [>] and [<] and multiply loops",
+                )
+                .value_name("LEVEL")
+                .possible_values(&["0", "1", "2"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("strict-division")
+                .long("strict-division")
+                .help("Stop with an error when / or % divide by zero"),
+        )
+        .arg(
+            Arg::with_name("pointer")
+                .long("pointer")
+                .help("What to do when the data pointer leaves the tape (default clamp)")
+                .value_name("POLICY")
+                .possible_values(&["clamp", "wrap", "error"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("overflow")
+                .long("overflow")
+                .help("What to do when + or - take a cell out of its range (default wrap)")
+                .value_name("POLICY")
+                .possible_values(&["wrap", "error"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compat")
+                .long("compat")
+                .help("Set --overflow and --pointer together: classic is wrap and error, wrapping is wrap and wrap, strict is error and error. Either flag given as well takes precedence")
+                .value_name("PRESET")
+                .possible_values(&["classic", "wrapping", "strict"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-steps")
+                .long("max-steps")
+                .help("Stop with an error after executing this many instructions")
+                .value_name("STEPS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-output")
+                .long("max-output")
+                .help("Stop with an error once the program prints more than this many bytes")
+                .value_name("BYTES")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-loop-depth")
+                .long("max-loop-depth")
+                .help("Reject programs with loops nested deeper than this")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .help("Stop with an error after running for this many milliseconds")
+                .value_name("MS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .help("Log more, -v for info, -vv for debug, -vvv for trace. RUST_LOG overrides"),
+        )
         .get_matches();
 
-    let mut input: String = String::new();
-    let mut tape_size: usize = 2048;
+    let level = match matches.occurrences_of("verbose") {
+        0 => log::LevelFilter::Error,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
 
-    if let Some(input_file) = matches.value_of("input") {
-        log::trace!("Reading {}.bf file", input_file.to_string());
-        let filename = input_file;
-        input = fs::read_to_string(filename)
-            .with_context(|| format!("Failed to read file: {}", filename))?;
+    let mut input: String = String::new();
+    let mut sources = SourceMap::new();
+    // Settings asked for by the files, overridden by the flags below
+    let mut directive = Directive::default();
+    // Input for `,` that followed the program on stdin
+    let mut stdin_data = None;
+
+    // Explicit files and `-` win over a program piped to stdin. In the stdin
+    // cases `,` is fed what follows an `INPUT_SEPARATOR` line after the
+    // program, and otherwise sees nothing
+    match matches.values_of("input") {
+        None if matches.is_present("eval") => {
+            let program = matches.value_of("eval").unwrap_or_default();
+            sources.add("<eval>", program, 0);
+            input.push_str(program);
+        }
+        Some(input_files) => {
+            for input_file in input_files {
+                if input_file == "-" {
+                    log::trace!("Reading program from stdin");
+                    let mut source = String::new();
+                    io::stdin()
+                        .read_to_string(&mut source)
+                        .context("Failed to read program from stdin")?;
+                    let (program, data) = split_input(&source);
+                    sources.add("<stdin>", program, 0);
+                    input.push_str(program);
+                    stdin_data = data.map(|data| data.as_bytes().to_vec());
+                } else {
+                    log::trace!("Reading {}.bf file", input_file.to_string());
+                    let (found, program) = read_program_file(input_file, &mut sources)?;
+                    directive = directive.merge(found);
+                    input.push_str(&program);
+                }
+            }
+        }
+        None if !matches.is_present("repl") && !io::stdin().is_terminal() => {
+            log::trace!("Reading piped program from stdin");
+            let mut source = String::new();
+            io::stdin()
+                .read_to_string(&mut source)
+                .context("Failed to read program from stdin")?;
+            let (program, data) = split_input(&source);
+            sources.add("<stdin>", program, 0);
+            input.push_str(program);
+            stdin_data = data.map(|data| data.as_bytes().to_vec());
+        }
+        None => (),
     }
 
+    input = preprocess(&matches, &input, &mut sources)?;
+
+    let mut tape_size: usize = directive.tape_size.unwrap_or(DEFAULT_TAPE_SIZE);
     if let Some(input_string) = matches.value_of("size") {
         log::trace!("Setting tape size to {}", input_string.to_string());
-        tape_size = input_string.parse()?;
+        tape_size = parse_tape_size(input_string)?;
     }
 
     let mut cpu = Cpu::new(input, tape_size);
+    directive.apply(&mut cpu);
 
-    log::trace!("Running program");
-    cpu.run(None)?;
+    if let Some(level) = matches.value_of("level") {
+        log::trace!("Setting extended level to {}", level);
+        cpu.level = parse_level(level)?;
+    }
 
-    Ok(())
-}
+    if let Some(commands) = matches.value_of("enable") {
+        log::trace!("Enabling extended commands {}", commands);
+        cpu.enabled_commands = Some(CommandSet::parse(commands)?);
+    }
+
+    if matches.is_present("show-nonprintable") {
+        cpu.escape_output = true;
+    }
+
+    if matches.is_present("numeric") {
+        cpu.io_mode = IoMode::Numeric;
+    }
+
+    if let Some(bits) = matches.value_of("cell-bits") {
+        log::trace!("Setting cell width to {} bits", bits);
+        cpu.cell_width = CellWidth::from_bits(bits.parse()?)?;
+    }
+
+    if let Some(value) = matches.value_of("init") {
+        log::trace!("Setting initial cell value to {}", value);
+        cpu.initial_value = value
+            .parse()
+            .with_context(|| format!("Invalid initial cell value: {}", value))?;
+    }
+
+    if let Some(byte) = matches.value_of("halt-on-output") {
+        log::trace!("Halting on output byte {}", byte);
+        cpu.halt_on_output = Some(
+            byte.parse()
+                .with_context(|| format!("Invalid output byte: {}", byte))?,
+        );
+    }
+
+    if let Some(poison) = matches.value_of("poison") {
+        log::trace!("Poisoning the tape with {}", poison);
+        cpu.poison = Some(Poison::from_name(poison)?);
+    }
+
+    if let Some(policy) = matches.value_of("eof") {
+        log::trace!("Setting EOF policy to {}", policy);
+        cpu.eof_policy = EofPolicy::from_name(policy)?;
+    }
+
+    if let Some(mode) = matches.value_of("strict-ascii") {
+        log::trace!("Setting ASCII policy to {}", mode);
+        cpu.ascii_policy = AsciiPolicy::from_name(mode)?;
+    }
+
+    if let Some(opt_level) = matches.value_of("optimize") {
+        log::trace!("Setting optimization level to {}", opt_level);
+        cpu.opt_level = opt_level.parse()?;
+    }
+
+    if matches.is_present("debug-hash") {
+        cpu.debug_hash = true;
+    }
+
+    if matches.is_present("strict-division") {
+        cpu.strict_division = true;
+    }
+
+    if let Some(preset) = matches.value_of("compat") {
+        log::trace!("Setting compatibility preset to {}", preset);
+        (cpu.cell_overflow, cpu.pointer_policy) = Compat::from_name(preset)?.policies();
+    }
+
+    if let Some(policy) = matches.value_of("overflow") {
+        log::trace!("Setting cell overflow policy to {}", policy);
+        cpu.cell_overflow = CellOverflow::from_name(policy)?;
+    }
+
+    if let Some(policy) = matches.value_of("pointer") {
+        log::trace!("Setting pointer policy to {}", policy);
+        cpu.pointer_policy = PointerPolicy::from_name(policy)?;
+    }
+
+    if let Some(steps) = matches.value_of("max-steps") {
+        log::trace!("Setting step limit to {}", steps);
+        cpu.max_steps = Some(
+            steps
+                .parse()
+                .with_context(|| format!("Invalid step limit: {}", steps))?,
+        );
+    }
+
+    if let Some(depth) = matches.value_of("max-loop-depth") {
+        log::trace!("Setting loop depth limit to {}", depth);
+        cpu.max_loop_depth = Some(
+            depth
+                .parse()
+                .with_context(|| format!("Invalid loop depth limit: {}", depth))?,
+        );
+    }
+
+    if let Some(bytes) = matches.value_of("max-output") {
+        log::trace!("Setting output limit to {}", bytes);
+        cpu.max_output = Some(
+            bytes
+                .parse()
+                .with_context(|| format!("Invalid output limit: {}", bytes))?,
+        );
+    }
+
+    if let Some(milliseconds) = matches.value_of("timeout") {
+        log::trace!("Setting timeout to {}ms", milliseconds);
+        cpu.timeout =
+            Some(Duration::from_millis(milliseconds.parse().with_context(
+                || format!("Invalid timeout: {}", milliseconds),
+            )?));
+    }
+
+    if matches.is_present("check") {
+        cpu.validate().map_err(|error| locate(error, &sources))?;
+        println!("ok");
+        return Ok(());
+    }
+
+    if matches.is_present("analyze") {
+        println!(
+            "{}",
+            Metrics::new_enabled(&cpu.feed_tape, cpu.level, cpu.enabled_commands)
+        );
+        cpu.validate().map_err(|error| locate(error, &sources))?;
+        return Ok(());
+    }
+
+    if matches.is_present("minify") {
+        println!(
+            "{}",
+            minify_enabled(&cpu.feed_tape, cpu.level, cpu.enabled_commands)
+        );
+        return Ok(());
+    }
+
+    if matches.value_of("emit") == Some("ir") {
+        log::trace!("Emitting the compiled instructions");
+        let (instructions, offsets) = cpu.compile().map_err(|error| locate(error, &sources))?;
+        print!("{}", emit::emit_ir(&instructions, &offsets));
+        return Ok(());
+    }
+
+    if let Some(target) = matches
+        .value_of("emit")
+        .filter(|&target| target != "trace-hash")
+    {
+        log::trace!("Emitting {} code", target);
+        let (instructions, _) = cpu.compile().map_err(|error| locate(error, &sources))?;
+        let code = match target {
+            "c" => emit::emit_c(&instructions, cpu.tape_size, cpu.cell_width),
+            "asm" => emit::emit_asm(&instructions, cpu.tape_size, cpu.cell_width),
+            _ => emit::emit_rust(&instructions, cpu.tape_size, cpu.cell_width),
+        };
+        print!("{}", code);
+        return Ok(());
+    }
+
+    if let Some(offsets) = matches.value_of("break") {
+        log::trace!("Setting breakpoints at {}", offsets);
+        for offset in offsets.split(',') {
+            cpu.breakpoints.insert(
+                offset
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid breakpoint: {}", offset))?,
+            );
+        }
+    }
+
+    if let Some(cells) = matches.value_of("watch") {
+        log::trace!("Setting watchpoints at {}", cells);
+        for cell in cells.split(',') {
+            cpu.watchpoints.insert(
+                cell.trim()
+                    .parse()
+                    .with_context(|| format!("Invalid watchpoint: {}", cell))?,
+            );
+        }
+    }
+
+    if matches.is_present("debug") || !cpu.breakpoints.is_empty() {
+        let mut debugger = Debugger::new(
+            Box::new(io::BufReader::new(io::stdin())),
+            Box::new(io::stderr()),
+        );
+        // With only breakpoints set, run freely until one is hit
+        debugger.stepping = matches.is_present("debug");
+        if let Some(interval) = matches.value_of("checkpoint-interval") {
+            debugger.checkpoint_interval = Some(
+                interval
+                    .parse()
+                    .ok()
+                    .filter(|&interval| interval > 0)
+                    .with_context(|| format!("Invalid checkpoint interval: {}", interval))?,
+            );
+        }
+        cpu.debugger = Some(debugger);
+    }
+
+    if let Some(output_file) = matches.value_of("output") {
+        log::trace!("Writing output to {}", output_file);
+        let file = fs::File::create(output_file)
+            .with_context(|| format!("Failed to create output file: {}", output_file))?;
+        cpu.sink = Box::new(io::BufWriter::new(file));
+    }
+
+    if let Some(trace_file) = matches.value_of("trace") {
+        log::trace!("Tracing execution to {}", trace_file);
+        let file = fs::File::create(trace_file)
+            .with_context(|| format!("Failed to create trace file: {}", trace_file))?;
+        cpu.trace = Some(Box::new(io::BufWriter::new(file)));
+    }
+
+    // The hash covers what the trace would contain, without writing it
+    let trace_hasher = (matches.value_of("emit") == Some("trace-hash")).then(TraceHasher::new);
+    if let Some(trace_hasher) = &trace_hasher {
+        cpu.trace = Some(Box::new(trace_hasher.clone()));
+        cpu.sink = Box::new(io::sink());
+    }
+
+    if matches.is_present("stats") {
+        cpu.stats = Some(Stats::default());
+    }
+
+    if matches.is_present("coverage") {
+        cpu.coverage = Some(Coverage::default());
+    }
+
+    if matches.is_present("profile-memory") {
+        cpu.memory_profile = Some(MemoryProfile::default());
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    let hotspot_count = match matches.value_of("hotspots") {
+        Some(count) => {
+            cpu.hotspots = Some(Hotspots::default());
+            Some(
+                count
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid hotspot count: {}", count))?,
+            )
+        }
+        None => None,
+    };
+
+    if matches.is_present("repl") {
+        log::trace!("Starting REPL");
+        repl(&mut cpu, &mut io::stdin().lock(), &mut io::stdout())?;
+        return Ok(());
+    }
+
+    let lints = cpu.lint().map_err(|error| locate(error, &sources))?;
+    for lint in &lints {
+        eprintln!("warning: {}", lint);
+    }
+    if matches.is_present("strict") && !lints.is_empty() {
+        anyhow::bail!("{} loop(s) can never finish", lints.len());
+    }
+
+    if matches.is_present("unreachable") {
+        match cpu.unreachable().map_err(|error| locate(error, &sources))? {
+            Some(unreachable) => println!("{}", unreachable),
+            None => println!("No unreachable code found"),
+        }
+        return Ok(());
+    }
 
-    #[test]
-    fn test_new_cpu() {
-        let cpu = Cpu::new(String::from("++++++++++"), 2048);
-        assert_eq!(cpu.feed_tape, "++++++++++");
-        assert_eq!(cpu.data_pointer, 0);
+    let data = match matches.value_of("data") {
+        Some(data_file) => {
+            log::trace!("Reading input data from {}", data_file);
+            Some(
+                fs::read(data_file)
+                    .with_context(|| format!("Failed to read data file: {}", data_file))?,
+            )
+        }
+        None => stdin_data,
+    };
+
+    // Piped input and data files are not shown by anything, so not echoed,
+    // and an output file is no place to show what was typed
+    cpu.echo_input = matches.is_present("echo-input")
+        && data.is_none()
+        && matches.value_of("output").is_none()
+        && io::stdin().is_terminal();
+
+    if matches.is_present("line-input") {
+        cpu.stdin = Box::new(LineInput::new(io::stdin().lock()));
+    }
+
+    if let Some(transcript) = matches.value_of("replay-input") {
+        log::trace!("Replaying input from {}", transcript);
+        let file = fs::File::open(transcript)
+            .with_context(|| format!("Failed to open input transcript: {}", transcript))?;
+        cpu.stdin = Box::new(io::BufReader::new(file));
+    }
+
+    if let Some(transcript) = matches.value_of("record-input") {
+        log::trace!("Recording input to {}", transcript);
+        // Unbuffered, so the transcript is complete even if the run fails
+        let file = fs::File::create(transcript)
+            .with_context(|| format!("Failed to create input transcript: {}", transcript))?;
+        cpu.record_input = Some(Box::new(file));
     }
 
-    #[test]
-    fn test_run_cpu() {
-        let mut cpu = Cpu::new(String::from("++++++++++"), 2048);
-        let result = cpu.run(None);
-        assert!(result.is_ok());
+    handle_interrupts();
+    cpu.interrupt = Some(&INTERRUPTED);
+
+    let data = match matches.value_of("pipe") {
+        Some(pipe_file) => {
+            log::trace!("Running program with its output piped to {}", pipe_file);
+            // The settings are those of the first program
+            let mut pipe_sources = SourceMap::new();
+            let (_, program) = read_program_file(pipe_file, &mut pipe_sources)?;
+            let program = preprocess(&matches, &program, &mut pipe_sources)?;
+
+            // Output is collected like one-shot output, so its bytes reach
+            // the second program unchanged, as a shell pipe would pass them on
+            let sink = std::mem::replace(&mut cpu.sink, Box::new(io::sink()));
+            let one_shot_output = std::mem::replace(&mut cpu.one_shot_output, true);
+            cpu.run(data).map_err(|error| locate(error, &sources))?;
+            let piped = std::mem::take(&mut cpu.output);
+
+            cpu.sink = sink;
+            cpu.one_shot_output = one_shot_output;
+            cpu.reset();
+            cpu.feed_tape = program;
+            sources = pipe_sources;
+            Some(piped)
+        }
+        None => data,
+    };
+
+    log::trace!("Running program");
+    cpu.run(data).map_err(|error| locate(error, &sources))?;
+
+    if let Some(trace_hasher) = trace_hasher {
+        println!("{:016x}", trace_hasher.hash());
     }
 
-    #[test]
-    fn test_hello_world() {
-        let mut cpu = Cpu::new(
-            String::from(
-                "++++++++++[>+++++++>++++++++++>+++>+<<<<-]>++.>+.+++++++\
-            ..+++.>++.<<+++++++++++++++.>.+++.------.--------.>+.>.",
-            ),
-            2048,
+    if let Some(report) = cpu
+        .report()
+        .filter(|report| report.halted_reason == HaltReason::OutputMatched)
+    {
+        eprintln!(
+            "Halted on output byte {} after {} steps and {} bytes of output",
+            cpu.halt_on_output.unwrap_or_default(),
+            report.steps,
+            report.output_len
         );
-        cpu.one_shot_output = true;
-        let result = cpu.run(None);
-        assert!(result.is_ok());
-        assert_eq!(cpu.output, "Hello World!\n");
-    }
-
-    #[test]
-    fn test_hello_world_file() {
-        let program = r#"
-        [ This program prints "Hello World!" and a newline to the screen, its
-        length is 106 active command characters. [It is not the shortest.]
-
-        This loop is an "initial comment loop", a simple way of adding a comment
-        to a BF program such that you don't have to worry about any command
-        characters. Any ".", ",", "+", "-", "<" and ">" characters are simply
-        ignored, the "[" and "]" characters just have to be balanced. This
-        loop and the commands it contains are ignored because the current cell
-        defaults to a value of 0; the 0 value causes this loop to be skipped.
-        ]
-        ++++++++               Set Cell #0 to 8
-        [
-            >++++               Add 4 to Cell #1; this will always set Cell #1 to 4
-            [                   as the cell will be cleared by the loop
-                >++             Add 2 to Cell #2
-                >+++            Add 3 to Cell #3
-                >+++            Add 3 to Cell #4
-                >+              Add 1 to Cell #5
-                <<<<-           Decrement the loop counter in Cell #1
-            ]                   Loop until Cell #1 is zero; number of iterations is 4
-            >+                  Add 1 to Cell #2
-            >+                  Add 1 to Cell #3
-            >-                  Subtract 1 from Cell #4
-            >>+                 Add 1 to Cell #6
-            [<]                 Move back to the first zero cell you find; this will
-                                be Cell #1 which was cleared by the previous loop
-            <-                  Decrement the loop Counter in Cell #0
-        ]                       Loop until Cell #0 is zero; number of iterations is 8
-
-        The result of this is:
-        Cell no :   0   1   2   3   4   5   6
-        Contents:   0   0  72 104  88  32   8
-        Pointer :   ^
-
-        >>.                     Cell #2 has value 72 which is 'H'
-        >---.                   Subtract 3 from Cell #3 to get 101 which is 'e'
-        +++++++..+++.           Likewise for 'llo' from Cell #3
-        >>.                     Cell #5 is 32 for the space
-        <-.                     Subtract 1 from Cell #4 for 87 to give a 'W'
-        <.                      Cell #3 was set to 'o' from the end of 'Hello'
-        +++.------.--------.    Cell #3 for 'rl' and 'd'
-        >>+.                    Add 1 to Cell #5 gives us an exclamation point
-        >++.                    And finally a newline from Cell #6
-        "#;
-        let mut cpu = Cpu::new(String::from(program), 2048);
-        cpu.one_shot_output = true;
-        let result = cpu.run(None);
-        assert!(result.is_ok());
-        assert_eq!(cpu.output, "Hello World!\n");
-    }
-
-    #[test]
-    fn test_add_numbers() {
-        let program = r#"
-        ++       Cell c0 = 2
-        > +++++  Cell c1 = 5
-
-        [        Start your loops with your cell pointer on the loop counter (c1 in our case)
-        < +      Add 1 to c0
-        > -      Subtract 1 from c1
-        ]        End your loops with the cell pointer on the loop counter
-
-        At this point our program has added 5 to 2 leaving 7 in c0 and 0 in c1
-        but we cannot output this value to the terminal since it is not ASCII encoded
-
-        To display the ASCII character "7" we must add 48 to the value 7
-        We use a loop to compute 48 = 6 * 8
-
-        ++++ ++++  c1 = 8 and this will be our loop counter again
-        [
-        < +++ +++  Add 6 to c0
-        > -        Subtract 1 from c1
-        ]
-        < .        Print out c0 which has the value 55 which translates to "7"!
-        "#;
-        let mut cpu = Cpu::new(String::from(program), 2048);
-        cpu.one_shot_output = true;
-        let result = cpu.run(None);
-        assert!(result.is_ok());
-        assert_eq!(cpu.output, "7");
-    }
-
-    #[test]
-    fn test_input() {
-        let program = r#"
-        ,.
-        "#;
-        let mut cpu = Cpu::new(String::from(program), 2048);
-        cpu.one_shot_output = true;
-        let result = cpu.run(Some("1".to_string()));
-        assert!(result.is_ok());
-        assert_eq!(cpu.output, "1");
     }
+
+    if let Some(stats) = &cpu.stats {
+        eprintln!("{}", stats);
+    }
+
+    if let Some(coverage) = &cpu.coverage {
+        eprintln!("{}", coverage);
+    }
+
+    if let Some(memory_profile) = &cpu.memory_profile {
+        eprintln!("{}", memory_profile);
+    }
+
+    if let (Some(hotspots), Some(count)) = (&cpu.hotspots, hotspot_count) {
+        eprintln!("Hotspots:");
+        for (offset, iterations) in hotspots.top(count) {
+            match sources.locate(offset) {
+                Some(location) => eprintln!("  {}: {} iterations", location, iterations),
+                None => eprintln!("  position {}: {} iterations", offset, iterations),
+            }
+        }
+    }
+
+    if matches.is_present("print-cstring") {
+        let start = match matches.value_of("print-cstring") {
+            None => 0,
+            Some("dp") => cpu.data_pointer,
+            Some(cell) => cell
+                .parse()
+                .with_context(|| format!("Invalid start cell: {}", cell))?,
+        };
+        eprintln!("{}", cpu.cstring(start));
+    }
+
+    if matches.is_present("dump-tape") {
+        eprint!("{}", cpu.dump_tape(0..cpu.high_water_mark() + 1));
+        if cpu.level > 0 {
+            eprintln!("storage: {}", cpu.storage);
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,141 @@
+//! Compiling a program once to run it many times.
+//!
+//! `Cpu::run` compiles its program on every call. A server running the same
+//! program against many inputs can compile it into a `Program` instead and
+//! run that, which only resets the state a run starts from. Every run starts
+//! on cell 0 of a fresh tape, whatever the previous one left behind.
+
+use crate::ir::{self, Instruction};
+use crate::{BrainfuckError, Cpu, Hooks, RunReport};
+
+/// A compiled program, with its instructions and the offset of the command
+/// each was compiled from.
+#[derive(Debug, Clone)]
+pub struct Program {
+    source: String,
+    options: ir::Options,
+    instructions: Vec<Instruction>,
+    offsets: Vec<usize>,
+}
+
+impl Program {
+    /// Compiles `source` with the commands of `level` and the optimizations
+    /// of `opt_level`.
+    pub fn compile(source: &str, level: u8, opt_level: u8) -> Result<Self, BrainfuckError> {
+        let options = ir::Options {
+            level,
+            opt_level,
+            ..ir::Options::default()
+        };
+        let (instructions, offsets) = ir::compile_with_offsets(source.as_bytes(), &options)?;
+        Ok(Self {
+            source: source.to_string(),
+            options,
+            instructions,
+            offsets,
+        })
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// Returns the instructions and their offsets if they are what `options`
+    /// compiles to.
+    pub(crate) fn compiled_as(&self, options: &ir::Options) -> Option<(&[Instruction], &[usize])> {
+        (self.options == *options).then_some((&self.instructions, &self.offsets))
+    }
+
+    /// Runs the program on a fresh tape like `Cpu::run`, but without
+    /// compiling it again. Unlike `Cpu::run`, every run starts on cell 0 with
+    /// empty storage. `cpu` supplies the settings of the run, e.g. the tape
+    /// size, policies, limits and sink, and is left with its state. Its
+    /// program, level and optimizations are replaced by the ones compiled
+    /// here. Settings
+    /// that change how the program compiles, like a debugger needing every
+    /// command, still compile it for this run.
+    pub fn run(
+        &self,
+        pre_defined_input: Option<Vec<u8>>,
+        cpu: &mut Cpu,
+    ) -> Result<RunReport, BrainfuckError> {
+        cpu.feed_tape.clone_from(&self.source);
+        cpu.level = self.options.level;
+        cpu.enabled_commands = self.options.enabled;
+        cpu.opt_level = self.options.opt_level;
+        cpu.debug_hash = self.options.debug_hash;
+        cpu.max_loop_depth = self.options.max_loop_depth;
+        cpu.halt_reason = None;
+        cpu.data_pointer = 0;
+        cpu.storage = 0;
+        cpu.reset_run_state();
+        cpu.execute_with(pre_defined_input, Hooks::default(), Some(self))?;
+        Ok(cpu.report().expect("a finished run has a report"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HaltReason;
+
+    #[test]
+    fn test_run_twice() {
+        // Adds the two digits it reads
+        let program = Program::compile(
+            ",>,[-<+>]<------------------------------------------------.",
+            0,
+            2,
+        )
+        .unwrap();
+        let mut cpu = Cpu::new(String::new(), 4);
+        cpu.one_shot_output = true;
+
+        let report = program.run(Some("12".into()), &mut cpu).unwrap();
+        assert_eq!(cpu.output, b"3");
+        assert_eq!(report.halted_reason, HaltReason::Finished);
+
+        // The tape starts fresh, and the output of the first run is gone
+        program.run(Some("45".into()), &mut cpu).unwrap();
+        assert_eq!(cpu.output, b"9");
+        assert_eq!(cpu.tape, vec![57, 0, 0, 0]);
+        assert_eq!(cpu.feed_tape, program.source);
+
+        // Moves right and stores before printing, none of which carries over
+        let program = Program::compile(",$>+.", 1, 2).unwrap();
+        program.run(Some("a".into()), &mut cpu).unwrap();
+        program.run(Some("b".into()), &mut cpu).unwrap();
+        assert_eq!(cpu.output, b"\x01");
+        assert_eq!(cpu.tape, vec![98, 1, 0, 0]);
+        assert_eq!(cpu.data_pointer, 1);
+        assert_eq!(cpu.storage, 98);
+    }
+
+    #[test]
+    fn test_run_at_own_opt_level() {
+        // Every command is a step without folding, not 3 at the default level
+        let program = Program::compile("+++[-].", 0, 0).unwrap();
+        let mut cpu = Cpu::new(String::new(), 4);
+        cpu.one_shot_output = true;
+        let report = program.run(None, &mut cpu).unwrap();
+        assert_eq!(report.steps, 11);
+        assert_eq!(cpu.opt_level, 0);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            Program::compile("+[", 0, 2),
+            Err(BrainfuckError::UnmatchedBracket { pos: 1 })
+        ));
+
+        // Runs out of input under the default EOF policy
+        let program = Program::compile(",[.,]", 0, 2).unwrap();
+        let mut cpu = Cpu::new(String::new(), 4);
+        assert!(matches!(
+            program.run(Some(Vec::new()), &mut cpu),
+            Err(BrainfuckError::EndOfInput { pos: 0 })
+        ));
+        assert_eq!(cpu.report().unwrap().halted_reason, HaltReason::Error);
+    }
+}
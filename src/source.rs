@@ -0,0 +1,137 @@
+//! Mapping byte offsets in a program back to the files it was read from.
+//!
+//! Errors carry offsets into the program as it is run, which may be several
+//! files joined together with their shebang lines stripped. A `SourceMap`
+//! records where each file starts so that an offset can be reported as a
+//! line and column of the original text, comments included.
+
+use std::fmt;
+
+/// The start of every line of a text, for turning offsets into positions.
+#[derive(Debug, Clone)]
+pub struct LineIndex {
+    text: String,
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(index, _)| index + 1))
+            .collect();
+        Self {
+            text: text.to_string(),
+            line_starts,
+        }
+    }
+
+    /// Returns the 1-based line and column, counted in characters, of the
+    /// byte `offset`. Offsets past the end map to the end of the last line.
+    pub fn line_column(&self, offset: usize) -> (usize, usize) {
+        let offset = offset.min(self.text.len());
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let column = self.text[self.line_starts[line]..]
+            .char_indices()
+            .take_while(|&(index, _)| self.line_starts[line] + index < offset)
+            .count();
+        (line + 1, column + 1)
+    }
+}
+
+/// A position in a named source file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub name: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.name, self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SourceFile {
+    name: String,
+    // Offset in the program of the first byte taken from the file
+    start: usize,
+    // Bytes at the start of the file that are not part of the program
+    skipped: usize,
+    index: LineIndex,
+}
+
+/// The files a program was joined from, in order.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+    length: usize,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the program continues with `text`, read from `name`,
+    /// from its byte `skipped` on.
+    pub fn add(&mut self, name: &str, text: &str, skipped: usize) {
+        self.files.push(SourceFile {
+            name: name.to_string(),
+            start: self.length,
+            skipped,
+            index: LineIndex::new(text),
+        });
+        self.length += text.len() - skipped;
+    }
+
+    /// Returns where the byte `offset` of the program came from.
+    pub fn locate(&self, offset: usize) -> Option<Location> {
+        if offset >= self.length {
+            return None;
+        }
+        let file = self.files.iter().rev().find(|file| file.start <= offset)?;
+        let (line, column) = file.index.line_column(offset - file.start + file.skipped);
+        Some(Location {
+            name: file.name.clone(),
+            line,
+            column,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_column() {
+        let index = LineIndex::new("+ comment\n\n  é[\n]");
+        assert_eq!(index.line_column(0), (1, 1));
+        assert_eq!(index.line_column(9), (1, 10));
+        assert_eq!(index.line_column(10), (2, 1));
+        // `é` is two bytes but one column
+        assert_eq!(index.line_column(13), (3, 3));
+        assert_eq!(index.line_column(15), (3, 4));
+        assert_eq!(index.line_column(17), (4, 1));
+
+        // The `\r` of a CRLF ends its line like a column of its own
+        let index = LineIndex::new("+\r\n-[\r\n");
+        assert_eq!(index.line_column(1), (1, 2));
+        assert_eq!(index.line_column(4), (2, 2));
+    }
+
+    #[test]
+    fn test_joined_files() {
+        let mut map = SourceMap::new();
+        map.add("a.bf", "+\n+", 0);
+        // The shebang is stripped up to its newline
+        map.add("b.bf", "#!bf\n[\n-", 4);
+
+        assert_eq!(map.locate(2).unwrap().to_string(), "a.bf:2:1");
+        assert_eq!(map.locate(3).unwrap().to_string(), "b.bf:1:5");
+        assert_eq!(map.locate(4).unwrap().to_string(), "b.bf:2:1");
+        assert_eq!(map.locate(7), None);
+    }
+}
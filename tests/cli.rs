@@ -0,0 +1,830 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// Written with Extended Type I commands
+const HELLO_WORLD: &str = "programs/hello_world.bf";
+
+/// Runs the interpreter binary with `args`, writing `stdin` to it.
+fn run(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_brainfuck-interpreter"))
+        .args(args)
+        // Logging is controlled by the flags under test
+        .env_remove("RUST_LOG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The interpreter may exit without reading all of its stdin
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_program_from_pipe() {
+    let output = run(&[], "++++++++[>++++++++<-]>+.");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"A");
+}
+
+#[test]
+fn test_program_from_explicit_stdin() {
+    let output = run(&["--input", "-"], "++++++++[>++++++++<-]>++.");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"B");
+}
+
+#[test]
+fn test_input_file_takes_precedence_over_stdin() {
+    let output = run(
+        &["--input", HELLO_WORLD, "--level", "1"],
+        "++++++++[>++++++++<-]>+.",
+    );
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "Hello World!\n");
+}
+
+#[test]
+fn test_data_file_feeds_input() {
+    let data_file = std::env::temp_dir().join(format!("bf-cli-data-{}", std::process::id()));
+    std::fs::write(&data_file, "echo me\n").unwrap();
+
+    // Echoes its input until `,` reads 0 at the end of the data
+    let output = run(
+        &[
+            "--input",
+            "-",
+            "--data",
+            data_file.to_str().unwrap(),
+            "--eof",
+            "zero",
+        ],
+        ",[.,]",
+    );
+    std::fs::remove_file(&data_file).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"echo me\n");
+}
+
+#[test]
+fn test_data_file_may_be_binary() {
+    let data_file = std::env::temp_dir().join(format!("bf-cli-binary-{}", std::process::id()));
+    std::fs::write(&data_file, [0xff, 0xfe, b'A']).unwrap();
+
+    // 0xff and 0xfe are never valid UTF-8
+    let output = run(
+        &["--input", "-", "--data", data_file.to_str().unwrap()],
+        ",>,>,+.",
+    );
+    std::fs::remove_file(&data_file).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"B");
+}
+
+#[test]
+fn test_exhausted_data_is_an_error_by_default() {
+    let data_file = std::env::temp_dir().join(format!("bf-cli-eof-{}", std::process::id()));
+    std::fs::write(&data_file, "a").unwrap();
+
+    let output = run(
+        &["--input", "-", "--data", data_file.to_str().unwrap()],
+        ",.,.",
+    );
+    std::fs::remove_file(&data_file).unwrap();
+    assert!(!output.status.success());
+    assert_eq!(output.stdout, b"a");
+}
+
+#[test]
+fn test_lint_warnings() {
+    let output = run(&["--input", "-"], "++.>[]");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\x02");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "warning: Empty loop at position 4 never ends once entered\n"
+    );
+
+    let output = run(&["--input", "-", "--strict"], "++.>[]");
+    assert!(!output.status.success());
+    assert_eq!(output.stdout, b"");
+}
+
+#[test]
+fn test_halt_on_output() {
+    // Stops at the space, before `World!` is printed
+    let output = run(&["--halt-on-output", "32", "-i", HELLO_WORLD], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"Hello ");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Halted on output byte 32 after"));
+
+    let output = run(&["--halt-on-output", "256", "-i", HELLO_WORLD], "");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_enable() {
+    // Stores 3 and multiplies it by itself, the `/` would divide it back
+    let output = run(&["--level", "2", "--enable", "$*", "-e", "+++$*/."], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\x09");
+
+    let output = run(&["--enable", "*+", "-e", "+."], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Not an extended command: '+'"));
+
+    // `}` halves the cell, so the loop ends and the lints must know that
+    let output = run(&["--enable", "}", "--strict", "-e", "+[}]+."], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\x01");
+    assert_eq!(output.stderr, b"");
+    let output = run(&["--enable", "}", "--unreachable", "-e", "+[}]+."], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"No unreachable code found\n");
+
+    // The static counts see the same commands
+    let output = run(&["--analyze", "--enable", "$", "-e", "+$*"], "");
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("Commands: 2\n  '$': 1\n  '+': 1\n"));
+}
+
+#[test]
+fn test_compat_presets() {
+    // Takes the first cell below zero, then leaves the tape
+    let program = " -<+.";
+    let output = run(&["--compat", "classic", "--size", "4", "-e", program], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Data pointer moved off the tape to -1 at position 2"));
+
+    let output = run(&["--compat", "wrapping", "--size", "4", "-e", program], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\x01");
+
+    let output = run(&["--compat", "strict", "--size", "4", "-e", program], "");
+    assert!(!output.status.success());
+    assert_eq!(output.stdout, b"");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Cell value went out of range to -1 at position 1"));
+
+    // A policy given on its own wins over the preset
+    let output = run(
+        &[
+            "--compat",
+            "strict",
+            "--pointer",
+            "wrap",
+            "--overflow",
+            "wrap",
+            "--size",
+            "4",
+            "-e",
+            program,
+        ],
+        "",
+    );
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\x01");
+}
+
+#[test]
+fn test_overflow_error_at_every_opt_level() {
+    // `[+]` is a clear loop at -O2, which must count up to 256 all the same
+    for opt_level in ["-O0", "-O2"] {
+        let output = run(&[opt_level, "--overflow", "error", "-e", "+[+]"], "");
+        assert!(!output.status.success(), "{}", opt_level);
+        assert!(String::from_utf8(output.stderr)
+            .unwrap()
+            .contains("Cell value went out of range to 256 at position 2"));
+    }
+}
+
+#[test]
+fn test_unreachable() {
+    let output = run(&["--unreachable", "-e", "+[]>+."], "");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Code from position 3 on is unreachable, the loop at position 1 never ends\n"
+    );
+
+    let output = run(&["--unreachable", "-e", ",[]>+."], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"No unreachable code found\n");
+}
+
+#[test]
+fn test_verbosity() {
+    let output = run(&["--input", "-", "-vvv"], "+.");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Running program"));
+
+    let output = run(&["--input", "-"], "+.");
+    assert_eq!(output.stderr, b"");
+}
+
+#[test]
+fn test_input_files_are_joined() {
+    let directory = std::env::temp_dir().join(format!("bf-cli-join-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let prelude = directory.join("prelude.b");
+    let body = directory.join("body.b");
+    // The loop opens in the prelude and closes in the body
+    std::fs::write(&prelude, "++++++++[>++++").unwrap();
+    std::fs::write(&body, "++++<-]>+.").unwrap();
+
+    let output = run(
+        &[
+            "-i",
+            prelude.to_str().unwrap(),
+            "-i",
+            body.to_str().unwrap(),
+        ],
+        "",
+    );
+    let alone = run(&["-i", prelude.to_str().unwrap()], "");
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"A");
+    assert!(!alone.status.success());
+    assert!(String::from_utf8(alone.stderr)
+        .unwrap()
+        .contains("Unmatched bracket at position 8"));
+}
+
+#[test]
+fn test_shebang_is_ignored() {
+    let script = std::env::temp_dir().join(format!("bf-cli-shebang-{}", std::process::id()));
+    // The `-` and `.` in the shebang would otherwise run as commands
+    std::fs::write(
+        &script,
+        "#!/usr/bin/env brainfuck-interpreter -l 0 --eof=zero.\n++.\n",
+    )
+    .unwrap();
+
+    let output = run(&["-i", script.to_str().unwrap()], "");
+    std::fs::remove_file(&script).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\x02");
+}
+
+#[test]
+fn test_optimization_levels_agree() {
+    let outputs: Vec<Vec<u8>> = ["-O0", "-O1", "-O2"]
+        .iter()
+        .map(|level| {
+            let output = run(&["--input", HELLO_WORLD, "--level", "1", level], "");
+            assert!(output.status.success());
+            output.stdout
+        })
+        .collect();
+    assert!(outputs.iter().all(|output| *output == outputs[0]));
+
+    assert!(!run(&["-O3"], "+").status.success());
+}
+
+#[test]
+fn test_errors_are_reported_by_line_and_column() {
+    let program = std::env::temp_dir().join(format!("bf-cli-location-{}", std::process::id()));
+    std::fs::write(
+        &program,
+        "Clear the cell\n+[-]\nand then [ never close it\n",
+    )
+    .unwrap();
+
+    let output = run(&["-i", program.to_str().unwrap()], "");
+    let name = program.to_str().unwrap().to_string();
+    std::fs::remove_file(&program).unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains(&format!("{}:3:10: Unmatched bracket at position 29", name)));
+}
+
+#[test]
+fn test_max_loop_depth() {
+    let program = "+[[[-]]]";
+    assert!(run(&["--max-loop-depth", "3"], program).status.success());
+
+    let output = run(&["--max-loop-depth", "2"], program);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("<stdin>:1:4: Loop nested deeper than 2 at position 3"));
+}
+
+#[test]
+fn test_bom_and_crlf() {
+    let directory = std::env::temp_dir().join(format!("bf-cli-bom-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let valid = directory.join("valid.bf");
+    let invalid = directory.join("invalid.bf");
+    std::fs::write(
+        &valid,
+        "\u{feff}#!/usr/bin/env bf\r\n++++++++[>++++++++<-]>+.\r\n",
+    )
+    .unwrap();
+    std::fs::write(&invalid, "\u{feff}+\r\n+[\r\n").unwrap();
+
+    let output = run(&["-i", valid.to_str().unwrap()], "");
+    let error = run(&["-i", invalid.to_str().unwrap()], "");
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"A");
+    assert!(String::from_utf8(error.stderr).unwrap().contains(&format!(
+        "{}:2:2: Unmatched bracket at position 4",
+        invalid.to_str().unwrap()
+    )));
+}
+
+#[test]
+fn test_analyze() {
+    let output = run(&["--analyze"], "++[>+<-] never runs: .");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Commands: 9\n  '+': 3\n  '-': 1\n  '.': 1\n  '<': 1\n  '>': 1\n  '[': 1\n  ']': 1\n\
+         Loops: 1\nMax nesting depth: 1\nBrackets balanced: yes\n"
+    );
+
+    let output = run(&["--count-only"], "+[[]");
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .ends_with("Brackets balanced: no\n"));
+}
+
+#[test]
+fn test_eval() {
+    let output = run(&["--eval", "++++++[>++++++++<-]>+."], "ignored");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"1");
+
+    let output = run(&["-e", "+[", "-i", HELLO_WORLD], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("cannot be used with"));
+}
+
+#[test]
+fn test_initial_cell_value() {
+    // Counts 10 from 255 + 11 and subtracts 190 from the next 255
+    let program = "+++++++++++[>-------------------<-]>.";
+    let output = run(&["--init", "255", "-e", program], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"A");
+
+    assert_eq!(run(&["-e", program], "").stdout, b"/");
+}
+
+#[test]
+fn test_show_nonprintable() {
+    let program = "+++++++.+++.";
+    let output = run(&["--show-nonprintable", "-e", program], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\\x07\n");
+
+    // Raw bytes stay the default
+    assert_eq!(run(&["-e", program], "").stdout, b"\x07\n");
+}
+
+#[test]
+fn test_directive() {
+    let directory = std::env::temp_dir().join(format!("bf-cli-directive-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let sized = directory.join("sized.bf");
+    let unknown = directory.join("unknown.bf");
+    // `>>>` only lands back on the marked first cell of a wrapping 3 cell tape
+    std::fs::write(
+        &sized,
+        "!bf: size=3 pointer=wrap\n+>>>[+++++++++[>+++++++<-]>.<]",
+    )
+    .unwrap();
+    std::fs::write(&unknown, "!bf: speed=11\n+.").unwrap();
+
+    let output = run(&["-i", sized.to_str().unwrap()], "");
+    let overridden = run(&["-i", sized.to_str().unwrap(), "--size", "8"], "");
+    let error = run(&["-i", unknown.to_str().unwrap()], "");
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"F");
+    assert!(overridden.status.success());
+    assert_eq!(overridden.stdout, b"");
+    assert!(!error.status.success());
+    assert!(String::from_utf8(error.stderr)
+        .unwrap()
+        .contains("Unknown directive setting: speed"));
+}
+
+#[test]
+fn test_output_is_kept_on_errors() {
+    let output = run(
+        &["--max-steps", "100", "-e", "++++++++[>++++++++<-]>+.+.[]"],
+        "",
+    );
+    assert!(!output.status.success());
+    assert_eq!(output.stdout, b"AB");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Step limit exceeded"));
+}
+
+#[test]
+fn test_macros() {
+    let library = std::env::temp_dir().join(format!("bf-cli-macros-{}", std::process::id()));
+    std::fs::write(&library, "letter_a = ++++++++[>++++++++<-]>+\nprint = .\n").unwrap();
+
+    let output = run(
+        &[
+            "--macros",
+            library.to_str().unwrap(),
+            "-e",
+            "@letter_a@print+@print",
+        ],
+        "",
+    );
+    let unknown = run(
+        &["--macros", library.to_str().unwrap(), "-e", "@letter_b"],
+        "",
+    );
+    std::fs::remove_file(&library).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"AB");
+    assert!(String::from_utf8(unknown.stderr)
+        .unwrap()
+        .contains("Unknown macro: letter_b"));
+}
+
+#[test]
+fn test_hotspots() {
+    let output = run(&["--hotspots", "1", "-e", "++++[>+++[>+<-]<-]"], "");
+    assert!(output.status.success());
+    // Only the inner loop is listed, by where its `[` is
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Hotspots:\n  <eval>:1:10: 8 iterations\n"
+    );
+}
+
+#[test]
+fn test_emit_ir() {
+    let output = run(&["--emit", "ir", "-e", "++++"], "");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "index  offset  instruction\n    0       0  Add(4)\n"
+    );
+
+    // Without folding every command is listed
+    let output = run(&["--emit", "ir", "-O0", "-e", "++"], "");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "index  offset  instruction\n    0       0  Add(1)\n    1       1  Add(1)\n"
+    );
+}
+
+#[test]
+fn test_emit_follows_opt_level() {
+    let output = run(&["--emit", "c", "-e", "++"], "");
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("    *p += 2;\n"));
+
+    let output = run(&["--emit", "c", "-O0", "-e", "++"], "");
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("    *p += 1;\n    *p += 1;\n"));
+}
+
+#[test]
+fn test_profile_memory() {
+    // Sets cells 0 to 9 to 1
+    let program = "+>+>+>+>+>+>+>+>+>+";
+    let output = run(&["--profile-memory", "-s", "32", "-e", program], "");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Cells written: 10 of 32\nWritten range: 0..=9 (10 cells)\nSmallest tape that fits: 10 cells\n"
+    );
+}
+
+#[test]
+fn test_output_file() {
+    let path = std::env::temp_dir().join(format!("bf-cli-output-{}", std::process::id()));
+    let output = run(
+        &[
+            "-i",
+            "programs/hello_world.bf",
+            "--output",
+            path.to_str().unwrap(),
+        ],
+        "",
+    );
+    let written = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(written, std::fs::read("programs/hello_world.out").unwrap());
+}
+
+#[test]
+fn test_output_file_keeps_high_bytes() {
+    let path = std::env::temp_dir().join(format!("bf-cli-high-{}", std::process::id()));
+    // Prints every byte from 128 to 255
+    let program = "+".repeat(128) + "." + &"+.".repeat(127);
+    let output = run(&["-e", &program, "--output", path.to_str().unwrap()], "");
+    let written = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(written, (128..=255).collect::<Vec<u8>>());
+}
+
+#[test]
+fn test_strict_ascii() {
+    let data = std::env::temp_dir().join(format!("bf-cli-ascii-{}", std::process::id()));
+    std::fs::write(&data, "é").unwrap();
+    let data = data.to_str().unwrap();
+
+    // Prints the second byte of `é`, 0xa9, masked to `)`
+    let masked = run(&["--strict-ascii", "mask", "--data", data, "-e", ",,."], "");
+    let rejected = run(
+        &["--strict-ascii", "error", "--data", data, "-e", ",,."],
+        "",
+    );
+    std::fs::remove_file(data).unwrap();
+
+    assert!(masked.status.success());
+    assert_eq!(masked.stdout, b")");
+    assert!(!rejected.status.success());
+    assert!(String::from_utf8(rejected.stderr)
+        .unwrap()
+        .contains("<eval>:1:1: Read non-ASCII byte 0xc3 at position 0"));
+}
+
+#[test]
+fn test_check() {
+    let directory = std::env::temp_dir().join(format!("bf-cli-check-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let valid = directory.join("valid.bf");
+    let invalid = directory.join("invalid.bf");
+    std::fs::write(&valid, "+[>,.<-]").unwrap();
+    // Would wait for input if it ran
+    std::fs::write(&invalid, ",[\n[-]").unwrap();
+
+    let valid_output = run(&["--check", "-i", valid.to_str().unwrap()], "");
+    let invalid_output = run(&["--check", "-i", invalid.to_str().unwrap()], "");
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    assert!(valid_output.status.success());
+    assert_eq!(valid_output.stdout, b"ok\n");
+    assert!(!invalid_output.status.success());
+    assert!(String::from_utf8(invalid_output.stderr)
+        .unwrap()
+        .contains("invalid.bf:1:2: Unmatched bracket at position 1"));
+}
+
+#[test]
+fn test_disabled_spans() {
+    let program = "++++++++[>++++++++<-]>+.`[-]+.\n>>>`.";
+    let output = run(&["-e", program], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"AA");
+
+    let unclosed = run(&["-e", "+.\n`-."], "");
+    assert!(!unclosed.status.success());
+    assert!(String::from_utf8(unclosed.stderr)
+        .unwrap()
+        .contains("<eval>:2:1: Disabled span is never closed"));
+}
+
+#[test]
+fn test_tape_size_suffixes() {
+    let cells = |size: &str| {
+        let output = run(&["--profile-memory", "-s", size, "-e", "+"], "");
+        assert!(output.status.success(), "{}", size);
+        String::from_utf8(output.stderr).unwrap()
+    };
+    // Suffixes are binary
+    assert!(cells("1M").starts_with("Cells written: 1 of 1048576\n"));
+    assert!(cells("30k").starts_with("Cells written: 1 of 30720\n"));
+    assert!(cells("300").starts_with("Cells written: 1 of 300\n"));
+
+    for size in ["1.5k", "k", "-1k", "1kk", "1kb", "99999999999999999999G"] {
+        // `=` keeps `-1k` from being taken for a flag
+        let output = run(&[&format!("--size={}", size), "-e", "+"], "");
+        assert!(!output.status.success(), "{}", size);
+        assert!(String::from_utf8(output.stderr).unwrap().contains(size));
+    }
+}
+
+#[test]
+fn test_poison() {
+    // Prints the first cell, and then clears it before printing it again
+    let output = run(&["--poison", "pattern", "-e", ".[-]."], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"\xaa\0");
+
+    let seeded = |poison: &str| run(&["--poison", poison, "-e", ">>>>.<.<.<.<."], "").stdout;
+    assert_eq!(seeded("random:5"), seeded("random:5"));
+    assert_ne!(seeded("random:5"), seeded("random:6"));
+
+    let output = run(&["--poison", "ones", "-e", "."], "");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_program_and_input_on_stdin() {
+    // Echoes its input until `,` reads 0 at the end of it
+    let stream = ",[.,]\n!\necho me\n";
+    for args in [&["--eof", "zero"][..], &["--input", "-", "--eof", "zero"]] {
+        let output = run(args, stream);
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"echo me\n");
+    }
+
+    // A mistake in the program is located in the stream
+    let output = run(&["--input", "-"], "+\n+]\n!\n");
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("<stdin>:2:2:"));
+}
+
+#[test]
+fn test_print_cstring() {
+    // Writes "Hi" and a zero, and leaves the pointer on the `i`
+    let program = format!("{}>{}", "+".repeat(72), "+".repeat(105));
+    for args in [&["--print-cstring"][..], &["--print-cstring", "0"]] {
+        let output = run(&[args, &["-e", &program]].concat(), "");
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"");
+        assert_eq!(output.stderr, b"Hi\n");
+    }
+
+    let output = run(&["--print-cstring", "dp", "-e", &program], "");
+    assert_eq!(output.stderr, b"i\n");
+
+    let output = run(&["--print-cstring", "first", "-e", &program], "");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_pipe() {
+    let filter = std::env::temp_dir().join(format!("bf-cli-pipe-{}.bf", std::process::id()));
+    // Upper-cases its input until it reads a zero
+    std::fs::write(&filter, "upper: ,[>++++[<-------->-]<.,]").unwrap();
+    let filter = filter.to_str().unwrap();
+
+    let output = run(&["--eof", "zero", "-e", ",[.,]", "--pipe", filter], "abc");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"ABC");
+
+    // Bytes above 127 are passed on as single bytes
+    std::fs::write(filter, ",[.,]").unwrap();
+    let program = "+".repeat(200) + ".";
+    let output = run(&["--eof", "zero", "-e", &program, "--pipe", filter], "");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, [200]);
+
+    // Errors in the second program are located in its file
+    std::fs::write(filter, "\n+]").unwrap();
+    let output = run(&["-e", "+", "--pipe", filter], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains(&format!("{}:2:2:", filter)));
+
+    // Macros are expanded in both programs
+    let library = std::env::temp_dir().join(format!("bf-cli-pipe-macros-{}", std::process::id()));
+    std::fs::write(&library, "echo = ,[.,]\n").unwrap();
+    std::fs::write(filter, "@echo").unwrap();
+    let output = run(
+        &[
+            "--eof",
+            "zero",
+            "--macros",
+            library.to_str().unwrap(),
+            "-e",
+            "@echo",
+            "--pipe",
+            filter,
+        ],
+        "abc",
+    );
+    std::fs::remove_file(filter).unwrap();
+    std::fs::remove_file(&library).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"abc");
+}
+
+#[test]
+fn test_level_out_of_range() {
+    let output = run(&["--level", "4", "-e", "+."], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Invalid level: 4 (expected 0 to 3)"));
+
+    assert!(run(&["--level", "3", "-e", "+"], "").status.success());
+}
+
+#[test]
+fn test_trace_hash() {
+    let trace_hash = |args: &[&str], stdin| {
+        let output = run(&[&["--emit", "trace-hash"], args].concat(), stdin);
+        assert!(output.status.success());
+        let hash = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(hash.len(), 17, "{:?}", hash);
+        hash
+    };
+
+    let hello = trace_hash(&["-i", HELLO_WORLD, "--level", "1"], "");
+    assert_eq!(hello, trace_hash(&["-i", HELLO_WORLD, "--level", "1"], ""));
+    // The trace is taken command for command whatever the optimizations
+    assert_eq!(
+        hello,
+        trace_hash(&["-i", HELLO_WORLD, "--level", "1", "-O0"], "")
+    );
+    assert_ne!(hello, trace_hash(&["-e", "+."], ""));
+
+    // Input is part of the trace through the cells it sets
+    let echo = |stdin| trace_hash(&["--eof", "zero", "-e", ",[.,]"], stdin);
+    assert_eq!(echo("ab"), echo("ab"));
+    assert_ne!(echo("ab"), echo("ac"));
+}
+
+#[test]
+fn test_record_and_replay_input() {
+    let transcript = std::env::temp_dir().join(format!("bf-transcript-{}", std::process::id()));
+    let transcript = transcript.to_str().unwrap();
+    let program = ",----------[++++++++++.,----------]";
+
+    let output = run(&["--record-input", transcript, "-e", program], "ab\ncd");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"ab");
+    assert_eq!(std::fs::read(transcript).unwrap(), b"ab\n");
+
+    // Stdin is not read at all when replaying
+    let output = run(&["--replay-input", transcript, "-e", program], "xyz\n");
+    std::fs::remove_file(transcript).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"ab");
+}
+
+#[test]
+fn test_line_input() {
+    let output = run(
+        &["--line-input", "--eof", "zero", "-e", ",[.,]"],
+        "one\ntwo",
+    );
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"one\ntwo");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_ctrl_c_while_waiting_for_input() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_brainfuck-interpreter"))
+        .args(["-e", "+.,."])
+        .env_remove("RUST_LOG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // stdin stays open, so `,` waits until the signal arrives
+    let stdin = child.stdin.take().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    // SAFETY: only sends a signal to the child started above
+    assert_eq!(
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) },
+        0
+    );
+
+    let output = child.wait_with_output().unwrap();
+    drop(stdin);
+    assert!(!output.status.success());
+    assert_eq!(output.stdout, b"\x01");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Interrupted at position 2"));
+}
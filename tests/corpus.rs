@@ -0,0 +1,55 @@
+//! Runs every program in `programs/` that has an expected output next to it.
+//!
+//! A program `name.b` or `name.bf` is checked against `name.out`. Programs
+//! that read input are only run if there is a `name.in` to feed them, and
+//! read 0 once it is exhausted. Settings come from the program's `!bf:`
+//! directive.
+
+use brainfuck_interpreter::directive::strip_directive;
+use brainfuck_interpreter::{minify, strip_shebang, Cpu, EofPolicy, DEFAULT_TAPE_SIZE};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[test]
+fn test_corpus() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("programs");
+    let mut checked = 0;
+
+    let mut paths: Vec<_> = fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| matches!(path.extension(), Some(extension) if extension == "b" || extension == "bf"))
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let Ok(expected) = fs::read(path.with_extension("out")) else {
+            continue;
+        };
+        let source = fs::read_to_string(&path).unwrap();
+        let (directive, program) = strip_directive(strip_shebang(&source)).unwrap();
+
+        let mut cpu = Cpu::new(
+            program.to_string(),
+            directive.tape_size.unwrap_or(DEFAULT_TAPE_SIZE),
+        );
+        directive.apply(&mut cpu);
+        cpu.one_shot_output = true;
+        cpu.sink = Box::new(io::sink());
+        cpu.eof_policy = directive.eof_policy.unwrap_or(EofPolicy::Zero);
+
+        let input = fs::read(path.with_extension("in")).ok();
+        if input.is_none() && minify(program, cpu.level).contains(',') {
+            continue;
+        }
+
+        let result = cpu.run(Some(input.unwrap_or_default()));
+        assert!(result.is_ok(), "{}: {:?}", path.display(), result);
+        assert_eq!(cpu.output, expected, "{}", path.display());
+        checked += 1;
+    }
+
+    // Guards against the directory or the pairing silently going missing
+    assert!(checked >= 6, "only {} programs were checked", checked);
+}
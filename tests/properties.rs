@@ -0,0 +1,175 @@
+//! Invariants checked against randomly generated programs.
+//!
+//! proptest is not available to this build, so programs come from a small
+//! seeded generator instead. There is no shrinking, but every failure reports
+//! the seed and the program so it can be replayed, and programs are kept
+//! short so failures stay readable.
+
+use brainfuck_interpreter::{BrainfuckError, Cpu, EofPolicy, PointerPolicy};
+use std::io;
+
+const CASES: u64 = 500;
+const TAPE_SIZE: usize = 16;
+const MAX_STEPS: u64 = 20_000;
+
+/// xorshift64, good enough to pick commands
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+/// Appends up to `length` commands with balanced loops nested at most `depth`
+/// deep.
+fn generate(rng: &mut Rng, program: &mut String, length: u64, depth: u32) {
+    for _ in 0..rng.below(length + 1) {
+        match rng.below(8) {
+            0 if depth > 0 => {
+                program.push('[');
+                generate(rng, program, length / 2, depth - 1);
+                program.push(']');
+            }
+            command => program.push(b"+-<>.,+-"[command as usize] as char),
+        }
+    }
+}
+
+fn program(seed: u64) -> String {
+    let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1);
+    let mut program = String::new();
+    generate(&mut rng, &mut program, 24, 3);
+    program
+}
+
+fn cpu(program: &str, pointer_policy: PointerPolicy) -> Cpu {
+    let mut cpu = Cpu::new(program.to_string(), TAPE_SIZE);
+    cpu.one_shot_output = true;
+    cpu.sink = Box::new(io::sink());
+    cpu.pointer_policy = pointer_policy;
+    cpu.eof_policy = EofPolicy::Zero;
+    cpu.max_steps = Some(MAX_STEPS);
+    cpu
+}
+
+/// Executes one command at a time on a wrapping or clamped tape, without any
+/// of the compilation `Cpu` does. Returns `None` if it runs for too long.
+fn reference(program: &str, input: &[u8], wrap: bool) -> Option<Vec<u8>> {
+    let program = program.as_bytes();
+    let mut tape = [0u8; TAPE_SIZE];
+    let mut pointer = 0;
+    let mut input = input.iter();
+    let mut output = Vec::new();
+    let mut position = 0;
+
+    for _ in 0..100 * MAX_STEPS {
+        if position == program.len() {
+            return Some(output);
+        }
+
+        match program[position] {
+            b'+' => tape[pointer] = tape[pointer].wrapping_add(1),
+            b'-' => tape[pointer] = tape[pointer].wrapping_sub(1),
+            b'>' if wrap => pointer = (pointer + 1) % TAPE_SIZE,
+            b'<' if wrap => pointer = (pointer + TAPE_SIZE - 1) % TAPE_SIZE,
+            b'>' => pointer = (pointer + 1).min(TAPE_SIZE - 1),
+            b'<' => pointer = pointer.saturating_sub(1),
+            b'.' => output.push(tape[pointer]),
+            b',' => tape[pointer] = input.next().copied().unwrap_or(0),
+            b'[' if tape[pointer] == 0 => {
+                let mut depth = 0;
+                loop {
+                    match program[position] {
+                        b'[' => depth += 1,
+                        b']' if depth == 1 => break,
+                        b']' => depth -= 1,
+                        _ => (),
+                    }
+                    position += 1;
+                }
+            }
+            b']' if tape[pointer] != 0 => {
+                let mut depth = 0;
+                loop {
+                    match program[position] {
+                        b']' => depth += 1,
+                        b'[' if depth == 1 => break,
+                        b'[' => depth -= 1,
+                        _ => (),
+                    }
+                    position -= 1;
+                }
+            }
+            _ => (),
+        }
+        position += 1;
+    }
+
+    None
+}
+
+#[test]
+fn test_clamped_pointer_stays_on_the_tape() {
+    for seed in 0..CASES {
+        let program = program(seed);
+        let mut cpu = cpu(&program, PointerPolicy::Clamp);
+        let result = cpu.run(Some(b"input".to_vec()));
+        assert!(
+            matches!(result, Ok(()) | Err(BrainfuckError::StepLimitExceeded)),
+            "seed {}: {:?} failed with {:?}",
+            seed,
+            program,
+            result
+        );
+        assert!(
+            cpu.data_pointer < TAPE_SIZE && cpu.high_water_mark() < TAPE_SIZE,
+            "seed {}: {:?} moved the pointer to {}",
+            seed,
+            program,
+            cpu.data_pointer
+        );
+    }
+}
+
+#[test]
+fn test_every_policy_returns_a_result() {
+    // Panics fail the test, any `Result` is fine
+    for seed in 0..CASES {
+        let program = program(seed);
+        for policy in [
+            PointerPolicy::Clamp,
+            PointerPolicy::Wrap,
+            PointerPolicy::Error,
+        ] {
+            let _ = cpu(&program, policy).run(Some(b"in".to_vec()));
+        }
+    }
+}
+
+#[test]
+fn test_matches_reference_executor() {
+    for seed in 0..CASES {
+        let program = program(seed);
+        let input = b"some input";
+
+        for (policy, wrap) in [(PointerPolicy::Wrap, true), (PointerPolicy::Clamp, false)] {
+            let mut cpu = cpu(&program, policy);
+            if let Err(BrainfuckError::StepLimitExceeded) = cpu.run(Some(input.to_vec())) {
+                continue;
+            }
+
+            assert_eq!(
+                Some(cpu.output),
+                reference(&program, input, wrap),
+                "seed {} under {:?}: {:?}",
+                seed,
+                policy,
+                program
+            );
+        }
+    }
+}